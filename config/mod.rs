@@ -0,0 +1,1360 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time;
+
+use crate::core::{AuxConfig, STRATEGIES};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlagKind {
+    Switch,
+    Value,
+    RuleSwitch,
+    RuleValue,
+    Strategies,
+    Preset,
+}
+
+pub struct Flag {
+    pub name: &'static str,
+    pub kind: FlagKind,
+    pub value: &'static str,
+    pub default: Option<fn(&AuxConfig) -> String>,
+    pub help: &'static str,
+}
+
+fn millis(duration: time::Duration) -> String {
+    duration.as_millis().to_string()
+}
+
+pub const FLAGS: &[Flag] = &[
+    Flag { name: "--help", kind: FlagKind::Switch, value: "", default: None, help: "Print this help and exit" },
+    Flag { name: "--version", kind: FlagKind::Switch, value: "", default: None, help: "Print the version and exit" },
+    Flag { name: "--config", kind: FlagKind::Value, value: "<path>", default: None, help: "Read settings and rules from a TOML or JSON file at this point of the command line" },
+    Flag { name: "--check-config", kind: FlagKind::Switch, value: "", default: None, help: "Validate the configuration and exit without binding" },
+    Flag { name: "--explain", kind: FlagKind::Switch, value: "", default: None, help: "Print the effective rules and global settings and exit" },
+    Flag { name: "--print-rules", kind: FlagKind::Switch, value: "", default: None, help: "Same as --explain" },
+    Flag { name: "--json", kind: FlagKind::Switch, value: "", default: None, help: "With --explain, print JSON instead of a table" },
+    Flag { name: "--bind_host", kind: FlagKind::Value, value: "<host>", default: Some(|c| c.bind_host.clone()), help: "Address the SOCKS5 listener binds to" },
+    Flag { name: "--bind_port", kind: FlagKind::Value, value: "<u16>", default: Some(|c| c.bind_port.to_string()), help: "Port the SOCKS5 listener binds to" },
+    Flag { name: "--bind_iface", kind: FlagKind::Value, value: "<name>", default: Some(|c| c.bind_iface.clone()), help: "Interface name (reserved, currently unused)" },
+    Flag { name: "--bind_iface_mtu", kind: FlagKind::Value, value: "<u32>", default: Some(|c| c.bind_iface_mtu.to_string()), help: "Interface MTU (reserved, currently unused)" },
+    Flag { name: "--bind_iface_ipv4", kind: FlagKind::Value, value: "<address>", default: Some(|c| c.bind_iface_ipv4.clone()), help: "Interface IPv4 address (reserved, currently unused)" },
+    Flag { name: "--bind_iface_ipv6", kind: FlagKind::Value, value: "<address>", default: Some(|c| c.bind_iface_ipv6.clone()), help: "Interface IPv6 address (reserved, currently unused)" },
+    Flag { name: "--fake_packet_ttl", kind: FlagKind::Value, value: "<u8>", default: Some(|c| c.fake_packet_ttl.to_string()), help: "TTL of fake segments, low enough to expire before the server" },
+    Flag { name: "--send_fake_clienthello", kind: FlagKind::Switch, value: "", default: None, help: "Send a fake ClientHello before every hooked TLS write" },
+    Flag { name: "--disable_sack", kind: FlagKind::Switch, value: "", default: None, help: "Turn off selective acknowledgements on upstream sockets" },
+    Flag { name: "--fc_sni", kind: FlagKind::Value, value: "<host>", default: Some(|c| c.fake_clienthello_sni.clone()), help: "SNI of the fake ClientHello" },
+    Flag { name: "--fake_packet_sni", kind: FlagKind::Value, value: "<host>", default: Some(|c| c.fake_packet_sni.clone()), help: "SNI written into fake segments" },
+    Flag { name: "--fake_packet_send_http", kind: FlagKind::Switch, value: "", default: None, help: "Use an HTTP request as the fake payload" },
+    Flag { name: "--fake_as_oob", kind: FlagKind::Switch, value: "", default: None, help: "Send fake segments as out-of-band data" },
+    Flag { name: "--http_host_cmix", kind: FlagKind::Switch, value: "", default: None, help: "Mix the case of the Host header name" },
+    Flag { name: "--http_domain_cmix", kind: FlagKind::Switch, value: "", default: None, help: "Uppercase the first letter of the Host header value" },
+    Flag { name: "--http_host_rmspace", kind: FlagKind::Switch, value: "", default: None, help: "Remove the space after Host:" },
+    Flag { name: "--http_host_space", kind: FlagKind::Switch, value: "", default: None, help: "Insert a space after Host:" },
+    Flag { name: "--fake_packet_host", kind: FlagKind::Value, value: "<host>", default: Some(|c| c.fake_packet_host.clone()), help: "Host of the fake HTTP request" },
+    Flag { name: "--fake_packet_str", kind: FlagKind::Value, value: "<text>", default: None, help: "Use this text as the fake payload" },
+    Flag { name: "--fake_packet_hex", kind: FlagKind::Value, value: "<hex>", default: None, help: "Use these bytes as the fake payload" },
+    Flag { name: "--fake_packet_file", kind: FlagKind::Value, value: "file://<path>", default: None, help: "Use the contents of a file as the fake payload" },
+    Flag { name: "--oob_stream_hell_data", kind: FlagKind::Value, value: "<text>", default: Some(|c| c.oob_streamhell_data.clone()), help: "Bytes sent out-of-band one by one by tcp_out_of_band_hell" },
+    Flag { name: "--disorder_packet_ttl", kind: FlagKind::Value, value: "<u8>", default: Some(|c| c.disorder_packet_ttl.to_string()), help: "TTL of disordered segments (reserved, currently unused)" },
+    Flag { name: "--packet_hop", kind: FlagKind::Value, value: "<u64>", default: Some(|c| c.packet_hop.to_string()), help: "Number of client writes that get desynced when a rule has no other trigger" },
+    Flag { name: "--reassembly_timeout", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.reassembly_timeout)), help: "How long to wait for the rest of a split ClientHello or HTTP header" },
+    Flag { name: "--reassembly_max_size", kind: FlagKind::Value, value: "<usize>", default: Some(|c| c.reassembly_max_size.to_string()), help: "Largest first message that is reassembled before desync" },
+    Flag { name: "--handshake_timeout", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.handshake_timeout)), help: "Timeout for the SOCKS5 handshake and the upstream connect" },
+    Flag { name: "--idle_timeout", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.idle_timeout)), help: "Close relays without traffic for this long, 0 disables" },
+    Flag { name: "--max_lifetime", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.max_lifetime)), help: "Close relays older than this, 0 disables" },
+    Flag { name: "--shutdown_grace", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.shutdown_grace)), help: "How long to drain active relays on SIGTERM or SIGINT" },
+    Flag { name: "--log_level", kind: FlagKind::Value, value: "<level>", default: Some(|c| c.log_level.to_string()), help: "Least severe events that are logged: error, warn, info, debug or trace" },
+    Flag { name: "--log_format", kind: FlagKind::Value, value: "<format>", default: Some(|c| c.log_format.to_string()), help: "Write log lines as text or as JSON objects" },
+    Flag { name: "--log_file", kind: FlagKind::Value, value: "<path>", default: None, help: "Append the log to this file instead of stderr" },
+    Flag { name: "--metrics_addr", kind: FlagKind::Value, value: "<host:port>", default: None, help: "Serve Prometheus metrics at http://<host:port>/metrics, read once at startup" },
+    Flag { name: "--reset_window", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.reset_window)), help: "Classify connections reset this soon after a desynced write as reset" },
+    Flag { name: "--response_timeout", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.response_timeout)), help: "Classify connections without a server response this long after a desynced write as timed out, 0 disables classification" },
+    Flag { name: "--fallback_retries", kind: FlagKind::Value, value: "<n>", default: Some(|c| c.fallback_retries.to_string()), help: "Reconnect blocked connections with at most this many fallback sets, 0 disables" },
+    Flag { name: "--fallback_budget", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.fallback_budget)), help: "Give up falling back once a connection is this old" },
+    Flag { name: "--learn_file", kind: FlagKind::Value, value: "<path>", default: None, help: "Remember per host which fallback set got through in this file and start there next time, read once at startup" },
+    Flag { name: "--learn_expiry", kind: FlagKind::Value, value: "<ms>", default: Some(|c| millis(c.learn_expiry)), help: "Forget a learned set after this long without a success" },
+    Flag { name: "--learn_demote_after", kind: FlagKind::Value, value: "<n>", default: Some(|c| c.learn_demote_after.to_string()), help: "Forget a learned set after this many blocked connections in a row" },
+    Flag { name: "--block_page_patterns", kind: FlagKind::Value, value: "<text,...>", default: None, help: "Add texts that mark an HTTP 200 or 30x response as an ISP block page, case insensitive" },
+    Flag { name: "--trace_dir", kind: FlagKind::Value, value: "<dir>", default: None, help: "Write every desync emission of each connection to <dir>/connection-<id>.pcapng" },
+    Flag { name: "--trace_capture", kind: FlagKind::Switch, value: "", default: None, help: "Also capture the real outgoing packets of traced connections (Linux, needs CAP_NET_RAW)" },
+    Flag { name: "--control_addr", kind: FlagKind::Value, value: "<host:port>", default: None, help: "Serve the control API used by `waterfall ctl` on this loopback address, read once at startup" },
+    Flag { name: "--control_token", kind: FlagKind::Value, value: "<token>", default: None, help: "Require `Authorization: Bearer <token>` on control API requests, as sent by `waterfall ctl --token`" },
+    Flag { name: "--fake_packet_random", kind: FlagKind::Switch, value: "", default: None, help: "Send an extra random fake segment after every hooked write" },
+    Flag { name: "--fake_packet_double", kind: FlagKind::Switch, value: "", default: None, help: "Send fake segments twice (reserved, currently unused)" },
+    Flag { name: "--fake_packet_reversed", kind: FlagKind::Switch, value: "", default: None, help: "Build fake segments from the first part of the split instead of the second" },
+    Flag { name: "--so_recv_size", kind: FlagKind::Value, value: "<usize>", default: Some(|c| c.so_recv_size.to_string()), help: "Receive buffer size of upstream sockets while connecting" },
+    Flag { name: "--so_send_size", kind: FlagKind::Value, value: "<usize>", default: Some(|c| c.so_send_size.to_string()), help: "Send buffer size of upstream sockets while connecting" },
+    Flag { name: "--so_opt_cutoff", kind: FlagKind::Value, value: "<ms>", default: Some(|c| c.so_opt_cutoff.to_string()), help: "Time after which upstream buffer sizes are reset" },
+    Flag { name: "--default_ttl", kind: FlagKind::Value, value: "<u8>", default: Some(|c| c.default_ttl.to_string()), help: "TTL restored after sending fake or short-lived segments" },
+    Flag { name: "--out_of_band_charid", kind: FlagKind::Value, value: "<u8>", default: Some(|c| c.out_of_band_charid.to_string()), help: "Byte sent as urgent data by the out-of-band strategies" },
+    Flag { name: "--resist_timing_attack", kind: FlagKind::Value, value: "<u8>", default: Some(|c| millis(c.l7_packet_jitter_max)), help: "Maximum random delay in milliseconds after every hooked write" },
+    Flag { name: "--filter_protocol", kind: FlagKind::RuleValue, value: "tcp|udp", default: None, help: "Only apply the following strategies to this protocol, empty for both" },
+    Flag { name: "--filter_port", kind: FlagKind::RuleValue, value: "<range>", default: None, help: "Only apply the following strategies to these ports, e.g. 443 or 50000-51000" },
+    Flag { name: "--filter_sni", kind: FlagKind::RuleValue, value: "<hosts>|file://<path>", default: None, help: "Add hosts the following strategies are limited to, comma separated or one per line" },
+    Flag { name: "--reset_sni_filter", kind: FlagKind::RuleSwitch, value: "", default: None, help: "Clear the host list for the following strategies" },
+    Flag { name: "--filter_writes", kind: FlagKind::RuleValue, value: "<range>", default: None, help: "Desync these client writes (1-based) instead of the first --packet_hop" },
+    Flag { name: "--desync_at_offset", kind: FlagKind::RuleValue, value: "<u64,...>", default: None, help: "Desync the writes that contain these stream offsets" },
+    Flag { name: "--desync_after_ms", kind: FlagKind::RuleValue, value: "<ms,...>", default: None, help: "Desync the first write after each of these delays" },
+    Flag { name: "--reset_desync_triggers", kind: FlagKind::RuleSwitch, value: "", default: None, help: "Clear write, offset and time triggers for the following strategies" },
+    Flag { name: "--preset", kind: FlagKind::Preset, value: "<name>[@version]", default: None, help: "Add the rules of a built-in or config file preset; resets the rule flags" },
+    Flag { name: "--strategy_stack", kind: FlagKind::RuleValue, value: "<stack>", default: None, help: "Segment pattern the following strategies are checked against, see list-strategies" },
+    Flag { name: "--dpi_bypass_strategies", kind: FlagKind::Strategies, value: "<name,...> <positions>...", default: None, help: "Add strategies, followed by one comma separated position list per strategy" },
+    Flag { name: "--fallback_strategies", kind: FlagKind::Strategies, value: "<name,...> <positions>...", default: None, help: "Add the next set to try, in order, when the preceding --dpi_bypass_strategies gets blocked" },
+];
+
+pub fn find_flag(name: &str) -> Option<&'static Flag> {
+    FLAGS.iter().find(|flag| flag.name == name)
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub origin: String,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.origin, self.message)?;
+
+        if let Some(ref hint) = self.hint {
+            write!(f, "\n  help: {}", hint)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn report(errors: &[ConfigError]) {
+    for error in errors {
+        eprintln!("error: {}", error);
+    }
+
+    eprintln!("{} configuration error(s)", errors.len());
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, left) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+
+        row[0] = i + 1;
+
+        for (j, right) in b.iter().enumerate() {
+            let substitution = diagonal + (left != *right) as usize;
+
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+fn same_words(a: &str, b: &str) -> bool {
+    let words = |text: &str| {
+        let mut words: Vec<String> = text
+            .trim_start_matches("--")
+            .split(['_', '-'])
+            .map(String::from)
+            .collect();
+
+        words.sort();
+        words
+    };
+
+    words(a) == words(b)
+}
+
+// Picks the closest candidate, also catching words given in the wrong order
+// (--reset_filter_sni for --reset_sni_filter).
+
+pub fn suggest<'a>(input: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|candidate| (if same_words(input, candidate) { 0 } else { edit_distance(input, candidate) }, candidate))
+        .filter(|(distance, candidate)| *distance <= 2.max(candidate.len() / 4))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+pub trait Limit: TryFrom<u64> {
+    const MAX: u64;
+}
+
+macro_rules! limit {
+    ($($kind:ty),*) => {
+        $(impl Limit for $kind { const MAX: u64 = <$kind>::MAX as u64; })*
+    };
+}
+
+limit!(u8, u16, u32, u64, usize);
+
+// Walks the expanded argument list and collects every problem instead of stopping at the
+// first one. `origins` names where each argument came from, for the error messages.
+
+pub struct ArgReader {
+    args: Vec<String>,
+    origins: Vec<String>,
+    offset: usize,
+    flag: String,
+    pub errors: Vec<ConfigError>,
+}
+
+impl ArgReader {
+    pub fn new(args: Vec<String>, origins: Vec<String>) -> ArgReader {
+        ArgReader { args, origins, offset: 0, flag: String::new(), errors: Vec::new() }
+    }
+
+    pub fn next(&mut self) -> Option<String> {
+        let arg = self.args.get(self.offset)?.clone();
+
+        self.offset += 1;
+        self.flag = arg.clone();
+
+        Some(arg)
+    }
+
+    pub fn last(&self) -> usize {
+        self.offset.saturating_sub(1)
+    }
+
+    pub fn error_at(&mut self, index: usize, message: String, hint: Option<String>) {
+        self.errors.push(ConfigError {
+            origin: self.origins.get(index).cloned().unwrap_or_else(|| format!("argument {}", index + 1)),
+            message,
+            hint
+        });
+    }
+
+    pub fn error(&mut self, message: String, hint: Option<String>) {
+        self.error_at(self.last(), message, hint);
+    }
+
+    // A following flag is never taken as a value, so a forgotten value doesn't swallow it.
+
+    pub fn value_for(&mut self, what: &str) -> Option<String> {
+        match self.args.get(self.offset) {
+            Some(value) if find_flag(value).is_none() => {
+                let value = value.clone();
+
+                self.offset += 1;
+
+                Some(value)
+            },
+            _ => {
+                let message = format!("{} expects {}", self.flag, what);
+
+                self.error(message, None);
+
+                None
+            }
+        }
+    }
+
+    pub fn value(&mut self) -> Option<String> {
+        self.value_for("a value")
+    }
+
+    pub fn text(&mut self, target: &mut String) {
+        if let Some(value) = self.value() {
+            *target = value;
+        }
+    }
+
+    pub fn parse_number<T: Limit>(&mut self, value: &str) -> Option<T> {
+        let number = value.trim().parse::<u64>().ok().and_then(|number| T::try_from(number).ok());
+
+        if number.is_none() {
+            let message = format!("{} expects an integer between 0 and {}, got `{}`", self.flag, T::MAX, value);
+
+            self.error(message, None);
+        }
+
+        number
+    }
+
+    pub fn number<T: Limit>(&mut self, target: &mut T) {
+        if let Some(number) = self.value().and_then(|value| self.parse_number(&value)) {
+            *target = number;
+        }
+    }
+
+    pub fn numbers<T: Limit>(&mut self) -> Option<Vec<T>> {
+        let value = self.value()?;
+
+        value
+            .split(",")
+            .map(|item| self.parse_number(item))
+            .collect::<Vec<Option<T>>>()
+            .into_iter()
+            .collect()
+    }
+
+    pub fn millis(&mut self, target: &mut time::Duration) {
+        if let Some(value) = self.value() {
+            match value.trim().parse::<u64>() {
+                Ok(millis) => *target = time::Duration::from_millis(millis),
+                Err(_) => {
+                    let message = format!("{} expects a number of milliseconds, got `{}`", self.flag, value);
+
+                    self.error(message, Some("durations are plain integers in milliseconds, e.g. 1500".to_string()));
+                }
+            }
+        }
+    }
+
+    // Host lists and fake payloads can only be read from file:// paths.
+
+    pub fn file(&mut self) -> Option<(String, Vec<u8>)> {
+        let value = self.value()?;
+
+        self.read_file(&value)
+    }
+
+    pub fn read_file(&mut self, value: &str) -> Option<(String, Vec<u8>)> {
+        let Some(path) = value.strip_prefix("file://") else {
+            let message = format!("{} expects a file:// path, got `{}`", self.flag, value);
+
+            self.error(message, Some(format!("write it as file://{}", value)));
+
+            return None;
+        };
+
+        match fs::read(path) {
+            Ok(data) => Some((path.to_string(), data)),
+            Err(error) => {
+                self.error(format!("cannot read `{}`: {}", path, error), None);
+
+                None
+            }
+        }
+    }
+
+    pub fn unknown(&mut self, arg: &str, strategies: &[&'static str]) {
+        if !arg.starts_with("-") {
+            self.error(format!("unexpected value `{}`", arg), Some("values must directly follow the flag they belong to".to_string()));
+
+            return;
+        }
+
+        let name = arg.trim_start_matches("-");
+
+        let hint = if strategies.contains(&name) {
+            Some(format!("{} is a strategy, use --dpi_bypass_strategies {} <positions>", name, name))
+        } else {
+            suggest(arg, FLAGS.iter().map(|flag| flag.name)).map(|flag| format!("did you mean {}?", flag))
+        };
+
+        self.error(format!("unknown flag `{}`", arg), hint);
+    }
+}
+
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits ({})", text.len()));
+    }
+
+    text.as_bytes()
+        .chunks(2)
+        .enumerate()
+        .map(|(index, pair)| std::str::from_utf8(pair)
+            .ok()
+            .filter(|pair| pair.chars().all(|digit| digit.is_ascii_hexdigit()))
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or(format!("`{}` at offset {} is not a hex byte", String::from_utf8_lossy(pair), index * 2)))
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ConfigFile {
+    #[serde(flatten)]
+    pub settings: BTreeMap<String, Value>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleBlock>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preset {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub rules: Vec<RuleBlock>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleBlock {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ports: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub host_files: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub writes: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub desync_at_offset: Vec<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub desync_after_ms: Vec<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub strategies: Vec<StrategyBlock>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<FallbackBlock>,
+}
+
+impl RuleBlock {
+    // Fields set on a block that names a preset replace the same fields of every rule the
+    // preset expands into.
+
+    fn layer(&self, overrides: &RuleBlock) -> RuleBlock {
+        let mut rule = self.clone();
+
+        rule.preset = None;
+
+        for (field, value) in [
+            (&mut rule.protocol, &overrides.protocol),
+            (&mut rule.ports, &overrides.ports),
+            (&mut rule.stack, &overrides.stack),
+            (&mut rule.writes, &overrides.writes),
+        ] {
+            if value.is_some() {
+                *field = value.clone();
+            }
+        }
+
+        if !overrides.hosts.is_empty() || !overrides.host_files.is_empty() {
+            rule.hosts = overrides.hosts.clone();
+            rule.host_files = overrides.host_files.clone();
+        }
+
+        if !overrides.desync_at_offset.is_empty() {
+            rule.desync_at_offset = overrides.desync_at_offset.clone();
+        }
+
+        if !overrides.desync_after_ms.is_empty() {
+            rule.desync_after_ms = overrides.desync_after_ms.clone();
+        }
+
+        if !overrides.strategies.is_empty() {
+            rule.strategies = overrides.strategies.clone();
+        }
+
+        if !overrides.fallbacks.is_empty() {
+            rule.fallbacks = overrides.fallbacks.clone();
+        }
+
+        rule
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        for file in &mut self.host_files {
+            *file = resolve_path(base, file);
+        }
+    }
+
+    // Every rule starts from a clean filter state instead of inheriting the previous one.
+
+    fn to_args(&self, origin: &str) -> Result<Vec<String>, Vec<ConfigError>> {
+        let error = |message: String, hint: Option<String>| ConfigError { origin: origin.to_string(), message, hint };
+
+        if self.strategies.is_empty() {
+            return Err(vec![error("no strategies".to_string(), Some("add at least one [[rules.strategies]] block or a preset".to_string()))]);
+        }
+
+        let mut errors: Vec<ConfigError> = Vec::new();
+
+        let mut args: Vec<String> = reset_args();
+
+        args.extend([
+            "--filter_protocol".to_string(), self.protocol.clone().unwrap_or_default(),
+            "--filter_port".to_string(), self.ports.clone().unwrap_or_default(),
+            "--strategy_stack".to_string(), self.stack.clone().unwrap_or_default(),
+        ]);
+
+        if !self.hosts.is_empty() {
+            args.extend(["--filter_sni".to_string(), self.hosts.join(",")]);
+        }
+
+        for file in &self.host_files {
+            args.extend(["--filter_sni".to_string(), format!("file://{}", file)]);
+        }
+
+        if let Some(ref writes) = self.writes {
+            args.extend(["--filter_writes".to_string(), writes.clone()]);
+        }
+
+        if !self.desync_at_offset.is_empty() {
+            args.extend(["--desync_at_offset".to_string(), value_to_arg(&self.desync_at_offset.clone().into())]);
+        }
+
+        if !self.desync_after_ms.is_empty() {
+            args.extend(["--desync_after_ms".to_string(), value_to_arg(&self.desync_after_ms.clone().into())]);
+        }
+
+        let sets = [("--dpi_bypass_strategies", &self.strategies)]
+            .into_iter()
+            .chain(self.fallbacks.iter().map(|fallback| ("--fallback_strategies", &fallback.strategies)));
+
+        for (flag, strategies) in sets {
+            if strategies.is_empty() {
+                errors.push(error("fallback without strategies".to_string(), Some("add at least one [[rules.fallbacks.strategies]] block".to_string())));
+
+                continue;
+            }
+
+            args.push(flag.to_string());
+            args.push(strategies
+                .iter()
+                .map(|strategy| strategy.method.clone())
+                .collect::<Vec<String>>()
+                .join(","));
+
+            for strategy in strategies {
+                if strategy.positions.is_empty() {
+                    errors.push(error(format!("strategy {} has no positions", strategy.method), None));
+                }
+
+                args.push(strategy.positions.join(","));
+            }
+        }
+
+        if errors.is_empty() { Ok(args) } else { Err(errors) }
+    }
+}
+
+// Clears every filter, trigger and stack a rule may have set, so that neither the previous
+// rule nor a whole file or preset leaks into what follows.
+
+fn reset_args() -> Vec<String> {
+    [
+        "--reset_sni_filter", "--reset_desync_triggers",
+        "--filter_protocol", "", "--filter_port", "", "--strategy_stack", "",
+    ].map(String::from).to_vec()
+}
+
+// One entry of a rule's ordered fallback list, tried when its strategies get blocked.
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackBlock {
+    pub strategies: Vec<StrategyBlock>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StrategyBlock {
+    pub method: String,
+    pub positions: Vec<String>,
+}
+
+fn resolve_path(base: &Path, path: &str) -> String {
+    if Path::new(path).is_absolute() {
+        return path.to_string();
+    }
+
+    base.join(path).to_string_lossy().to_string()
+}
+
+fn value_to_arg(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(value_to_arg)
+            .collect::<Vec<String>>()
+            .join(","),
+        other => other.to_string()
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: &str) -> Result<ConfigFile, String> {
+        let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+
+        if path.ends_with(".json") {
+            serde_json::from_str(&text).map_err(|error| format!("{}: {}", path, error))
+        } else {
+            toml::from_str(&text).map_err(|error| format!("{}: {}", path, error))
+        }
+    }
+
+    // The file is lowered onto the command line syntax, so both go through the same parser.
+    // Presets defined in it are added to `presets` for the rest of the command line.
+
+    pub fn to_args(&self, path: &str, presets: &mut BTreeMap<String, Preset>) -> Result<(Vec<String>, Vec<String>), Vec<ConfigError>> {
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+
+        let mut args: Vec<String> = Vec::new();
+        let mut origins: Vec<String> = Vec::new();
+        let mut errors: Vec<ConfigError> = Vec::new();
+
+        let error = |origin: String, message: String, hint: Option<String>| ConfigError { origin, message, hint };
+
+        for (name, preset) in &self.presets {
+            let mut preset = preset.clone();
+
+            preset.rules.iter_mut().for_each(|rule| rule.resolve_paths(base));
+
+            presets.insert(name.clone(), preset);
+        }
+
+        for (key, value) in &self.settings {
+            let name = format!("--{}", key);
+            let origin = format!("{}, setting `{}`", path, key);
+
+            match find_flag(&name).map(|flag| flag.kind) {
+                Some(FlagKind::Switch) => match value.as_bool() {
+                    Some(true) => args.push(name),
+                    Some(false) => {},
+                    None => errors.push(error(origin.clone(), format!("expected true or false, got {}", value), None))
+                },
+                Some(FlagKind::Value) if name == "--fake_packet_file" => {
+                    args.push(name);
+                    args.push(format!("file://{}", resolve_path(base, &value_to_arg(value))));
+                },
+                Some(FlagKind::Value) => {
+                    args.push(name);
+                    args.push(value_to_arg(value));
+                },
+                _ => {
+                    let settings = FLAGS
+                        .iter()
+                        .filter(|flag| matches!(flag.kind, FlagKind::Switch | FlagKind::Value))
+                        .map(|flag| flag.name.trim_start_matches("--"));
+
+                    let hint = match find_flag(&name).map(|flag| flag.kind) {
+                        Some(FlagKind::Preset) => Some("use a [[rules]] block with preset = \"name\"".to_string()),
+                        Some(_) => Some("filters, triggers and stacks belong into a [[rules]] block".to_string()),
+                        None => suggest(key, settings).map(|setting| format!("did you mean `{}`?", setting))
+                    };
+
+                    errors.push(error(origin, "not a global setting".to_string(), hint));
+
+                    continue;
+                }
+            }
+
+            origins.resize(args.len(), origin);
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            let origin = format!("{}, rule #{}", path, index + 1);
+
+            let mut rule = rule.clone();
+
+            rule.resolve_paths(base);
+
+            match expand_rule(&rule, presets, &origin, 0) {
+                Ok(expanded) => for expanded in expanded {
+                    match expanded.to_args(&origin) {
+                        Ok(rule_args) => args.extend(rule_args),
+                        Err(rule_errors) => errors.extend(rule_errors)
+                    }
+                },
+                Err(error) => errors.push(error)
+            }
+
+            origins.resize(args.len(), origin);
+        }
+
+        if errors.is_empty() { Ok((args, origins)) } else { Err(errors) }
+    }
+
+    // Replays the implicit filter state of a command line and records a rule block for
+    // every --dpi_bypass_strategies occurrence.
+
+    pub fn from_args(args: &[String]) -> (ConfigFile, Vec<String>) {
+        let mut config = ConfigFile::default();
+        let mut warnings: Vec<String> = Vec::new();
+        let mut state = RuleBlock::default();
+
+        let mut offset: usize = 0;
+
+        while offset < args.len() {
+            let name = args[offset].as_str();
+            let value = args.get(offset + 1).cloned();
+
+            let Some(flag) = find_flag(name) else {
+                warnings.push(format!("argument {} ({}) is not a known flag and was dropped", offset + 1, name));
+
+                offset += 1;
+
+                continue;
+            };
+
+            if matches!(flag.kind, FlagKind::Value | FlagKind::RuleValue | FlagKind::Strategies | FlagKind::Preset) && value.is_none() {
+                warnings.push(format!("argument {} ({}) is missing its value", offset + 1, name));
+
+                break;
+            }
+
+            let value = value.unwrap_or_default();
+
+            match name {
+                "--help" | "--version" | "--check-config" | "--explain" | "--print-rules" | "--json" => {},
+                "--filter_protocol" => state.protocol = Some(value).filter(|value| !value.is_empty()),
+                "--filter_port" => state.ports = Some(value).filter(|value| !value.is_empty()),
+                "--strategy_stack" => state.stack = Some(value).filter(|value| !value.is_empty()),
+                "--filter_writes" => state.writes = Some(value).filter(|value| !value.is_empty()),
+                "--desync_at_offset" => {
+                    state.desync_at_offset = value.split(",").filter_map(|n| n.trim().parse().ok()).collect();
+                },
+                "--desync_after_ms" => {
+                    state.desync_after_ms = value.split(",").filter_map(|n| n.trim().parse().ok()).collect();
+                },
+                "--preset" => config.rules.push(RuleBlock { preset: Some(value), ..RuleBlock::default() }),
+                "--reset_desync_triggers" => {
+                    state.writes = None;
+                    state.desync_at_offset.clear();
+                    state.desync_after_ms.clear();
+                },
+                "--filter_sni" => match value.split("file://").nth(1) {
+                    Some(path) => state.host_files.push(path.to_string()),
+                    None => state.hosts.extend(value.split(",").map(String::from))
+                },
+                "--reset_sni_filter" => {
+                    state.hosts.clear();
+                    state.host_files.clear();
+                },
+                "--dpi_bypass_strategies" | "--fallback_strategies" => {
+                    let methods: Vec<&str> = value.split(",").collect();
+
+                    let strategies: Vec<StrategyBlock> = methods
+                        .iter()
+                        .enumerate()
+                        .map(|(index, method)| StrategyBlock {
+                            method: method.to_string(),
+                            positions: args
+                                .get(offset + 2 + index)
+                                .map(|positions| positions.split(",").map(String::from).collect())
+                                .unwrap_or_default()
+                        })
+                        .collect();
+
+                    match (name, config.rules.last_mut()) {
+                        ("--fallback_strategies", Some(rule)) => rule.fallbacks.push(FallbackBlock { strategies }),
+                        ("--fallback_strategies", None) => {
+                            warnings.push(format!("argument {} (--fallback_strategies) has no rule to fall back from and was dropped", offset + 1));
+                        },
+                        _ => config.rules.push(RuleBlock { strategies, ..state.clone() })
+                    }
+
+                    offset += methods.len();
+                },
+                _ => {
+                    let key = name.trim_start_matches("--").to_string();
+
+                    let setting: Value = match flag.kind {
+                        FlagKind::Switch => Value::Bool(true),
+                        _ if name == "--fake_packet_file" => Value::String(value.split("file://").nth(1).unwrap_or(&value).to_string()),
+                        _ => value
+                            .parse::<u64>()
+                            .ok()
+                            .filter(|number| number.to_string() == value)
+                            .map(Value::from)
+                            .unwrap_or(Value::String(value.clone()))
+                    };
+
+                    config.settings.insert(key, setting);
+                }
+            }
+
+            offset += if matches!(flag.kind, FlagKind::Switch | FlagKind::RuleSwitch) { 1 } else { 2 };
+        }
+
+        (config, warnings)
+    }
+}
+
+// A preset is looked up among the ones defined by config files so far, then among the
+// built-in ones. `name@version` pins the version.
+
+fn find_preset(presets: &BTreeMap<String, Preset>, spec: &str, origin: &str) -> Result<Preset, ConfigError> {
+    let error = |message: String, hint: Option<String>| ConfigError { origin: origin.to_string(), message, hint };
+
+    let (name, version) = match spec.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None)
+    };
+
+    let preset = match presets.get(name) {
+        Some(preset) => preset.clone(),
+        None => match crate::presets::builtin(name) {
+            Some(preset) => preset.map_err(|message| error(message, None))?,
+            None => {
+                let mut known: Vec<&str> = crate::presets::names().collect();
+
+                known.extend(presets.keys().map(String::as_str));
+
+                let hint = suggest(name, known)
+                    .map(|known| format!("did you mean {}?", known))
+                    .unwrap_or("run `waterfall list-presets` for the built-in ones".to_string());
+
+                return Err(error(format!("unknown preset `{}`", name), Some(hint)));
+            }
+        }
+    };
+
+    let current = preset.version.unwrap_or(1);
+
+    if let Some(version) = version {
+        if version != current.to_string() {
+            return Err(error(format!("preset {} is at version {}, not {}", name, current, version), Some(format!("review the changes and pin {}@{}", name, current))));
+        }
+    }
+
+    Ok(preset)
+}
+
+fn expand_rule(rule: &RuleBlock, presets: &BTreeMap<String, Preset>, origin: &str, depth: usize) -> Result<Vec<RuleBlock>, ConfigError> {
+    let Some(ref spec) = rule.preset else {
+        return Ok(vec![rule.clone()]);
+    };
+
+    if depth > 8 {
+        return Err(ConfigError {
+            origin: origin.to_string(),
+            message: format!("preset {} nests too deep", spec),
+            hint: Some("check for presets that include each other".to_string())
+        });
+    }
+
+    let preset = find_preset(presets, spec, origin)?;
+    let mut rules: Vec<RuleBlock> = Vec::new();
+
+    for inner in &preset.rules {
+        for expanded in expand_rule(inner, presets, origin, depth + 1)? {
+            rules.push(expanded.layer(rule));
+        }
+    }
+
+    Ok(rules)
+}
+
+// Splices the contents of every --config file into the argument list at the place where it
+// appears, so flags given after it on the command line still override the file. Returns the
+// origin of every argument next to it. A broken file, rule or preset is left out and the
+// rest is still expanded, so that every problem gets reported.
+
+pub fn expand_args(args: Vec<String>, sources: &mut Vec<String>) -> Result<(Vec<String>, Vec<String>), Vec<ConfigError>> {
+    let mut expanded: Vec<String> = Vec::new();
+    let mut origins: Vec<String> = Vec::new();
+    let mut errors: Vec<ConfigError> = Vec::new();
+
+    let mut presets: BTreeMap<String, Preset> = BTreeMap::new();
+
+    let mut iter = args.into_iter().enumerate();
+
+    while let Some((index, arg)) = iter.next() {
+        if arg == "--preset" {
+            let origin = format!("argument {}", index + 2);
+
+            let Some((_, spec)) = iter.next() else {
+                errors.push(ConfigError { origin: format!("argument {}", index + 1), message: "--preset expects a name".to_string(), hint: None });
+
+                break;
+            };
+
+            let rule = RuleBlock { preset: Some(spec.clone()), ..RuleBlock::default() };
+
+            match expand_rule(&rule, &presets, &origin, 0) {
+                Ok(rules) => for (number, rule) in rules.iter().enumerate() {
+                    let origin = format!("{} (preset {}, rule #{})", origin, spec, number + 1);
+
+                    match rule.to_args(&origin) {
+                        Ok(rule_args) => expanded.extend(rule_args),
+                        Err(rule_errors) => errors.extend(rule_errors)
+                    }
+
+                    origins.resize(expanded.len(), origin);
+                },
+                Err(error) => errors.push(error)
+            }
+
+            expanded.extend(reset_args());
+            origins.resize(expanded.len(), origin);
+
+            continue;
+        }
+
+        if arg != "--config" {
+            expanded.push(arg);
+            origins.push(format!("argument {}", index + 1));
+
+            continue;
+        }
+
+        let Some((_, path)) = iter.next() else {
+            errors.push(ConfigError { origin: format!("argument {}", index + 1), message: "--config expects a path".to_string(), hint: None });
+
+            break;
+        };
+
+        let file = match ConfigFile::load(&path) {
+            Ok(file) => file,
+            Err(message) => {
+                errors.push(ConfigError { origin: format!("argument {}", index + 2), message, hint: None });
+
+                continue;
+            }
+        };
+
+        match file.to_args(&path, &mut presets) {
+            Ok((file_args, file_origins)) => {
+                expanded.extend(file_args);
+                origins.extend(file_origins);
+            },
+            Err(file_errors) => errors.extend(file_errors)
+        }
+
+        expanded.extend(reset_args());
+        origins.resize(expanded.len(), format!("argument {}", index + 2));
+
+        sources.push(path);
+    }
+
+    if errors.is_empty() { Ok((expanded, origins)) } else { Err(errors) }
+}
+
+pub fn convert_command(args: &[String]) -> std::io::Result<()> {
+    let as_json: bool = args.first().map(String::as_str) == Some("--json");
+    let args = if as_json { &args[1..] } else { args };
+
+    let (config, warnings) = ConfigFile::from_args(args);
+
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    let output = if as_json {
+        serde_json::to_string_pretty(&config).map_err(std::io::Error::other)?
+    } else {
+        toml::to_string_pretty(&config).map_err(std::io::Error::other)?
+    };
+
+    println!("{}", output);
+
+    Ok(())
+}
+
+fn print_flags(title: &str, flags: Vec<&Flag>) {
+    let defaults = AuxConfig::default();
+
+    let width = flags
+        .iter()
+        .map(|flag| flag.name.len() + flag.value.len() + 1)
+        .max()
+        .unwrap_or(0);
+
+    println!("{}:", title);
+
+    for flag in flags {
+        let usage = format!("{} {}", flag.name, flag.value);
+        let default = flag.default
+            .map(|default| default(&defaults))
+            .filter(|default| !default.is_empty())
+            .map(|default| format!(" [default: {}]", default))
+            .unwrap_or_default();
+
+        println!("  {:width$}  {}{}", usage.trim_end(), flag.help, default, width = width);
+    }
+
+    println!();
+}
+
+pub fn help_command() {
+    println!("Usage: waterfall [FLAGS]");
+    println!("       waterfall convert [--json] [FLAGS]");
+    println!("       waterfall list-strategies");
+    println!("       waterfall list-presets");
+    println!("       waterfall simulate (--hex <hex> | --file <path> | --pcap <path>) [--port <port>] [--json] [FLAGS]");
+    println!("       waterfall probe <domain> [--port <port>] [--connect <host:port>] [--http] [--attempts <n>] [--positions <list>] [--ttls <list>] [--no_stacks] [--json] [FLAGS]");
+    println!("       waterfall ctl [--addr <host:port>] [--json] <command>");
+    println!();
+
+    print_flags("Flags", FLAGS
+        .iter()
+        .filter(|flag| matches!(flag.kind, FlagKind::Switch | FlagKind::Value))
+        .collect());
+
+    print_flags("Rule flags, applied to every --dpi_bypass_strategies that follows them", FLAGS
+        .iter()
+        .filter(|flag| !matches!(flag.kind, FlagKind::Switch | FlagKind::Value))
+        .collect());
+
+    println!("Durations marked <ms> are in milliseconds. Run `waterfall list-strategies` for strategies,");
+    println!("positions and stacks.");
+}
+
+pub fn list_strategies_command() {
+    let width = STRATEGIES
+        .iter()
+        .map(|info| info.name.len())
+        .max()
+        .unwrap_or(0);
+
+    println!("Strategies:");
+
+    for info in STRATEGIES {
+        println!("  {:width$}  {}", info.name, info.summary, width = width);
+        println!("  {:width$}  protocol: {}, positions: {}, stack: {}", "", info.protocol, info.positions, info.stack.describe(), width = width);
+    }
+
+    println!();
+    println!("Positions, one comma separated list per strategy:");
+    println!("  N, N+, N-  split N bytes into the write; the sign is optional and does not change the offset");
+    println!("  N+s        split N bytes past the start of the SNI; skipped when the write carries no SNI");
+    println!("  N+h        accepted, the Host header offset is not applied yet");
+    println!("  auto       in a later strategy of a list: repeat the first strategy's positions one byte earlier;");
+    println!("             pairs tcp_split after disorder, fake and out-of-band strategies, and tcp_disorder or");
+    println!("             tcp_disorder2 after fake strategies. Strategies that ignore positions accept it anywhere");
+    println!();
+    println!("Stacks (--strategy_stack), one letter per segment in the order it is sent:");
+    println!("  B  a segment that belongs later in the stream than the one after it");
+    println!("  A  a segment that belongs earlier in the stream than the one before it");
+    println!("  F  a fake segment");
+    println!("  O  an out-of-band byte");
+    println!("  Every strategy given after --strategy_stack is checked against it, e.g. tcp_disorder needs BA.");
+}
+
+pub fn list_presets_command() -> std::io::Result<()> {
+    for name in crate::presets::names() {
+        let preset = crate::presets::builtin(name)
+            .unwrap_or(Err(String::new()))
+            .map_err(std::io::Error::other)?;
+
+        println!("{}@{}  {}", name, preset.version.unwrap_or(1), preset.description.unwrap_or_default());
+
+        for rule in &preset.rules {
+            println!("    {} {}: {}{}",
+                rule.protocol.as_deref().unwrap_or("any"),
+                rule.ports.as_deref().unwrap_or("any"),
+                rule.strategies
+                    .iter()
+                    .map(|strategy| format!("{} {}", strategy.method, strategy.positions.join(",")))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                if rule.hosts.is_empty() { String::new() } else { format!(" ({} hosts)", rule.hosts.len()) });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn reader(line: &str) -> ArgReader {
+        let args = args(line);
+        let origins = (0..args.len()).map(|index| format!("test.toml, setting {}", index)).collect();
+
+        ArgReader::new(args, origins)
+    }
+
+    fn preset(text: &str) -> Preset {
+        toml::from_str(text).unwrap()
+    }
+
+    fn strategy(method: &str, positions: &str) -> StrategyBlock {
+        StrategyBlock { method: method.to_string(), positions: vec![positions.to_string()] }
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("--bind_port", "--bind_port"), 0);
+        assert_eq!(edit_distance("--bind_prot", "--bind_port"), 2);
+    }
+
+    #[test]
+    fn same_words_ignores_their_order() {
+        assert!(same_words("--reset_filter_sni", "--reset_sni_filter"));
+        assert!(same_words("filter-sni", "--sni_filter"));
+        assert!(!same_words("--filter_sni", "--filter_port"));
+        assert!(!same_words("--filter_sni", "--filter_sni_reset"));
+    }
+
+    #[test]
+    fn suggestions_pick_the_closest_flag() {
+        let flags = || FLAGS.iter().map(|flag| flag.name);
+
+        assert_eq!(suggest("--bind_prot", flags()), Some("--bind_port"));
+        assert_eq!(suggest("--reset_filter_sni", flags()), Some("--reset_sni_filter"));
+        assert_eq!(suggest("--log_levl", flags()), Some("--log_level"));
+        assert_eq!(suggest("--completely_unrelated", flags()), None);
+    }
+
+    #[test]
+    fn missing_values_are_reported_without_taking_the_next_flag() {
+        let mut reader = reader("--bind_port --log_level debug");
+
+        reader.next();
+
+        assert_eq!(reader.value(), None);
+        assert_eq!(reader.next().as_deref(), Some("--log_level"));
+
+        assert_eq!(reader.errors.len(), 1);
+        assert_eq!(reader.errors[0].origin, "test.toml, setting 0");
+        assert_eq!(reader.errors[0].message, "--bind_port expects a value");
+    }
+
+    #[test]
+    fn malformed_numbers_and_durations_are_reported() {
+        let mut reader = reader("--bind_port 70000 --idle_timeout 1.5s --packet_hop 1,x");
+        let mut port: u16 = 1;
+        let mut timeout = time::Duration::ZERO;
+
+        reader.next();
+        reader.number(&mut port);
+        reader.next();
+        reader.millis(&mut timeout);
+        reader.next();
+
+        assert_eq!(reader.numbers::<u64>(), None);
+
+        assert_eq!((port, timeout), (1, time::Duration::ZERO));
+
+        let messages: Vec<&str> = reader.errors.iter().map(|error| error.message.as_str()).collect();
+
+        assert_eq!(messages, [
+            "--bind_port expects an integer between 0 and 65535, got `70000`",
+            "--idle_timeout expects a number of milliseconds, got `1.5s`",
+            "--packet_hop expects an integer between 0 and 18446744073709551615, got `x`",
+        ]);
+
+        assert_eq!(reader.errors[1].hint.as_deref(), Some("durations are plain integers in milliseconds, e.g. 1500"));
+        assert_eq!(reader.errors[2].origin, "test.toml, setting 5");
+    }
+
+    #[test]
+    fn files_need_a_file_url() {
+        let mut reader = reader("--filter_sni hosts.txt");
+
+        reader.next();
+
+        assert_eq!(reader.file(), None);
+        assert_eq!(reader.errors[0].hint.as_deref(), Some("write it as file://hosts.txt"));
+    }
+
+    #[test]
+    fn unknown_arguments_get_a_hint() {
+        let mut reader = reader("");
+
+        reader.unknown("--bind_prot", &["tcp_split"]);
+        reader.unknown("--tcp_split", &["tcp_split"]);
+        reader.unknown("443", &["tcp_split"]);
+
+        let hints: Vec<Option<&str>> = reader.errors.iter().map(|error| error.hint.as_deref()).collect();
+
+        assert_eq!(hints, [
+            Some("did you mean --bind_port?"),
+            Some("tcp_split is a strategy, use --dpi_bypass_strategies tcp_split <positions>"),
+            Some("values must directly follow the flag they belong to"),
+        ]);
+
+        assert_eq!(reader.errors[2].message, "unexpected value `443`");
+        assert_eq!(reader.errors[2].origin, "argument 1");
+    }
+
+    #[test]
+    fn command_lines_survive_a_round_trip_through_a_file() {
+        let line = args("--bind_port 10000 --log_level debug --filter_sni a.test,b.test --filter_port 443 \
+            --dpi_bypass_strategies tcp_split,tcp_disorder 1+s 2+,5 --fallback_strategies tcp_split 3+s \
+            --reset_sni_filter --filter_writes 1-2 --dpi_bypass_strategies tcp_out_of_band 1");
+
+        let (file, warnings) = ConfigFile::from_args(&line);
+
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(file.settings["bind_port"], Value::from(10000));
+        assert_eq!(file.settings["log_level"], Value::from("debug"));
+        assert_eq!(file.rules.len(), 2);
+
+        assert_eq!(file.rules[0].hosts, ["a.test", "b.test"]);
+        assert_eq!(file.rules[0].ports.as_deref(), Some("443"));
+        assert_eq!(file.rules[0].strategies, [strategy("tcp_split", "1+s"), StrategyBlock { method: "tcp_disorder".to_string(), positions: args("2+ 5") }]);
+        assert_eq!(file.rules[0].fallbacks, [FallbackBlock { strategies: vec![strategy("tcp_split", "3+s")] }]);
+
+        assert!(file.rules[1].hosts.is_empty());
+        assert_eq!(file.rules[1].ports.as_deref(), Some("443"));
+        assert_eq!(file.rules[1].writes.as_deref(), Some("1-2"));
+
+        let (lowered, origins) = file.to_args("test.toml", &mut BTreeMap::new()).unwrap();
+
+        assert_eq!(lowered.len(), origins.len());
+        assert_eq!(origins[0], "test.toml, setting `bind_port`");
+        assert!(crate::core::parse_args_from(lowered.clone()).is_ok());
+
+        let (again, warnings) = ConfigFile::from_args(&lowered);
+
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(again.settings, file.settings);
+        assert_eq!(again.rules, file.rules);
+    }
+
+    #[test]
+    fn dropped_arguments_are_warned_about() {
+        let (file, warnings) = ConfigFile::from_args(&args("--no_such_flag --fallback_strategies tcp_split 1 --bind_port"));
+
+        assert!(file.rules.is_empty());
+        assert_eq!(warnings, [
+            "argument 1 (--no_such_flag) is not a known flag and was dropped",
+            "argument 2 (--fallback_strategies) has no rule to fall back from and was dropped",
+            "argument 5 (--bind_port) is missing its value",
+        ]);
+    }
+
+    #[test]
+    fn files_and_presets_leave_no_filters_behind() {
+        let path = std::env::temp_dir().join(format!("waterfall-leak-{}.toml", std::process::id()));
+
+        fs::write(&path, "[[rules]]\nprotocol = \"tcp\"\nports = \"443\"\nhosts = [\"a.test\"]\nwrites = \"2\"\nstack = \"AB\"\n\
+            [[rules.strategies]]\nmethod = \"tcp_split\"\npositions = [\"1+s\"]").unwrap();
+
+        let from_file = crate::core::parse_args_from(args(&format!("--config {} --dpi_bypass_strategies tcp_disorder 1", path.display())));
+
+        fs::remove_file(&path).unwrap();
+
+        let from_preset = crate::core::parse_args_from(args("--preset http --dpi_bypass_strategies tcp_disorder 1"));
+
+        for config in [from_file.unwrap(), from_preset.unwrap()] {
+            let last = &config.strategies.last().unwrap().data;
+
+            assert!(last.filter_protocol.is_none());
+            assert!(last.filter_port.is_none());
+            assert!(last.filter_sni.is_none());
+            assert!(last.trigger.writes.is_none());
+        }
+    }
+
+    #[test]
+    fn every_broken_rule_is_reported() {
+        let path = std::env::temp_dir().join(format!("waterfall-broken-{}.toml", std::process::id()));
+
+        fs::write(&path, "idle_timeut = 5\n\
+            [[rules]]\nports = \"443\"\n\
+            [[rules]]\n[[rules.strategies]]\nmethod = \"tcp_split\"\npositions = []\n\
+            [[rules]]\n[[rules.strategies]]\nmethod = \"tcp_split\"\npositions = [\"1\"]").unwrap();
+
+        let errors = crate::core::parse_args_from(args(&format!("--config {} --preset nosuch", path.display())));
+
+        fs::remove_file(&path).unwrap();
+
+        let path = path.display().to_string();
+
+        let reported: Vec<(String, String)> = errors
+            .unwrap_err()
+            .into_iter()
+            .map(|error| (error.origin, error.message))
+            .collect();
+
+        assert_eq!(reported, [
+            (format!("{}, setting `idle_timeut`", path), "not a global setting".to_string()),
+            (format!("{}, rule #1", path), "no strategies".to_string()),
+            (format!("{}, rule #2", path), "strategy tcp_split has no positions".to_string()),
+            ("argument 4".to_string(), "unknown preset `nosuch`".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn presets_are_pinned_by_version() {
+        let presets = BTreeMap::from([("mine".to_string(), preset("version = 2\n[[rules]]\n[[rules.strategies]]\nmethod = \"tcp_split\"\npositions = [\"1\"]"))]);
+
+        assert_eq!(find_preset(&presets, "mine", "here").unwrap().version, Some(2));
+        assert_eq!(find_preset(&presets, "mine@2", "here").unwrap().version, Some(2));
+
+        let error = find_preset(&presets, "mine@1", "here").unwrap_err();
+
+        assert_eq!(error.origin, "here");
+        assert_eq!(error.message, "preset mine is at version 2, not 1");
+        assert_eq!(error.hint.as_deref(), Some("review the changes and pin mine@2"));
+
+        assert_eq!(find_preset(&presets, "http@1", "here").unwrap().rules[0].ports.as_deref(), Some("80-80"));
+        assert!(find_preset(&presets, "http@2", "here").is_err());
+
+        let error = find_preset(&presets, "mien", "here").unwrap_err();
+
+        assert_eq!(error.message, "unknown preset `mien`");
+        assert_eq!(error.hint.as_deref(), Some("did you mean mine?"));
+    }
+
+    #[test]
+    fn presets_defined_in_files_shadow_built_in_ones() {
+        let presets = BTreeMap::from([("http".to_string(), preset("[[rules]]\nports = \"8080\""))]);
+
+        assert_eq!(find_preset(&presets, "http", "here").unwrap().rules[0].ports.as_deref(), Some("8080"));
+    }
+
+    #[test]
+    fn presets_that_include_each_other_are_refused() {
+        let presets = BTreeMap::from([
+            ("ping".to_string(), preset("[[rules]]\npreset = \"pong\"")),
+            ("pong".to_string(), preset("[[rules]]\npreset = \"ping\"")),
+        ]);
+
+        let rule = RuleBlock { preset: Some("ping".to_string()), ..RuleBlock::default() };
+        let error = expand_rule(&rule, &presets, "here", 0).unwrap_err();
+
+        assert!(error.message.ends_with("nests too deep"), "{}", error.message);
+        assert_eq!(error.hint.as_deref(), Some("check for presets that include each other"));
+    }
+
+    #[test]
+    fn preset_fields_are_layered_from_the_inside_out() {
+        let presets = BTreeMap::from([
+            ("base".to_string(), preset("\
+                [[rules]]\nprotocol = \"tcp\"\nports = \"443\"\nhosts = [\"a.test\"]\nwrites = \"1\"\n\
+                [[rules.strategies]]\nmethod = \"tcp_split\"\npositions = [\"1+s\"]\n\
+                [[rules]]\nprotocol = \"udp\"\n\
+                [[rules.strategies]]\nmethod = \"udp_0trail\"\npositions = [\"0\"]")),
+            ("outer".to_string(), preset("[[rules]]\npreset = \"base\"\nports = \"8443\"\nwrites = \"2\"")),
+        ]);
+
+        let rule = RuleBlock { preset: Some("outer".to_string()), hosts: vec!["b.test".to_string()], writes: Some("3".to_string()), ..RuleBlock::default() };
+        let rules = expand_rule(&rule, &presets, "here", 0).unwrap();
+
+        // The block naming a preset wins over the preset it names, which wins over the ones
+        // it includes, and rules keep the order of the innermost preset.
+
+        assert_eq!(rules.len(), 2);
+
+        assert_eq!(rules[0].protocol.as_deref(), Some("tcp"));
+        assert_eq!(rules[1].protocol.as_deref(), Some("udp"));
+
+        for rule in &rules {
+            assert_eq!(rule.preset, None);
+            assert_eq!(rule.ports.as_deref(), Some("8443"));
+            assert_eq!(rule.writes.as_deref(), Some("3"));
+            assert_eq!(rule.hosts, ["b.test"]);
+        }
+
+        assert_eq!(rules[0].strategies, [strategy("tcp_split", "1+s")]);
+        assert_eq!(rules[1].strategies, [strategy("udp_0trail", "0")]);
+    }
+}
@@ -0,0 +1,580 @@
+use crate::core::{self, AuxConfig};
+use crate::event;
+use crate::explain;
+use crate::relay::{self, Direction};
+
+use serde_json::{json, Value};
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7879";
+
+const MAX_BODY: usize = 65536;
+
+// No HTML form can send this header, so a browser has to ask before sending it cross-site, and
+// nothing here answers that preflight.
+
+const CONTROL_HEADER: &str = "X-Waterfall-Control";
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    length: usize,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// A body over MAX_BODY is read and dropped rather than kept, the request is turned down with
+// 413 before routing. Leaving it unread would reset the connection before the client reads why.
+
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    reader.read_line(&mut line)?;
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    let mut length: usize = 0;
+
+    loop {
+        line.clear();
+
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?;
+            }
+
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut body = Vec::new();
+
+    if length > MAX_BODY {
+        io::copy(&mut reader.by_ref().take(length as u64), &mut io::sink())?;
+    } else {
+        body.resize(length, 0);
+
+        reader.read_exact(&mut body)?;
+    }
+
+    Ok(Request { method, path, headers, length, body })
+}
+
+fn same_addr(host: &str, addr: &str) -> bool {
+    host.eq_ignore_ascii_case(addr) || host.parse::<SocketAddr>().is_ok_and(|host| addr.parse() == Ok(host))
+}
+
+// Loopback is reachable from every page open in a browser. A page on a rebound DNS name sends
+// its own name as Host, a cross-site one its Origin, and a plain form can only post text, so
+// changes need both names to be the control address, a JSON body and the control header.
+
+fn authorize(request: &Request, addr: &str, token: &str) -> Result<(), (u16, String)> {
+    if !request.header("host").is_some_and(|host| same_addr(host, addr)) {
+        return Err((403, format!("the Host header must be {}", addr)));
+    }
+
+    if request.header("origin").is_some_and(|origin| !origin.strip_prefix("http://").is_some_and(|origin| same_addr(origin, addr))) {
+        return Err((403, "cross-origin requests are not allowed".to_string()));
+    }
+
+    if !token.is_empty() && request.header("authorization") != Some(format!("Bearer {}", token).as_str()) {
+        return Err((401, "missing or wrong control token".to_string()));
+    }
+
+    if request.method != "GET" {
+        let json = request.header("content-type")
+            .and_then(|kind| kind.split(';').next())
+            .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("application/json"));
+
+        if !json {
+            return Err((415, "changes must be sent as application/json".to_string()));
+        }
+
+        if request.header(CONTROL_HEADER).is_none() {
+            return Err((403, format!("changes must carry the {} header", CONTROL_HEADER)));
+        }
+    }
+
+    if request.length > MAX_BODY {
+        return Err((413, format!("the body is larger than {} bytes", MAX_BODY)));
+    }
+
+    Ok(())
+}
+
+fn connections() -> Value {
+    let sessions: Vec<Value> = relay::active_sessions()
+        .iter()
+        .map(|session| {
+            let info = session.info.lock().unwrap().clone();
+
+            json!({
+                "id": session.id,
+                "client": session.client_addr(),
+                "target": info.target,
+                "sni": info.sni,
+                "strategies": info.strategies,
+                "outcome": info.outcome,
+                "set": info.set,
+                "upstream": session.relayed_bytes(Direction::Upstream),
+                "downstream": session.relayed_bytes(Direction::Downstream),
+                "age_ms": session.age().as_millis() as u64
+            })
+        })
+        .collect();
+
+    Value::from(sessions)
+}
+
+fn rule_index(config: &AuxConfig, rule: &str) -> Result<usize, String> {
+    rule.parse::<usize>()
+        .ok()
+        .filter(|rule| (1..=config.strategies.len()).contains(rule))
+        .map(|rule| rule - 1)
+        .ok_or(format!("no rule #{}, there are {}", rule, config.strategies.len()))
+}
+
+fn set_enabled(rule: &str, enabled: bool) -> Result<Value, String> {
+    core::update(|config| {
+        let index = rule_index(config, rule)?;
+
+        config.strategies[index].active = enabled;
+
+        Ok(json!({ "message": format!("rule #{} {}", index + 1, if enabled { "enabled" } else { "disabled" }) }))
+    })
+}
+
+// Adding a host to a rule without a host list gives it one, so from then on it only applies
+// to the hosts that were added. An empty list would match nothing, so removing the last host
+// takes the list away and the rule applies to every host again.
+
+fn edit_hosts(rule: &str, body: &[u8]) -> Result<Value, String> {
+    let edit: Value = serde_json::from_slice(body).map_err(|error| format!("invalid JSON body: {}", error))?;
+
+    let list = |key: &str| -> Result<Vec<String>, String> {
+        match edit.get(key) {
+            None => Ok(vec![]),
+            Some(Value::Array(hosts)) => hosts
+                .iter()
+                .map(|host| host.as_str().map(String::from).ok_or(format!("`{}` must only contain strings", key)))
+                .collect(),
+            Some(_) => Err(format!("`{}` must be a list of hosts", key))
+        }
+    };
+
+    let (add, remove) = (list("add")?, list("remove")?);
+
+    core::update(|config| {
+        let index = rule_index(config, rule)?;
+        let filter = &mut config.strategies[index].data.filter_sni;
+        let mut hosts = filter.take().unwrap_or_default();
+
+        hosts.retain(|host| !remove.contains(host));
+
+        for host in add {
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+
+        let message = match hosts.len() {
+            0 => format!("rule #{} now applies to every host", index + 1),
+            count => format!("rule #{} now has {} hosts", index + 1, count)
+        };
+
+        *filter = Some(hosts).filter(|hosts| !hosts.is_empty());
+
+        Ok(json!({ "message": message }))
+    })
+}
+
+fn route(request: &Request) -> (u16, Value) {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["connections"]) => Ok(connections()),
+        ("DELETE", ["connections", id]) => {
+            match id.parse::<u64>().ok().and_then(relay::find) {
+                Some(session) => {
+                    session.abort("closed through the control API");
+
+                    Ok(json!({ "message": format!("connection {} closed", id) }))
+                },
+                None => return (404, json!({ "error": format!("no active connection {}", id) }))
+            }
+        },
+        ("GET", ["rules"]) => Ok(explain::json(&core::config())["rules"].clone()),
+        ("POST", ["rules", rule, "enable"]) => set_enabled(rule, true),
+        ("POST", ["rules", rule, "disable"]) => set_enabled(rule, false),
+        ("POST", ["rules", rule, "hosts"]) => edit_hosts(rule, &request.body),
+        _ => return (404, json!({ "error": format!("no such endpoint: {} {}", request.method, request.path) }))
+    };
+
+    match result {
+        Ok(value) => (200, value),
+        Err(message) => (400, json!({ "error": message }))
+    }
+}
+
+fn respond(stream: TcpStream, addr: &str, token: &str) -> io::Result<()> {
+    let request = read_request(&stream)?;
+
+    let (status, body) = match authorize(&request, addr, token) {
+        Ok(()) => route(&request),
+        Err((status, message)) => (status, json!({ "error": message }))
+    };
+
+    if request.method != "GET" && status == 200 {
+        event!(Info, "control", method = request.method, path = request.path);
+    }
+
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        _ => "Not Found"
+    };
+
+    write!(&stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body)
+}
+
+pub fn serve(addr: &str, token: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (addr, token) = (addr.to_string(), token.to_string());
+
+    event!(Info, "control listening", addr = addr);
+
+    // Every request gets a thread of its own, so a client that sends slowly or not at all
+    // can't keep anyone else from killing connections.
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (addr, token) = (addr.clone(), token.clone());
+
+            thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(time::Duration::from_secs(5)));
+
+                if let Err(error) = respond(stream, &addr, &token) {
+                    event!(Debug, "control request failed", error = error.to_string());
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn call(addr: &str, token: &str, method: &str, path: &str, body: Option<Value>) -> io::Result<(u16, Value)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let authorization = if token.is_empty() { String::new() } else { format!("Authorization: Bearer {}\r\n", token) };
+
+    write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\n{}{}: 1\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method, path, addr, authorization, CONTROL_HEADER, body.len(), body)?;
+
+    let mut response = String::new();
+
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "malformed response"))?;
+
+    Ok((status, serde_json::from_str(body).map_err(io::Error::other)?))
+}
+
+fn print_connections(connections: &Value) {
+    let rows: Vec<[String; 7]> = connections
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|connection| [
+            connection["id"].to_string(),
+            connection["target"].as_str().unwrap_or_default().to_string(),
+            connection["sni"].as_str().unwrap_or("-").to_string(),
+            connection["strategies"].as_array().map(|names| names.iter().filter_map(Value::as_str).collect::<Vec<&str>>().join(",")).filter(|names| !names.is_empty()).unwrap_or("-".to_string()),
+            connection["upstream"].to_string(),
+            connection["downstream"].to_string(),
+            format!("{}s", connection["age_ms"].as_u64().unwrap_or(0) / 1000)
+        ])
+        .collect();
+
+    let header = ["id", "target", "sni", "strategies", "up", "down", "age"].map(String::from);
+
+    let widths: Vec<usize> = (0..7)
+        .map(|column| rows.iter().chain([&header]).map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+
+    for row in [&header].into_iter().chain(&rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+
+        println!("{}", line.trim_end());
+    }
+}
+
+const CTL_USAGE: &str = "Usage: waterfall ctl [--addr <host:port>] [--token <token>] [--json] <command>
+
+Commands:
+  connections                        List active connections
+  kill <id>                          Close a connection
+  rules                              List rules and whether they are enabled
+  enable <rule> | disable <rule>     Turn a rule (numbered as in --explain) on or off
+  hosts add|remove <rule> <host>...  Edit the host list of a rule
+
+The token is the proxy's --control_token, also read from WATERFALL_CONTROL_TOKEN. Changes apply
+to connections accepted afterwards and last until the next reload.";
+
+pub fn ctl_command(args: &[String]) -> io::Result<()> {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut token = std::env::var("WATERFALL_CONTROL_TOKEN").unwrap_or_default();
+    let mut json_output = false;
+    let mut words: Vec<&str> = Vec::new();
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => addr = args.next().cloned().unwrap_or_default(),
+            "--token" => token = args.next().cloned().unwrap_or_default(),
+            "--json" => json_output = true,
+            word => words.push(word)
+        }
+    }
+
+    let request = match words.as_slice() {
+        ["connections"] => Some(("GET", "/connections".to_string(), None)),
+        ["kill", id] => Some(("DELETE", format!("/connections/{}", id), None)),
+        ["rules"] => Some(("GET", "/rules".to_string(), None)),
+        ["enable", rule] => Some(("POST", format!("/rules/{}/enable", rule), None)),
+        ["disable", rule] => Some(("POST", format!("/rules/{}/disable", rule), None)),
+        ["hosts", action @ ("add" | "remove"), rule, hosts @ ..] if !hosts.is_empty() => {
+            Some(("POST", format!("/rules/{}/hosts", rule), Some(json!({ *action: hosts }))))
+        },
+        _ => None
+    };
+
+    let Some((method, path, body)) = request else {
+        eprintln!("{}", CTL_USAGE);
+
+        std::process::exit(2);
+    };
+
+    let (status, response) = call(&addr, &token, method, &path, body)
+        .map_err(|error| io::Error::new(error.kind(), format!("can't reach the control API at {}: {}", addr, error)))?;
+
+    if status != 200 {
+        eprintln!("error: {}", response["error"].as_str().unwrap_or("request failed"));
+
+        std::process::exit(1);
+    }
+
+    match response.get("message").and_then(Value::as_str) {
+        Some(message) if !json_output => println!("{}", message),
+        _ if words[0] == "connections" && !json_output => print_connections(&response),
+        _ => println!("{}", serde_json::to_string_pretty(&response).map_err(io::Error::other)?)
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    // The rules live in the one global snapshot, so tests that change it take turns.
+
+    static CONFIG: Mutex<()> = Mutex::new(());
+
+    fn install(args: &str) -> std::sync::MutexGuard<'static, ()> {
+        let guard = CONFIG.lock().unwrap_or_else(|error| error.into_inner());
+        let config = core::parse_args_from(args.split_whitespace().map(String::from).collect()).unwrap();
+
+        core::update(|current| {
+            *current = config;
+
+            Ok(())
+        }).unwrap();
+
+        guard
+    }
+
+    fn hosts(rule: usize) -> Option<Vec<String>> {
+        core::config().strategies[rule].data.filter_sni.clone()
+    }
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            length: body.len(),
+            body: body.as_bytes().to_vec()
+        }
+    }
+
+    const ADDR: &str = "127.0.0.1:7879";
+
+    const CHANGE: &[(&str, &str)] = &[("Host", ADDR), ("Content-Type", "application/json"), ("X-Waterfall-Control", "1")];
+
+    #[test]
+    fn removing_hosts_never_leaves_an_empty_filter() {
+        let _guard = install("--dpi_bypass_strategies tcp_split 1 --filter_sni a.test --dpi_bypass_strategies tcp_disorder 1");
+
+        edit_hosts("1", br#"{"remove": ["a.test"]}"#).unwrap();
+        assert_eq!(hosts(0), None);
+
+        edit_hosts("2", br#"{"add": ["a.test", "b.test", "a.test"]}"#).unwrap();
+        assert_eq!(hosts(1), Some(vec!["a.test".to_string(), "b.test".to_string()]));
+
+        edit_hosts("2", br#"{"remove": ["a.test", "b.test"]}"#).unwrap();
+        assert_eq!(hosts(1), None);
+    }
+
+    #[test]
+    fn host_edits_are_checked() {
+        let _guard = install("--dpi_bypass_strategies tcp_split 1");
+
+        assert!(edit_hosts("1", b"not json").is_err());
+        assert!(edit_hosts("1", br#"{"add": "a.test"}"#).is_err());
+        assert!(edit_hosts("1", br#"{"add": [1]}"#).is_err());
+        assert!(edit_hosts("2", br#"{"add": ["a.test"]}"#).is_err());
+        assert_eq!(hosts(0), None);
+    }
+
+    #[test]
+    fn rules_are_enabled_and_disabled() {
+        let _guard = install("--dpi_bypass_strategies tcp_split 1 --dpi_bypass_strategies tcp_disorder 1");
+
+        set_enabled("2", false).unwrap();
+        assert!(core::config().strategies[0].active);
+        assert!(!core::config().strategies[1].active);
+
+        set_enabled("2", true).unwrap();
+        assert!(core::config().strategies[1].active);
+
+        assert!(set_enabled("0", false).is_err());
+        assert!(set_enabled("3", false).is_err());
+        assert!(set_enabled("x", false).is_err());
+    }
+
+    #[test]
+    fn routes() {
+        let _guard = install("--dpi_bypass_strategies tcp_split 1");
+
+        assert_eq!(route(&request("GET", "/connections", &[], "")).0, 200);
+        assert_eq!(route(&request("GET", "/rules", &[], "")).0, 200);
+        assert_eq!(route(&request("POST", "/rules/1/disable", &[], "")).0, 200);
+        assert_eq!(route(&request("POST", "/rules/1/enable", &[], "")).0, 200);
+        assert_eq!(route(&request("POST", "/rules/1/hosts", &[], r#"{"add": ["a.test"]}"#)).0, 200);
+        assert_eq!(route(&request("POST", "/rules/2/enable", &[], "")).0, 400);
+        assert_eq!(route(&request("DELETE", "/connections/999999", &[], "")).0, 404);
+        assert_eq!(route(&request("GET", "/rules/1", &[], "")).0, 404);
+        assert_eq!(route(&request("PUT", "/rules/1/enable", &[], "")).0, 404);
+    }
+
+    #[test]
+    fn requests_from_browsers_are_refused() {
+        let status = |request: &Request, token: &str| authorize(request, ADDR, token).err().map(|(status, _)| status);
+
+        assert_eq!(status(&request("GET", "/connections", &[("Host", ADDR)], ""), ""), None);
+        assert_eq!(status(&request("POST", "/rules/1/disable", CHANGE, ""), ""), None);
+
+        assert_eq!(status(&request("GET", "/connections", &[], ""), ""), Some(403));
+        assert_eq!(status(&request("GET", "/connections", &[("Host", "rebound.example:7879")], ""), ""), Some(403));
+        assert_eq!(status(&request("GET", "/connections", &[("Host", ADDR), ("Origin", "https://example.com")], ""), ""), Some(403));
+        assert_eq!(status(&request("GET", "/connections", &[("Host", ADDR), ("Origin", "http://127.0.0.1:7879")], ""), ""), None);
+
+        assert_eq!(status(&request("POST", "/rules/1/disable", &[("Host", ADDR), ("X-Waterfall-Control", "1")], ""), ""), Some(415));
+        assert_eq!(status(&request("POST", "/rules/1/disable", &[("Host", ADDR), ("Content-Type", "text/plain")], ""), ""), Some(415));
+        assert_eq!(status(&request("POST", "/rules/1/disable", &[("Host", ADDR), ("Content-Type", "application/json; charset=utf-8")], ""), ""), Some(403));
+        assert_eq!(status(&request("DELETE", "/connections/1", &[("Host", ADDR), ("Content-Type", "application/json")], ""), ""), Some(403));
+    }
+
+    #[test]
+    fn token_is_required_when_set() {
+        let status = |headers: &[(&str, &str)]| authorize(&request("GET", "/connections", headers, ""), ADDR, "secret").err().map(|(status, _)| status);
+
+        assert_eq!(status(&[("Host", ADDR)]), Some(401));
+        assert_eq!(status(&[("Host", ADDR), ("Authorization", "Bearer wrong")]), Some(401));
+        assert_eq!(status(&[("Host", ADDR), ("Authorization", "Bearer secret")]), None);
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let body = vec![b' '; MAX_BODY + 1];
+
+            write!(stream, "POST /rules/1/hosts HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nX-Waterfall-Control: 1\r\nContent-Length: {}\r\n\r\n", addr, body.len()).unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let request = read_request(&stream).unwrap();
+
+        client.join().unwrap();
+
+        assert!(request.body.is_empty());
+        assert_eq!(request.length, MAX_BODY + 1);
+        assert_eq!(authorize(&request, &addr.to_string(), "").err().map(|(status, _)| status), Some(413));
+    }
+
+    #[test]
+    fn a_stalled_client_does_not_hold_up_the_others() {
+        let _guard = install("--dpi_bypass_strategies tcp_split 1");
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+        serve(&addr, "").unwrap();
+
+        let mut stalled = TcpStream::connect(&addr).unwrap();
+
+        write!(stalled, "GET /rules HTTP/1.1\r\n").unwrap();
+
+        let started = time::Instant::now();
+        let (status, _) = call(&addr, "", "GET", "/rules", None).unwrap();
+
+        assert_eq!(status, 200);
+        assert!(started.elapsed() < time::Duration::from_secs(2), "answered after {:?}", started.elapsed());
+    }
+}
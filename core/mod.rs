@@ -1,10 +1,13 @@
 use std::env;
 use std::time;
 use std::num::ParseIntError;
-use std::fs::File;
-use std::io::Read;
+use std::process;
+use std::sync::{Arc, RwLock};
 
-#[derive(Debug, Clone)]
+use crate::config::{ArgReader, ConfigError};
+use crate::logging::{Format, Level};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Strategies {
   NONE,
   SPLIT,
@@ -25,6 +28,148 @@ pub enum Strategies {
   FRAGTLS
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StackRequirement {
+  Any,
+  Disorder,
+  Split,
+  FakeFirst,
+  OobHell,
+  Meltdown
+}
+
+impl StackRequirement {
+  pub fn describe(&self) -> &'static str {
+    match self {
+      StackRequirement::Any => "any stack",
+      StackRequirement::Disorder => "a segment sent before an earlier one (BA)",
+      StackRequirement::Split => "at least two segments (AB)",
+      StackRequirement::FakeFirst => "a leading fake segment (FAB)",
+      StackRequirement::OobHell => "an out-of-band segment between two real ones (AOB)",
+      StackRequirement::Meltdown => "a fake segment followed by a real one (FAB)"
+    }
+  }
+}
+
+pub struct StrategyInfo {
+  pub name: &'static str,
+  pub method: Strategies,
+  pub protocol: &'static str,
+  pub positions: &'static str,
+  pub stack: StackRequirement,
+  pub summary: &'static str,
+}
+
+pub const STRATEGIES: &[StrategyInfo] = &[
+  StrategyInfo { name: "tcp_split", method: Strategies::SPLIT, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Split,
+    summary: "Split the write at the position and send both parts in order." },
+  StrategyInfo { name: "tcp_disorder", method: Strategies::DISORDER, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Disorder,
+    summary: "Split the write and send the first part with TTL 1, so it only arrives when retransmitted after the second." },
+  StrategyInfo { name: "tcp_disorder2", method: Strategies::DISORDER2, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Split,
+    summary: "Split the write, send the first part normally and the second with TTL 1." },
+  StrategyInfo { name: "tcp_fake_disordered", method: Strategies::FAKE, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::FakeFirst,
+    summary: "Send the first part with TTL 1, then a fake segment with --fake_packet_ttl, then the rest." },
+  StrategyInfo { name: "tcp_fake_insert", method: Strategies::FAKEMD, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::FakeFirst,
+    summary: "Send the first part, then a fake segment with --fake_packet_ttl, then the rest." },
+  StrategyInfo { name: "tcp_fake_surround", method: Strategies::FAKESURROUND, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::FakeFirst,
+    summary: "Send the first part between two fake segments with --fake_packet_ttl, then the rest." },
+  StrategyInfo { name: "tcp_fake2_disordered", method: Strategies::FAKE2DISORDER, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Disorder,
+    summary: "Send the first part, a fake copy of the second, then the second part with TTL 1." },
+  StrategyInfo { name: "tcp_fake2_insert", method: Strategies::FAKE2INSERT, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::FakeFirst,
+    summary: "Send the first part, then a fake built from the second part, then the rest." },
+  StrategyInfo { name: "tcp_out_of_band", method: Strategies::OOB, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Split,
+    summary: "Send the first part followed by one urgent byte (--out_of_band_charid), then the rest." },
+  StrategyInfo { name: "tcp_out_of_band_disorder", method: Strategies::DISOOB, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Disorder,
+    summary: "Like tcp_out_of_band, but the first part and the urgent byte go out with TTL 1." },
+  StrategyInfo { name: "tcp_out_of_band_disorder2", method: Strategies::OOB2, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Split,
+    summary: "Like tcp_out_of_band_disorder, and the second part goes out with TTL 1 as well." },
+  StrategyInfo { name: "tcp_meltdown", method: Strategies::MELTDOWN, protocol: "tcp", positions: "ignored", stack: StackRequirement::Meltdown,
+    summary: "Send the whole write with TTL 1, so it only arrives when retransmitted." },
+  StrategyInfo { name: "tcp_out_of_band_hell", method: Strategies::OOBSTREAMHELL, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::OobHell,
+    summary: "Send the first part, then --oob_stream_hell_data one urgent byte at a time, then the rest." },
+  StrategyInfo { name: "tls_record_frag", method: Strategies::FRAGTLS, protocol: "tcp", positions: "N+, N+s", stack: StackRequirement::Any,
+    summary: "Split the TLS record into two records, N counted from the start of the record payload." },
+  StrategyInfo { name: "udp_0trail", method: Strategies::TRAIL, protocol: "udp", positions: "ignored", stack: StackRequirement::Any,
+    summary: "UDP strategy; accepted, but UDP relaying is not implemented yet." },
+  StrategyInfo { name: "udp_meltdown", method: Strategies::MELTDOWNUDP, protocol: "udp", positions: "ignored", stack: StackRequirement::Meltdown,
+    summary: "UDP strategy; accepted, but UDP relaying is not implemented yet." },
+];
+
+impl Strategies {
+  pub fn info(&self) -> Option<&'static StrategyInfo> {
+    STRATEGIES
+        .iter()
+        .find(|info| info.method == *self)
+  }
+
+  pub fn from_name(name: &str) -> Option<Strategies> {
+    STRATEGIES
+        .iter()
+        .find(|info| info.name == name)
+        .map(|info| info.method.clone())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Family {
+  Split,
+  Disorder,
+  Fake,
+  OutOfBand,
+  Other
+}
+
+impl Strategies {
+  fn family(&self) -> Family {
+    match self {
+      Strategies::SPLIT => Family::Split,
+      Strategies::DISORDER | Strategies::DISORDER2 => Family::Disorder,
+      Strategies::FAKE | Strategies::FAKEMD | Strategies::FAKESURROUND | Strategies::FAKE2DISORDER | Strategies::FAKE2INSERT => Family::Fake,
+      Strategies::OOB | Strategies::DISOOB | Strategies::OOB2 | Strategies::OOBSTREAMHELL => Family::OutOfBand,
+      _ => Family::Other
+    }
+  }
+
+  pub fn uses_position(&self) -> bool {
+    !matches!(self, Strategies::MELTDOWN | Strategies::TRAIL | Strategies::MELTDOWNUDP)
+  }
+
+  pub fn sends_fakes(&self) -> bool {
+    self.family() == Family::Fake
+  }
+}
+
+// `auto` as the positions of a later strategy in a --dpi_bypass_strategies list repeats every
+// position of the first strategy one byte earlier. Only these combinations are paired.
+
+pub fn auto_pairs(base: &Strategies, paired: &Strategies) -> bool {
+  matches!((base.family(), paired.family()),
+    (Family::Disorder, Family::Split) |
+    (Family::Fake, Family::Disorder) |
+    (Family::Fake, Family::Split) |
+    (Family::OutOfBand, Family::Split))
+}
+
+// Positions are N, N+ or N- with optional s (relative to the SNI) and h (relative to the
+// Host header) flags, or auto.
+
+pub fn valid_position(position: &str) -> bool {
+  if position == "auto" {
+    return true;
+  }
+
+  let digits = position.len() - position.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+
+  if digits == 0 || position[..digits].parse::<i64>().is_err() {
+    return false;
+  }
+
+  match position[digits..].strip_prefix(['+', '-']) {
+    Some(flags) => flags.chars().all(|flag| flag == 's' || flag == 'h'),
+    None => digits == position.len()
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct WeakRange {
     pub start: u16,
@@ -61,6 +206,74 @@ impl WeakRange {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct StreamPosition {
+    pub write: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub elapsed: time::Duration,
+    pub last_elapsed: time::Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DesyncTrigger {
+    pub writes: Option<WeakRange>,
+    pub offsets: Vec<u64>,
+    pub after: Vec<time::Duration>,
+}
+
+impl DesyncTrigger {
+    fn has_window(&self) -> bool {
+        self.writes.is_some() || (self.offsets.is_empty() && self.after.is_empty())
+    }
+
+    fn window_contains(&self, packet_hop: u64, write: u64) -> bool {
+        match self.writes {
+            Some(ref range) => write >= range.start.into() && range.end.is_none_or(|end| write <= end.into()),
+            None => write <= packet_hop
+        }
+    }
+
+    // Whether a client write at this position gets desynced: either it falls into the
+    // write window, contains one of the byte offsets, or is the first write past a deadline.
+
+    pub fn fires(&self, packet_hop: u64, position: &StreamPosition) -> bool {
+        if self.has_window() && self.window_contains(packet_hop, position.write) {
+            return true;
+        }
+
+        if self.offsets
+            .iter()
+            .any(|offset| *offset >= position.offset && *offset < position.offset + position.len.max(1)) {
+            return true;
+        }
+
+        self.after
+            .iter()
+            .any(|after| *after <= position.elapsed && (*after > position.last_elapsed || position.write == 1))
+    }
+
+    // Whether no write after this position can fire any more, so the relay may stop hooking.
+
+    pub fn exhausted(&self, packet_hop: u64, position: &StreamPosition) -> bool {
+        if self.has_window() {
+            let open = match self.writes {
+                Some(ref range) => range.end.is_none_or(|end| position.write < end.into()),
+                None => position.write < packet_hop
+            };
+
+            if open {
+                return false;
+            }
+        }
+
+        let next_offset = position.offset + position.len;
+
+        self.offsets.iter().all(|offset| *offset < next_offset) &&
+            self.after.iter().all(|after| *after <= position.elapsed)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum NetworkProtocol {
     UDP,
@@ -77,11 +290,16 @@ pub struct Strategy {
 
   pub filter_protocol: Option<NetworkProtocol>,
   pub filter_port: Option<WeakRange>,
-  pub filter_sni: Option<Vec<String>>
+  pub filter_sni: Option<Vec<String>>,
+
+  pub trigger: DesyncTrigger,
+
+  // 0 for the strategies of a rule, N for its Nth --fallback_strategies set.
+  pub fallback: usize
 }
 
 impl Strategy {
-  pub fn from(first: String, second: String, subtract: bool, filter_protocol: &str, filter_port: &str, filter_sni: Option<Vec<String>>) -> Strategy {
+  pub fn from(first: String, second: String, subtract: bool, filter_protocol: &str, filter_port: &str, filter_sni: Option<Vec<String>>, trigger: DesyncTrigger) -> Strategy {
     let mut strategy: Strategy = Strategy {
       method: Strategies::NONE,
       base_index: 0,
@@ -90,15 +308,21 @@ impl Strategy {
       subtract,
       filter_protocol: None,
       filter_port: None,
-      filter_sni: None
+      filter_sni: None,
+      trigger,
+      fallback: 0
     };
 
     strategy.add_sni = second.contains('s');
     strategy.add_host = second.contains('h');
 
-    strategy.filter_protocol = Some(if filter_protocol == "tcp" { NetworkProtocol::TCP } else { NetworkProtocol::UDP });
+    strategy.filter_protocol = match filter_protocol {
+      "" => None,
+      "tcp" => Some(NetworkProtocol::TCP),
+      _ => Some(NetworkProtocol::UDP)
+    };
     strategy.filter_port = WeakRange::from(filter_port).ok();
-    strategy.filter_sni = filter_sni;
+    strategy.filter_sni = filter_sni.filter(|list| !list.is_empty());
 
     let separator = if second.contains('+') { "+" } else { "-" };
 
@@ -110,25 +334,10 @@ impl Strategy {
         strategy.base_index = if strategy.subtract { res - 1 } else { res };
     }
 
-    strategy.method = match first.as_str() {
-      "--tcp_split" => Strategies::SPLIT,
-      "--tcp_disorder" => Strategies::DISORDER,
-      "--tcp_disorder2" => Strategies::DISORDER2,
-      "--tcp_fake_disordered" => Strategies::FAKE,
-      "--tcp_fake_insert" => Strategies::FAKEMD,
-      "--tcp_fake_surround" => Strategies::FAKESURROUND,
-      "--tcp_fake2_disordered" => Strategies::FAKE2DISORDER,
-      "--tcp_fake2_insert" => Strategies::FAKE2INSERT,
-      "--tcp_out_of_band" => Strategies::OOB,
-      "--tcp_out_of_band_disorder" => Strategies::DISOOB,
-      "--tcp_out_of_band_disorder2" => Strategies::OOB2,
-      "--tcp_meltdown" => Strategies::MELTDOWN,
-      "--tcp_out_of_band_hell" => Strategies::OOBSTREAMHELL,
-      "--tls_record_frag" => Strategies::FRAGTLS,
-      "--udp_0trail" => Strategies::TRAIL,
-      "--udp_meltdown" => Strategies::MELTDOWNUDP,
-      _ => Strategies::NONE
-    };
+    strategy.method = first
+        .strip_prefix("--")
+        .and_then(Strategies::from_name)
+        .unwrap_or(Strategies::NONE);
 
     strategy
   }
@@ -178,10 +387,118 @@ pub struct AuxConfig {
   pub out_of_band_charid: u8,
   pub packet_hop: u64,
 
+  pub reassembly_timeout: time::Duration,
+  pub reassembly_max_size: usize,
+
+  pub handshake_timeout: time::Duration,
+  pub idle_timeout: time::Duration,
+  pub max_lifetime: time::Duration,
+  pub shutdown_grace: time::Duration,
+
+  pub log_level: Level,
+  pub log_format: Format,
+  pub log_file: String,
+
+  pub metrics_addr: String,
+
+  pub reset_window: time::Duration,
+  pub response_timeout: time::Duration,
+  pub block_page_patterns: Vec<String>,
+
+  pub fallback_retries: usize,
+  pub fallback_budget: time::Duration,
+
+  pub learn_file: String,
+  pub learn_expiry: time::Duration,
+  pub learn_demote_after: u32,
+
+  // Which fallback set of each rule applies, 0 for the rules' own strategies. Only the copies
+  // made while retrying a blocked connection set it.
+  pub attempt: usize,
+
+  pub control_addr: String,
+  pub control_token: String,
+
+  pub trace_dir: String,
+  pub trace_capture: bool,
+
   pub whitelist_sni: bool,
   pub whitelist_sni_list: Vec<String>,
 
   pub strategies: Vec<DataOverride::<Strategy>>,
+
+  pub source_files: Vec<String>,
+}
+
+impl Default for AuxConfig {
+  fn default() -> AuxConfig {
+    AuxConfig {
+      bind_host: String::from("127.0.0.1"),
+      bind_port: 7878u16,
+      bind_iface: String::from(""),
+      bind_iface_mtu: 8400,
+      bind_iface_ipv4: String::from("192.18.0.0"),
+      bind_iface_ipv6: String::from("fc00::1"),
+      fake_packet_ttl: 3,
+      fake_packet_sni: String::from("yandex.ru"),
+      fake_packet_send_http: false,
+      fake_packet_host: String::from("yandex.ru"),
+      fake_as_oob: false,
+      fake_packet_double: false,
+      fake_packet_reversed: false,
+      fake_packet_random: false,
+      fake_packet_override_data: DataOverride::<Vec<u8>> {
+        active: false,
+        data: vec![0u8]
+      },
+      oob_streamhell_data: String::from(".@nt1_r3@ss3mbly_101.yandex"),
+      disorder_packet_ttl: 8,
+      disable_sack: false,
+      default_ttl: 128,
+      out_of_band_charid: 213u8,
+      so_recv_size: 65535,
+      so_send_size: 65535,
+      so_opt_cutoff: 500,
+      packet_hop: 1,
+      reassembly_timeout: time::Duration::from_millis(500),
+      reassembly_max_size: 16389,
+      handshake_timeout: time::Duration::from_millis(10000),
+      idle_timeout: time::Duration::from_millis(300000),
+      max_lifetime: time::Duration::from_millis(0),
+      shutdown_grace: time::Duration::from_millis(10000),
+      log_level: Level::Info,
+      log_format: Format::Text,
+      log_file: String::new(),
+      metrics_addr: String::new(),
+      reset_window: time::Duration::from_millis(2000),
+      response_timeout: time::Duration::from_millis(5000),
+      block_page_patterns: vec![],
+      fallback_retries: 2,
+      fallback_budget: time::Duration::from_millis(10000),
+      learn_file: String::new(),
+      learn_expiry: time::Duration::from_millis(7 * 24 * 3600 * 1000),
+      learn_demote_after: 3,
+      attempt: 0,
+      control_addr: String::new(),
+      control_token: String::new(),
+      trace_dir: String::new(),
+      trace_capture: false,
+      l7_packet_jitter_max: time::Duration::from_millis(0),
+      http_host_cmix: false,
+      http_host_rmspace: false,
+      http_host_space: false,
+      http_domain_cmix: false,
+      fake_clienthello: false,
+      fake_clienthello_sni: String::from("yandex.ru"),
+
+      whitelist_sni: false,
+      whitelist_sni_list: vec![],
+
+      strategies: vec![],
+
+      source_files: vec![],
+    }
+  }
 }
 
 struct ResultPacket {
@@ -204,7 +521,7 @@ impl StrategyStack {
             .chars()
             .enumerate()
             .for_each(|(i, symbol)| strategy_stack.stack.push(match symbol {
-                'A' => ResultPacket { seqnum: i - 1, is_fake: false, oob: false },
+                'A' => ResultPacket { seqnum: i.wrapping_sub(1), is_fake: false, oob: false },
                 'B' => ResultPacket { seqnum: i + 1, is_fake: false, oob: false },
                 'F' => ResultPacket { seqnum: i, is_fake: true, oob: false },
                 'O' => ResultPacket { seqnum: i, is_fake: false, oob: true },
@@ -246,33 +563,33 @@ impl StrategyStack {
             .any(|arr| arr[0].is_fake && !arr[1].is_fake && !arr[1].oob)
     }
 
-    fn verify_signature(&self, strategies: Vec<Strategy>) -> Option<bool> {
+    fn satisfies(&self, requirement: StackRequirement) -> bool {
+        match requirement {
+            StackRequirement::Any => true,
+            StackRequirement::Disorder => self.can_disorder(),
+            StackRequirement::Split => self.can_split(),
+            StackRequirement::FakeFirst => self.has_fake_bit(),
+            StackRequirement::OobHell => self.can_oob_hell(),
+            StackRequirement::Meltdown => self.can_meltdown()
+        }
+    }
+
+    fn verify_signature(&self, strategies: Vec<Strategy>) -> Result<(), String> {
+        if self.stack.is_empty() {
+            return Ok(());
+        }
+
         for strategy in strategies {
-            match strategy.method {
-                crate::Strategies::DISORDER | 
-                crate::Strategies::FAKE2DISORDER | 
-                crate::Strategies::DISOOB if !self.can_disorder() => return None,
-
-                crate::Strategies::SPLIT | 
-                crate::Strategies::DISORDER2 | 
-                crate::Strategies::OOB | 
-                crate::Strategies::OOB2 if !self.can_split() => return None,
-
-                crate::Strategies::FAKE | 
-                crate::Strategies::FAKEMD | 
-                crate::Strategies::FAKESURROUND | 
-                crate::Strategies::FAKE2INSERT if !self.has_fake_bit() => return None,
-
-                crate::Strategies::OOBSTREAMHELL if !self.can_oob_hell() => return None,
-
-                crate::Strategies::MELTDOWN | 
-                crate::Strategies::MELTDOWNUDP if !self.can_meltdown() => return None,
-            
-                _ => (),
+            let Some(info) = strategy.method.info() else {
+                continue;
+            };
+
+            if !self.satisfies(info.stack) {
+                return Err(format!("{} needs a stack with {}", info.name, info.stack.describe()));
             }
         }
 
-        Some(true)
+        Ok(())
     }
 }
 
@@ -295,7 +612,7 @@ fn cutoff_options(so_clone: Socket, so_opt_cutoff: u64) {
     });
 }
 
-pub fn connect_socket(addr: SocketAddr) -> io::Result<TcpStream> {
+pub fn connect_socket(addr: SocketAddr, config: &AuxConfig) -> io::Result<TcpStream> {
     let domain_type = if addr.is_ipv4() {
         Domain::IPV4
     } else {
@@ -304,7 +621,7 @@ pub fn connect_socket(addr: SocketAddr) -> io::Result<TcpStream> {
 
     let socket = Socket::new(domain_type, Type::STREAM, Some(Protocol::TCP))?;
 
-    let AuxConfig { so_recv_size, so_send_size, so_opt_cutoff, .. } = parse_args();
+    let AuxConfig { so_recv_size, so_send_size, so_opt_cutoff, handshake_timeout, .. } = *config;
     
     socket.set_recv_buffer_size(so_recv_size)?;
     socket.set_send_buffer_size(so_send_size)?;
@@ -317,134 +634,135 @@ pub fn connect_socket(addr: SocketAddr) -> io::Result<TcpStream> {
         socket.set_only_v6(false)?;
     }
 
-    socket.connect(&addr.into())?;
+    socket.connect_timeout(&addr.into(), handshake_timeout)?;
     
     Ok(socket.into())
 }
 
-pub fn parse_args() -> AuxConfig {
-  let mut config: AuxConfig = AuxConfig {
-    bind_host: String::from("127.0.0.1"),
-    bind_port: 7878u16,
-    bind_iface: String::from(""),
-    bind_iface_mtu: 8400,
-    bind_iface_ipv4: String::from("192.18.0.0"),
-    bind_iface_ipv6: String::from("fc00::1"),
-    fake_packet_ttl: 3,
-    fake_packet_sni: String::from("yandex.ru"),
-    fake_packet_send_http: false,
-    fake_packet_host: String::from("yandex.ru"),
-    fake_as_oob: false,
-    fake_packet_double: false,
-    fake_packet_reversed: false,
-    fake_packet_random: false,
-    fake_packet_override_data: DataOverride::<Vec<u8>> {
-      active: false,
-      data: vec![0u8]
-    },
-    oob_streamhell_data: String::from(".@nt1_r3@ss3mbly_101.yandex"),
-    disorder_packet_ttl: 8,
-    disable_sack: false,
-    default_ttl: 128,
-    out_of_band_charid: 213u8,
-    so_recv_size: 65535,
-    so_send_size: 65535,
-    so_opt_cutoff: 500,
-    packet_hop: std::u64::MAX,
-    l7_packet_jitter_max: time::Duration::from_millis(0),
-    http_host_cmix: false,
-    http_host_rmspace: false,
-    http_host_space: false,
-    http_domain_cmix: false,
-    fake_clienthello: false,
-    fake_clienthello_sni: String::from("yandex.ru"),
-
-    whitelist_sni: false,
-    whitelist_sni_list: vec![],
-    
-    strategies: vec![],
-  };
+static CONFIG: RwLock<Option<Arc<AuxConfig>>> = RwLock::new(None);
 
-  let args: Vec<String> = env::args().skip(1).collect();
+// Every connection takes the snapshot that is current when it is accepted and keeps it
+// until it closes, so a reload only affects connections accepted afterwards.
 
-  let mut offset: usize = 0 as usize;
+pub fn config() -> Arc<AuxConfig> {
+  if let Some(ref config) = *CONFIG.read().unwrap() {
+    return config.clone();
+  }
 
-  let mut filter_protocol: &str = "";
-  let mut filter_port: &str = "";
+  reload().unwrap_or_else(|errors| {
+    crate::config::report(&errors);
 
-  let mut strategy_stack = StrategyStack::from(String::new());
+    process::exit(2);
+  })
+}
 
-  'reader: loop {
-    if args.len() == 0 {
-      break 'reader;
-    }
+pub fn reload() -> Result<Arc<AuxConfig>, Vec<ConfigError>> {
+  let config = Arc::new(parse_args()?);
 
-    if args.len() != 0 && offset > args.len() - 1 {
-      break 'reader;
-    }
+  *CONFIG.write().unwrap() = Some(config.clone());
 
-    match args[offset].as_str() {
-      "--filter_protocol" => {
-        offset += 1 as usize;
+  Ok(config)
+}
 
-        filter_protocol = &args[offset];
-      },
-      "--filter_port" => {
-        offset += 1 as usize;
+// Runtime changes from the control API are applied to a copy of the current snapshot, so like a
+// reload they only affect connections accepted afterwards. The next reload discards them.
 
-        filter_port = &args[offset];
-      },
-      "--bind_host" => {
-        offset += 1 as usize;
+pub fn update<T>(change: impl FnOnce(&mut AuxConfig) -> Result<T, String>) -> Result<T, String> {
+  let mut current = CONFIG.write().unwrap();
+  let mut config: AuxConfig = current.as_deref().cloned().unwrap_or_default();
 
-        config.bind_host = args[offset].clone();
-      },
-      "--bind_port" => {
-        offset += 1 as usize;
+  let result = change(&mut config)?;
 
-        config.bind_port = args[offset].parse::<u16>().expect("FATAL: bind_port argument exceeds uint16 limit.");
-      },
-      "--bind_iface" => {
-          offset += 1 as usize;
+  *current = Some(Arc::new(config));
 
-          config.bind_iface = args[offset].clone();
-      },
-      "--bind_iface_mtu" => {
-          offset += 1 as usize;
+  Ok(result)
+}
 
-          config.bind_iface_mtu = args[offset].parse::<u32>().expect("bind_iface_mtu must be u32");
-      },
-      "--bind_iface_ipv4" => {
-          offset += 1 as usize;
+pub fn parse_args() -> Result<AuxConfig, Vec<ConfigError>> {
+  parse_args_from(env::args().skip(1).collect())
+}
 
-          config.bind_iface_ipv4 = args[offset].clone();
-      },
-      "--bind_iface_ipv6" => {
-          offset += 1 as usize;
+pub fn parse_args_from(args: Vec<String>) -> Result<AuxConfig, Vec<ConfigError>> {
+  let mut config: AuxConfig = AuxConfig::default();
 
-          config.bind_iface_ipv6 = args[offset].clone();
+  let (args, origins) = crate::config::expand_args(args, &mut config.source_files)?;
+
+  let mut reader = ArgReader::new(args, origins);
+
+  let mut filter_protocol = String::new();
+  let mut filter_port = String::new();
+  let mut trigger = DesyncTrigger::default();
+
+  let mut strategy_stack = StrategyStack::from(String::new());
+  let mut fallback: usize = 0;
+
+  let strategy_names: Vec<&'static str> = STRATEGIES
+      .iter()
+      .map(|info| info.name)
+      .collect();
+
+  while let Some(arg) = reader.next() {
+    match arg.as_str() {
+      "--help" | "--version" | "--config" | "--check-config" | "--explain" | "--print-rules" | "--json" => { },
+      "--filter_protocol" => {
+        if let Some(protocol) = reader.value() {
+          match protocol.as_str() {
+            "" | "tcp" | "udp" => filter_protocol = protocol,
+            _ => reader.error(format!("unknown protocol `{}`", protocol), Some("use tcp, udp or an empty string for both".to_string()))
+          }
+        }
       },
-      "--fake_packet_ttl" => {
-        offset += 1 as usize;
+      "--filter_port" => {
+        if let Some(ports) = reader.value() {
+          if !ports.is_empty() && WeakRange::from(&ports).is_err() {
+            reader.error(format!("`{}` is not a port range", ports), Some("ranges look like 443, 443- or 50000-51000".to_string()));
+          }
 
-        config.fake_packet_ttl = args[offset].parse::<u8>().expect("FATAL: fake_packet_ttl argument exceeds uint8 limit.");
+          filter_port = ports;
+        }
+      },
+      "--filter_writes" => {
+        if let Some(writes) = reader.value() {
+          // Unlike a port, a bare write number means that write only.
+
+          match WeakRange::from(&writes) {
+            Ok(range) if !writes.contains('-') => trigger.writes = Some(WeakRange { end: Some(range.start), ..range }),
+            Ok(range) => trigger.writes = Some(range),
+            Err(_) => reader.error(format!("`{}` is not a write range", writes), Some("ranges look like 1, 2- or 1-3".to_string()))
+          }
+        }
+      },
+      "--desync_at_offset" => {
+        if let Some(offsets) = reader.numbers::<u64>() {
+          trigger.offsets = offsets;
+        }
       },
+      "--desync_after_ms" => {
+        if let Some(after) = reader.numbers::<u64>() {
+          trigger.after = after
+              .into_iter()
+              .map(time::Duration::from_millis)
+              .collect();
+        }
+      },
+      "--reset_desync_triggers" => {
+        trigger = DesyncTrigger::default();
+      },
+      "--bind_host" => reader.text(&mut config.bind_host),
+      "--bind_port" => reader.number(&mut config.bind_port),
+      "--bind_iface" => reader.text(&mut config.bind_iface),
+      "--bind_iface_mtu" => reader.number(&mut config.bind_iface_mtu),
+      "--bind_iface_ipv4" => reader.text(&mut config.bind_iface_ipv4),
+      "--bind_iface_ipv6" => reader.text(&mut config.bind_iface_ipv6),
+      "--fake_packet_ttl" => reader.number(&mut config.fake_packet_ttl),
       "--send_fake_clienthello" => {
         config.fake_clienthello = true;
       },
       "--disable_sack" => {
         config.disable_sack = true;
       },
-      "--fc_sni" => {
-        offset += 1 as usize;
-
-        config.fake_clienthello_sni = args[offset].clone();
-      },
-      "--fake_packet_sni" => {
-        offset += 1 as usize;
-
-        config.fake_packet_sni = args[offset].clone();
-      },
+      "--fc_sni" => reader.text(&mut config.fake_clienthello_sni),
+      "--fake_packet_sni" => reader.text(&mut config.fake_packet_sni),
       "--fake_packet_send_http" => {
         config.fake_packet_send_http = true;
       },
@@ -463,65 +781,91 @@ pub fn parse_args() -> AuxConfig {
       "--http_host_space" => {
         config.http_host_space = true;
       },
-      "--fake_packet_host" => {
-        offset += 1 as usize;
-
-        config.fake_packet_host = args[offset].clone();
-      },
+      "--fake_packet_host" => reader.text(&mut config.fake_packet_host),
       "--fake_packet_str" => {
-        offset += 1 as usize;
-
-        config.fake_packet_override_data = DataOverride::<Vec<u8>> {
-          active: true,
-          data: Vec::from(args[offset].as_bytes())
-        };
+        if let Some(text) = reader.value() {
+          config.fake_packet_override_data = DataOverride::<Vec<u8>> {
+            active: true,
+            data: Vec::from(text.as_bytes())
+          };
+        }
       },
       "--fake_packet_hex" => {
-        offset += 1 as usize;
-
-        let hex_str = &args[offset];
-
-        let hex_bytes = (0..hex_str.len())
-            .step_by(2)
-            .map(|i| u8::from_str_radix(&hex_str[i..(i + 2)], 16).unwrap())
-            .collect::<Vec<u8>>();
-
-        config.fake_packet_override_data = DataOverride::<Vec<u8>> {
-          active: true,
-          data: hex_bytes
-        };
+        if let Some(hex_str) = reader.value() {
+          match crate::config::parse_hex(&hex_str) {
+            Ok(hex_bytes) => {
+              config.fake_packet_override_data = DataOverride::<Vec<u8>> {
+                active: true,
+                data: hex_bytes
+              };
+            },
+            Err(message) => reader.error(format!("--fake_packet_hex: {}", message), Some("the payload is written as pairs of hex digits, e.g. 160301".to_string()))
+          }
+        }
       },
       "--fake_packet_file" => {
-          offset += 1 as usize;
-
-          if let Some(path) = args[offset].split("file://").nth(1) {
-            let mut file: File = File::open(path).unwrap();
-
-            let mut fake_data: Vec<u8> = Vec::new();
-
-            let _ = file.read_to_end(&mut fake_data);
+        if let Some((path, fake_data)) = reader.file() {
+          config.source_files.push(path);
 
-            config.fake_packet_override_data = DataOverride::<Vec<u8>> {
-                active: true,
-                data: fake_data
-            };
+          config.fake_packet_override_data = DataOverride::<Vec<u8>> {
+            active: true,
+            data: fake_data
+          };
+        }
+      },
+      "--oob_stream_hell_data" => reader.text(&mut config.oob_streamhell_data),
+      "--disorder_packet_ttl" => reader.number(&mut config.disorder_packet_ttl),
+      "--packet_hop" => reader.number(&mut config.packet_hop),
+      "--reassembly_timeout" => reader.millis(&mut config.reassembly_timeout),
+      "--reassembly_max_size" => reader.number(&mut config.reassembly_max_size),
+      "--handshake_timeout" => reader.millis(&mut config.handshake_timeout),
+      "--idle_timeout" => reader.millis(&mut config.idle_timeout),
+      "--max_lifetime" => reader.millis(&mut config.max_lifetime),
+      "--shutdown_grace" => reader.millis(&mut config.shutdown_grace),
+      "--log_level" => {
+        if let Some(level) = reader.value() {
+          match level.parse() {
+            Ok(level) => config.log_level = level,
+            Err(message) => reader.error(message, None)
           }
-      }
-      "--oob_stream_hell_data" => {
-          offset += 1 as usize;
-
-          config.oob_streamhell_data = args[offset].clone();
+        }
       },
-      "--disorder_packet_ttl" => {
-        offset += 1 as usize;
-
-        config.disorder_packet_ttl = args[offset].parse::<u8>().expect("FATAL: disorder_packet_ttl argument exceeds uint8 limit.");
+      "--log_format" => {
+        if let Some(format) = reader.value() {
+          match format.parse() {
+            Ok(format) => config.log_format = format,
+            Err(message) => reader.error(message, None)
+          }
+        }
       },
-      "--packet_hop" => {
-        offset += 1 as usize;
+      "--log_file" => reader.text(&mut config.log_file),
+      "--metrics_addr" => reader.text(&mut config.metrics_addr),
+      "--reset_window" => reader.millis(&mut config.reset_window),
+      "--response_timeout" => reader.millis(&mut config.response_timeout),
+      "--fallback_retries" => reader.number(&mut config.fallback_retries),
+      "--fallback_budget" => reader.millis(&mut config.fallback_budget),
+      "--learn_file" => reader.text(&mut config.learn_file),
+      "--learn_expiry" => reader.millis(&mut config.learn_expiry),
+      "--learn_demote_after" => reader.number(&mut config.learn_demote_after),
+      "--block_page_patterns" => {
+        if let Some(patterns) = reader.value() {
+          config.block_page_patterns.extend(patterns.split(',').filter(|pattern| !pattern.is_empty()).map(str::to_lowercase));
+        }
+      },
+      "--trace_dir" => reader.text(&mut config.trace_dir),
+      "--trace_capture" => {
+        config.trace_capture = true;
+      },
+      "--control_addr" => {
+        if let Some(addr) = reader.value() {
+          if !addr.is_empty() && !addr.parse::<SocketAddr>().is_ok_and(|addr| addr.ip().is_loopback()) {
+            reader.error(format!("`{}` is not a loopback address", addr), Some("the control API only listens on loopback, e.g. 127.0.0.1:7879".to_string()));
+          }
 
-        config.packet_hop = args[offset].parse::<u64>().expect("FATAL: packet_hop argument exceeds uint64 limit.");
+          config.control_addr = addr;
+        }
       },
+      "--control_token" => reader.text(&mut config.control_token),
       "--fake_packet_random" => {
         config.fake_packet_random = true;
       },
@@ -531,52 +875,36 @@ pub fn parse_args() -> AuxConfig {
       "--fake_packet_reversed" => {
         config.fake_packet_reversed = true;
       },
-      "--so_recv_size" => {
-          offset += 1 as usize;
-
-          config.so_recv_size = args[offset].parse::<usize>().expect("Receive buffer size must be usize"); 
-      },
-      "--so_send_size" => {
-          offset += 1 as usize;
-
-          config.so_send_size = args[offset].parse::<usize>().expect("Send buffer size must be usize"); 
-      },
-      "--so_opt_cutoff" => {
-          offset += 1 as usize;
-
-          config.so_opt_cutoff = args[offset].parse::<u64>().expect("Socket bufsize reset cutoff must be u64"); 
-      },
-      "--default_ttl" => {
-        offset += 1 as usize;
-
-        config.default_ttl = args[offset].parse::<u8>().expect("FATAL: default_ttl argument exceeds uint8 limit.");
-      },
-      "--out_of_band_charid" => {
-        offset += 1 as usize;
-
-        config.out_of_band_charid = args[offset].parse::<u8>().expect("FATAL: out_of_band_charid argument exceeds uint8 limit.");
-      },
+      "--so_recv_size" => reader.number(&mut config.so_recv_size),
+      "--so_send_size" => reader.number(&mut config.so_send_size),
+      "--so_opt_cutoff" => reader.number(&mut config.so_opt_cutoff),
+      "--default_ttl" => reader.number(&mut config.default_ttl),
+      "--out_of_band_charid" => reader.number(&mut config.out_of_band_charid),
       "--filter_sni" => {
-        offset += 1 as usize;
+        let Some(hosts) = reader.value() else {
+          continue;
+        };
 
         config.whitelist_sni = true;
 
-        if let Some(path) = args[offset].split("file://").nth(1) {
-            let mut file: File = File::open(path).unwrap();
-
-            let mut hosts_list: String = String::new();
+        if hosts.starts_with("file://") {
+          if let Some((path, hosts_list)) = reader.read_file(&hosts) {
+            config.source_files.push(path);
 
-            let _ = file.read_to_string(&mut hosts_list);
-
-            hosts_list
-                .split("\n")
-                .for_each(|sni| config.whitelist_sni_list.push(sni.replace("\r", "").to_string()));
+            String::from_utf8_lossy(&hosts_list)
+                .lines()
+                .map(str::trim)
+                .filter(|sni| !sni.is_empty())
+                .for_each(|sni| config.whitelist_sni_list.push(sni.to_string()));
+          }
 
-            continue;
+          continue;
         }
 
-        args[offset]
+        hosts
             .split(",")
+            .map(str::trim)
+            .filter(|sni| !sni.is_empty())
             .for_each(|sni| config.whitelist_sni_list.push(sni.to_string()))
       },
       "--reset_sni_filter" => {
@@ -584,82 +912,323 @@ pub fn parse_args() -> AuxConfig {
           config.whitelist_sni_list.drain(0..config.whitelist_sni_list.len());
       },
       "--resist_timing_attack" => {
-        offset += 1 as usize;
+        let mut jitter: u8 = 0;
+
+        reader.number(&mut jitter);
 
-        config.l7_packet_jitter_max = time::Duration::from_millis(args[offset].parse::<u8>().expect("Packet jitter should be 0-255").into());
+        config.l7_packet_jitter_max = time::Duration::from_millis(jitter.into());
       },
       "--strategy_stack" => {
-        offset += 1 as usize;
+        if let Some(stack) = reader.value() {
+          if let Some(symbol) = stack.chars().find(|symbol| !"ABFO".contains(*symbol)) {
+            reader.error(format!("unknown stack symbol `{}` in `{}`", symbol, stack), Some("stacks are built from A, B, F and O, e.g. BA or FABFBA".to_string()));
+          }
 
-        strategy_stack = StrategyStack::from(args[offset].clone());
+          strategy_stack = StrategyStack::from(stack);
+        }
       },
-      "--dpi_bypass_strategies" => {
-          offset += 1 as usize;
+      "--dpi_bypass_strategies" | "--fallback_strategies" => {
+          let Some(names) = reader.value_for("a comma separated list of strategies") else {
+            continue;
+          };
 
-          let mut bypass_strategies = args[offset]
-              .split(",")
-              .map(|n| n.to_string())
-              .collect::<Vec<String>>();
+          let names_at = reader.last();
+
+          if arg == "--dpi_bypass_strategies" {
+            fallback = 0;
+          } else if config.strategies.is_empty() {
+            reader.error_at(names_at, "--fallback_strategies has no rule to fall back from".to_string(), Some("put it after the --dpi_bypass_strategies it replaces when that one is blocked".to_string()));
+          } else {
+            fallback += 1;
+          }
 
-          let base_strategy_name = bypass_strategies[0].clone();
-          let base_opt_pos = args[offset + 1]
+          let bypass_strategies = names
               .split(",")
               .map(|n| n.to_string())
               .collect::<Vec<String>>();
 
-          for index in &base_opt_pos {
-              config.strategies.push(DataOverride::<Strategy> {
-                  active: true,
-                  data: Strategy::from("--".to_owned() + &base_strategy_name.clone(), index.to_string(), false, filter_protocol, filter_port, Some(config.whitelist_sni_list.clone()))
-              });
-          }
+          let mut base_opt_pos: Vec<String> = Vec::new();
 
-          bypass_strategies.drain(0..1);
+          let first_new_strategy = config.strategies.len();
 
-          if bypass_strategies.len() == 0 {
-              continue;
-          }
+          for (strategy_index, strategy) in bypass_strategies.iter().enumerate() {
+              if Strategies::from_name(strategy).is_none() {
+                  let hint = crate::config::suggest(strategy, strategy_names.iter().copied())
+                      .map(|name| format!("did you mean {}?", name))
+                      .unwrap_or_else(|| format!("known strategies: {}", strategy_names.join(", ")));
+
+                  reader.error_at(names_at, format!("unknown strategy `{}`", strategy), Some(hint));
+              }
+
+              let Some(positions) = reader.value_for(&format!("positions for {}", strategy)) else {
+                  break;
+              };
 
-          let mut strategy_index = 1;
+              let positions = positions
+                  .split(",")
+                  .map(|n| n.to_string())
+                  .collect::<Vec<String>>();
 
-          for strategy in bypass_strategies {
-              strategy_index += 1;
+              for position in positions.iter().filter(|position| !valid_position(position)) {
+                  reader.error(format!("`{}` is not a valid position for {}", position, strategy), Some("positions look like 2, 2+, 2+s, 2- or auto".to_string()));
+              }
+
+              let method = Strategies::from_name(strategy);
+              let positional = method.as_ref().is_some_and(Strategies::uses_position);
+
+              if strategy_index == 0 {
+                  if positional && positions.iter().any(|position| position == "auto") {
+                      reader.error(format!("auto can't be used for {}, the first strategy of the list", strategy), Some("auto repeats the positions of the first strategy, so give that one explicit positions".to_string()));
+                  }
 
-              let strategy_opt_pos = args[offset + strategy_index].clone();
+                  base_opt_pos = positions.clone();
 
-              if cfg!(windows) && strategy_opt_pos == "auto" {
                   for index in &base_opt_pos {
-                      if (base_strategy_name.contains("disorder") && strategy.contains("split")) ||
-                         (base_strategy_name.contains("fake") && strategy.contains("disorder")) { 
-                          config.strategies.push(DataOverride::<Strategy> {
-                              active: true,
-                              data: Strategy::from("--".to_owned() + &strategy.clone(), String::from(index), true, filter_protocol, filter_port, Some(config.whitelist_sni_list.clone()))
-                          });
-                      }
+                      config.strategies.push(DataOverride::<Strategy> {
+                          active: true,
+                          data: Strategy::from("--".to_owned() + strategy, index.to_string(), false, &filter_protocol, &filter_port, Some(config.whitelist_sni_list.clone()), trigger.clone())
+                      });
+                  }
+
+                  continue;
+              }
+
+              for strategy_opt_pos in &positions {
+                  if strategy_opt_pos != "auto" || !positional {
+                      config.strategies.push(DataOverride::<Strategy> {
+                          active: true,
+                          data: Strategy::from("--".to_owned() + strategy, String::from(strategy_opt_pos), false, &filter_protocol, &filter_port, Some(config.whitelist_sni_list.clone()), trigger.clone())
+                      });
+
+                      continue;
+                  }
+
+                  match (Strategies::from_name(&bypass_strategies[0]), &method) {
+                      (Some(base), Some(paired)) if auto_pairs(&base, paired) => {
+                          for index in &base_opt_pos {
+                              config.strategies.push(DataOverride::<Strategy> {
+                                  active: true,
+                                  data: Strategy::from("--".to_owned() + strategy, String::from(index), true, &filter_protocol, &filter_port, Some(config.whitelist_sni_list.clone()), trigger.clone())
+                              });
+                          }
+                      },
+                      (Some(_), Some(_)) => {
+                          reader.error(format!("auto can't pair {} with {}", strategy, bypass_strategies[0]), Some("auto pairs a split after disorder, fake or out-of-band strategies, and a disorder after fake strategies".to_string()));
+                      },
+                      _ => { }
                   }
-              } else {
-                  config.strategies.push(DataOverride::<Strategy> {
-                      active: true,
-                      data: Strategy::from("--".to_owned() + &strategy, String::from(&strategy_opt_pos), false, filter_protocol, filter_port, Some(config.whitelist_sni_list.clone()))
-                  });
               }
           }
 
-          if strategy_stack.verify_signature(config.strategies
-              .clone()
+          config.strategies[first_new_strategy..]
+              .iter_mut()
+              .for_each(|strategy| strategy.data.fallback = fallback);
+
+          if let Err(message) = strategy_stack.verify_signature(config.strategies[first_new_strategy..]
               .iter()
               .map(|n| n.data.clone())
-              .collect::<Vec<Strategy>>()).is_none() {
-              panic!("Strategy stack violated");
+              .collect::<Vec<Strategy>>()) {
+              reader.error_at(names_at, message, Some("change --strategy_stack or drop it to skip the check".to_string()));
           }
-
-          offset += strategy_index as usize;
       },
-      _ => { }
+      _ => reader.unknown(&arg, &strategy_names)
+    }
+  }
+
+  if !reader.errors.is_empty() {
+    return Err(reader.errors);
+  }
+
+  Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &str) -> Result<AuxConfig, Vec<ConfigError>> {
+    parse_args_from(args.split_whitespace().map(String::from).collect())
+  }
+
+  fn placed(config: &AuxConfig) -> Vec<(&'static str, i64, bool)> {
+    config.strategies
+        .iter()
+        .map(|strategy| (strategy.data.method.info().unwrap().name, strategy.data.base_index, strategy.data.add_sni))
+        .collect()
+  }
+
+  #[test]
+  fn auto_pairs_known_combinations() {
+    assert!(auto_pairs(&Strategies::DISORDER, &Strategies::SPLIT));
+    assert!(auto_pairs(&Strategies::FAKE, &Strategies::DISORDER));
+    assert!(auto_pairs(&Strategies::FAKEMD, &Strategies::SPLIT));
+    assert!(auto_pairs(&Strategies::OOB, &Strategies::SPLIT));
+    assert!(auto_pairs(&Strategies::DISOOB, &Strategies::SPLIT));
+
+    assert!(!auto_pairs(&Strategies::SPLIT, &Strategies::DISORDER));
+    assert!(!auto_pairs(&Strategies::DISORDER, &Strategies::FAKE));
+    assert!(!auto_pairs(&Strategies::FRAGTLS, &Strategies::SPLIT));
+  }
+
+  #[test]
+  fn auto_repeats_base_positions_one_byte_earlier() {
+    let config = parse("--dpi_bypass_strategies tcp_disorder,tcp_split 2+,3+s auto").unwrap();
+
+    assert_eq!(placed(&config), vec![
+      ("tcp_disorder", 2, false),
+      ("tcp_disorder", 3, true),
+      ("tcp_split", 1, false),
+      ("tcp_split", 2, true),
+    ]);
+  }
+
+  #[test]
+  fn auto_pairs_fake_and_out_of_band_with_split() {
+    let config = parse("--dpi_bypass_strategies tcp_fake_insert,tcp_split 4+ auto").unwrap();
+
+    assert_eq!(placed(&config), vec![("tcp_fake_insert", 4, false), ("tcp_split", 3, false)]);
+
+    let config = parse("--dpi_bypass_strategies tcp_out_of_band,tcp_split 5+s auto").unwrap();
+
+    assert_eq!(placed(&config), vec![("tcp_out_of_band", 5, true), ("tcp_split", 4, true)]);
+  }
+
+  #[test]
+  fn auto_mixes_with_explicit_positions() {
+    let config = parse("--dpi_bypass_strategies tcp_fake_disordered,tcp_disorder 6+ auto,9+").unwrap();
+
+    assert_eq!(placed(&config), vec![
+      ("tcp_fake_disordered", 6, false),
+      ("tcp_disorder", 5, false),
+      ("tcp_disorder", 9, false),
+    ]);
+  }
+
+  #[test]
+  fn auto_rejects_unpaired_combinations() {
+    let errors = parse("--dpi_bypass_strategies tcp_split,tcp_disorder 2+ auto").unwrap_err();
+
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].message.contains("can't pair"));
+  }
+
+  #[test]
+  fn auto_needs_explicit_base_positions() {
+    let errors = parse("--dpi_bypass_strategies tcp_disorder,tcp_split auto auto").unwrap_err();
+
+    assert!(errors[0].message.contains("first strategy"));
+  }
+
+  #[test]
+  fn auto_is_accepted_where_positions_are_ignored() {
+    let config = parse("--dpi_bypass_strategies udp_0trail,udp_meltdown auto 2+s").unwrap();
+
+    assert_eq!(placed(&config), vec![("udp_0trail", 0, false), ("udp_meltdown", 2, true)]);
+  }
+
+  fn at(write: u64, offset: u64, len: u64) -> StreamPosition {
+    StreamPosition { write, offset, len, ..StreamPosition::default() }
+  }
+
+  fn after(write: u64, elapsed: u64, last_elapsed: u64) -> StreamPosition {
+    StreamPosition {
+      write,
+      elapsed: time::Duration::from_millis(elapsed),
+      last_elapsed: time::Duration::from_millis(last_elapsed),
+      ..StreamPosition::default()
     }
+  }
+
+  #[test]
+  fn trigger_defaults_to_the_first_packet_hop_writes() {
+    let trigger = DesyncTrigger::default();
+
+    assert!(trigger.fires(1, &at(1, 0, 10)));
+    assert!(!trigger.fires(1, &at(2, 10, 10)));
+    assert!(trigger.fires(2, &at(2, 10, 10)));
+
+    assert!(!trigger.exhausted(2, &at(1, 0, 10)));
+    assert!(trigger.exhausted(2, &at(2, 10, 10)));
+  }
+
+  #[test]
+  fn trigger_fires_inside_the_write_range() {
+    let trigger = DesyncTrigger { writes: Some(WeakRange::from("2-3").unwrap()), ..DesyncTrigger::default() };
+
+    assert!(!trigger.fires(1, &at(1, 0, 10)));
+    assert!(trigger.fires(1, &at(2, 10, 10)));
+    assert!(trigger.fires(1, &at(3, 20, 10)));
+    assert!(!trigger.fires(1, &at(4, 30, 10)));
+
+    assert!(!trigger.exhausted(1, &at(2, 10, 10)));
+    assert!(trigger.exhausted(1, &at(3, 20, 10)));
+
+    let open = DesyncTrigger { writes: Some(WeakRange::from("2-").unwrap()), ..DesyncTrigger::default() };
+
+    assert!(open.fires(1, &at(100, 1000, 10)));
+    assert!(!open.exhausted(1, &at(100, 1000, 10)));
+  }
+
+  #[test]
+  fn trigger_fires_on_the_read_that_holds_an_offset() {
+    let trigger = DesyncTrigger { offsets: vec![100], ..DesyncTrigger::default() };
+
+    assert!(!trigger.fires(1, &at(1, 0, 100)));
+    assert!(trigger.fires(1, &at(2, 90, 20)));
+    assert!(trigger.fires(1, &at(2, 100, 1)));
+    assert!(!trigger.fires(1, &at(3, 101, 50)));
+
+    // A read that ends right before the offset leaves it for the next one.
 
-    offset += 1 as usize;
+    assert!(!trigger.exhausted(1, &at(1, 0, 100)));
+    assert!(trigger.exhausted(1, &at(2, 90, 20)));
   }
 
-  config
+  #[test]
+  fn trigger_fires_on_the_first_write_past_a_deadline() {
+    let trigger = DesyncTrigger { after: vec![time::Duration::from_millis(500)], ..DesyncTrigger::default() };
+
+    assert!(!trigger.fires(1, &after(2, 400, 100)));
+    assert!(trigger.fires(1, &after(3, 600, 400)));
+    assert!(!trigger.fires(1, &after(4, 700, 600)));
+
+    // The first write has no previous one to compare with.
+
+    assert!(trigger.fires(1, &after(1, 600, 600)));
+
+    assert!(!trigger.exhausted(1, &after(2, 400, 100)));
+    assert!(trigger.exhausted(1, &after(3, 600, 400)));
+  }
+
+  #[test]
+  fn a_bare_write_is_a_single_write_and_a_bare_port_is_open_ended() {
+    let config = parse("--filter_writes 2 --filter_port 443 --dpi_bypass_strategies tcp_split 1 --filter_writes 2- --dpi_bypass_strategies tcp_split 2").unwrap();
+    let strategies: Vec<&Strategy> = config.strategies.iter().map(|strategy| &strategy.data).collect();
+
+    assert_eq!(strategies[0].trigger.writes.as_ref().map(|writes| (writes.start, writes.end)), Some((2, Some(2))));
+    assert_eq!(strategies[1].trigger.writes.as_ref().map(|writes| (writes.start, writes.end)), Some((2, None)));
+    assert_eq!(strategies[0].filter_port.as_ref().map(|ports| (ports.start, ports.end)), Some((443, None)));
+  }
+
+  #[test]
+  fn trigger_is_exhausted_once_every_condition_is_past() {
+    let trigger = DesyncTrigger {
+      writes: Some(WeakRange { start: 1, end: Some(1) }),
+      offsets: vec![1000],
+      after: vec![time::Duration::from_millis(500)],
+    };
+
+    let mut position = at(1, 0, 10);
+
+    assert!(!trigger.exhausted(1, &position));
+
+    position.offset = 990;
+    position.len = 20;
+
+    assert!(!trigger.exhausted(1, &position));
+
+    position.elapsed = time::Duration::from_millis(500);
+
+    assert!(trigger.exhausted(1, &position));
+  }
 }
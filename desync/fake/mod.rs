@@ -4,7 +4,7 @@ pub mod fake {
     use crate::desync::utils::utils;
 
     let (sni_start, _sni_end) = sni_data;
-    let middle: u64 = (strategy.base_index as u64) + if strategy.add_sni { *sni_start as u64 } else { 0 };
+    let middle: u64 = (strategy.base_index + if strategy.add_sni { *sni_start as i64 } else { 0 }) as u64;
 
     if middle < packet_buffer.to_vec().len().try_into().unwrap() && middle > 0 {
       let packet_parts: Vec<Vec<u8>> = utils::slice_packet(packet_buffer.to_vec(), middle);
@@ -23,22 +23,19 @@ Content-Length: 1
 a", host).replace("\"", "").replace("\"", "");
   }
 
-  pub fn get_fake_packet(mut packet: Vec<u8>) -> Vec<u8> {
+  pub fn get_fake_packet(mut packet: Vec<u8>, conf: &crate::core::AuxConfig) -> Vec<u8> {
     use crate::desync::utils::utils;
-    use crate::core;
-
-    let conf: core::AuxConfig = core::parse_args();
 
     if conf.fake_packet_override_data.active {
       return conf.fake_packet_override_data.data.clone();
     } else if conf.fake_packet_send_http {
-      let fake_http: String = crate::fake::get_fake_http(conf.fake_packet_host);
+      let fake_http: String = crate::fake::get_fake_http(conf.fake_packet_host.clone());
       let bytes: Vec<u8> = Vec::from(fake_http.as_bytes());
 
       return bytes;
     } else {
       let (sni_start, sni_end) = utils::parse_sni_index(packet.clone());
-      let fake_sni: Vec<String> = String::from(conf.fake_packet_sni)
+      let fake_sni: Vec<String> = String::from(&conf.fake_packet_sni)
         .chars()
         .map(|ch| String::from(ch))
         .collect();
@@ -4,7 +4,7 @@ pub mod oob {
     use crate::desync::utils::utils;
 
     let (sni_start, _sni_end) = sni_data;
-    let middle: u64 = (strategy.base_index as u64) + if strategy.add_sni { *sni_start as u64 } else { 0 };
+    let middle: u64 = (strategy.base_index + if strategy.add_sni { *sni_start as i64 } else { 0 }) as u64;
 
     if middle < packet_buffer.to_vec().len().try_into().unwrap() && middle > 0 {
       let packet_parts: Vec<Vec<u8>> = utils::slice_packet(packet_buffer.to_vec(), middle);
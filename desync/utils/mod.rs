@@ -3,130 +3,65 @@ pub mod utils {
   use curl::easy::Easy;
   use std::net::TcpStream;
   use crate::core;
+  use crate::event;
+  use crate::trace;
   use std::io;
   use std::io::Write;
 
   use std::net::{IpAddr};
 
+  pub use crate::parse::{message_state, parse_sni_index, request, MessageState, RequestError};
+
   #[derive(Debug, Clone)]
   pub struct IpParser<'a> {
     pub host_raw: Vec<u8>,
     pub host_unprocessed: &'a [u8],
     pub port: u16,
     pub dest_addr_type: u8,
-    pub is_udp: bool,
   }
 
   impl IpParser<'_> {
-    pub fn parse(buffer: &[u8]) -> IpParser {
-      let dest_addr_type = buffer[3];
-      let is_udp = buffer[1] == 0x03;
-
-      match dest_addr_type {
-        1 => {
-          if buffer.len() < 10 {
-            return IpParser {
-              dest_addr_type,
-              host_raw: vec![0, 0, 0, 0],
-              host_unprocessed: &[0, 0, 0, 0],
-              port: 0,
-              is_udp
-            };
-          }
-          IpParser {
-            dest_addr_type,
-            host_raw: buffer[4..8].to_vec(),
-            host_unprocessed: &buffer[4..8],
-            port: u16::from_be_bytes([buffer[8], buffer[9]]),
-            is_udp
-          }
-        },
-        3 => {
-          let domain_length = buffer[4] as usize;
-
-          if 6 + domain_length >= buffer.len() {
-              return IpParser {
-                  dest_addr_type,
-                  host_raw: vec![0, 0, 0, 0],
-                  host_unprocessed: &[0, 0, 0, 0],
-                  port: 0,
-                  is_udp
-              };
-          }
+    pub fn parse(buffer: &[u8]) -> Result<IpParser<'_>, RequestError> {
+      let request = crate::parse::request(buffer)?;
 
-          let domain = &buffer[5..5 + domain_length];
-          let port = u16::from_be_bytes([buffer[5 + domain_length], buffer[6 + domain_length]]);
- 
-          if let Ok(domain_str) = std::str::from_utf8(domain) {
-            if let Ok(ip_addr) = domain_str.parse::<IpAddr>() {
-              let ip_buffer = match ip_addr {
-                  IpAddr::V4(ip) => ip.octets().to_vec(),
-                  IpAddr::V6(ip) => ip.octets().to_vec(),
-              };
-
-              return IpParser {
-                dest_addr_type,
-                host_raw: ip_buffer,
-                host_unprocessed: &domain,
-                port,
-                is_udp
-              };
-            }
+      let host_raw = match request.addr_type {
+        3 => resolve(request.host),
+        _ => request.host.to_vec()
+      };
 
-            if let Ok(ip) = doh_resolver(domain_str.to_string()) {
-              if let Ok(ip_addr) = ip.parse::<IpAddr>() {
-                let ip_buffer = match ip_addr {
-                  IpAddr::V4(ip) => ip.octets().to_vec(),
-                  IpAddr::V6(ip) => ip.octets().to_vec(),
-                };
-
-                return IpParser {
-                  dest_addr_type,
-                  host_raw: ip_buffer,
-                  host_unprocessed: &domain,
-                  port,
-                  is_udp
-                };
-              }
-            }
-          }
+      Ok(IpParser {
+        dest_addr_type: request.addr_type,
+        host_raw,
+        host_unprocessed: request.host,
+        port: request.port
+      })
+    }
+  }
 
-          IpParser {
-            dest_addr_type,
-            host_raw: vec![0, 0, 0, 0],
-            host_unprocessed: &domain,
-            port,
-            is_udp
-          }
-        },
-        4 => {
-          if buffer.len() < 22 {
-            return IpParser {
-              dest_addr_type,
-              host_raw: vec![0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-              host_unprocessed: &[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-              port: 0,
-              is_udp
-            };
-          }
-          IpParser {
-            dest_addr_type,
-            host_raw: buffer[4..20].to_vec(),
-            host_unprocessed: &buffer[4..20],
-            port: u16::from_be_bytes([buffer[20], buffer[21]]),
-            is_udp
-          }
-        },
-        _ => {
-          IpParser {
-            dest_addr_type,
-            host_raw: [0, 0, 0, 0].to_vec(),
-            host_unprocessed: &[0, 0, 0, 0],
-            port: 0,
-            is_udp
-          }
-        }
-      }
+  // A name that doesn't resolve comes back as 0.0.0.0, which the caller reports as a failed
+  // resolution.
+
+  fn resolve(domain: &[u8]) -> Vec<u8> {
+    let Ok(domain_str) = std::str::from_utf8(domain) else {
+      return vec![0, 0, 0, 0];
+    };
+
+    if let Ok(ip_addr) = domain_str.parse::<IpAddr>() {
+      return match ip_addr {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+      };
+    }
+
+    let started = std::time::Instant::now();
+    let resolved = doh_resolver(domain_str.to_string());
+
+    crate::metrics::doh_resolved(started.elapsed(), resolved.as_ref().is_ok_and(|ip| ip.parse::<IpAddr>().is_ok()));
+
+    match resolved.ok().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+      Some(IpAddr::V4(ip)) => ip.octets().to_vec(),
+      Some(IpAddr::V6(ip)) => ip.octets().to_vec(),
+      None => vec![0, 0, 0, 0]
     }
   }
 
@@ -166,8 +101,18 @@ pub mod utils {
     Ok(())
   }
 
-  pub fn send_duplicate(mut socket: &TcpStream, packet: Vec<u8>) -> Result<(), std::io::Error> {
-    let conf: core::AuxConfig = core::parse_args();
+  pub fn send_plain(mut socket: &TcpStream, packet: &[u8]) -> Result<(), std::io::Error> {
+    event!(Debug, "segment", kind = "plain", len = packet.len(), ttl = socket.ttl().unwrap_or_default());
+
+    trace::emit("plain", socket.ttl().unwrap_or_default(), false, packet);
+
+    socket.write_all(packet)
+  }
+
+  pub fn send_duplicate(mut socket: &TcpStream, packet: Vec<u8>, conf: &core::AuxConfig) -> Result<(), std::io::Error> {
+    event!(Debug, "segment", kind = "duplicate", len = packet.len(), ttl = 1);
+
+    trace::emit("duplicate", 1, false, &packet);
 
     let _ = set_ttl_raw(&socket, 1);
     let _ = socket.write_all(&packet.as_slice())?;
@@ -177,8 +122,13 @@ pub mod utils {
   }
 
   #[cfg(unix)]
-  pub fn send_drop(socket: &TcpStream, data: Vec<u8>) {
-    let conf: core::AuxConfig = core::parse_args();
+  pub fn send_drop(socket: &TcpStream, data: Vec<u8>, conf: &core::AuxConfig) {
+    // Only the first byte of the fake payload goes out on the wire
+
+    event!(Debug, "segment", kind = "fake", len = data.len().min(1), ttl = conf.fake_packet_ttl, oob = conf.fake_as_oob);
+
+    trace::emit("fake", conf.fake_packet_ttl.into(), conf.fake_as_oob, &data[..data.len().min(1)]);
+
     let _ = set_ttl_raw(&socket, conf.fake_packet_ttl.into());
 
     if cfg!(unix) {
@@ -196,8 +146,13 @@ pub mod utils {
   }
 
   #[cfg(windows)]
-  pub fn send_drop(socket: &TcpStream, data: Vec<u8>) {
-      let conf: core::AuxConfig = core::parse_args();
+  pub fn send_drop(socket: &TcpStream, data: Vec<u8>, conf: &core::AuxConfig) {
+      // Only the first byte of the fake payload goes out on the wire
+
+      event!(Debug, "segment", kind = "fake", len = data.len().min(1), ttl = conf.fake_packet_ttl, oob = conf.fake_as_oob);
+
+      trace::emit("fake", conf.fake_packet_ttl.into(), conf.fake_as_oob, &data[..data.len().min(1)]);
+
       let _ = set_ttl_raw(&socket, conf.fake_packet_ttl.into());
 
       use winapi::um::winsock2::{send, MSG_OOB};
@@ -212,6 +167,61 @@ pub mod utils {
       let _ = set_ttl_raw(&socket, conf.default_ttl.into());
   }
 
+  // Everything the strategies put on the wire goes through an Emitter, so the same code can
+  // drive a socket or be recorded by `waterfall simulate`.
+
+  pub trait Emitter {
+    fn peer_port(&self) -> Option<u16>;
+
+    fn rule(&self, _rule: Option<String>) { }
+
+    fn set_ttl(&self, ttl: u32);
+
+    fn plain(&self, data: &[u8]) -> Result<(), std::io::Error>;
+
+    fn duplicate(&self, data: Vec<u8>, conf: &core::AuxConfig) -> Result<(), std::io::Error>;
+
+    fn fake(&self, data: Vec<u8>, conf: &core::AuxConfig);
+
+    fn oob(&self, data: Vec<u8>);
+
+    fn disable_sack(&self);
+  }
+
+  impl Emitter for TcpStream {
+    fn peer_port(&self) -> Option<u16> {
+      self.peer_addr().ok().map(|addr| addr.port())
+    }
+
+    fn rule(&self, rule: Option<String>) {
+      trace::rule(rule);
+    }
+
+    fn set_ttl(&self, ttl: u32) {
+      let _ = set_ttl_raw(self, ttl);
+    }
+
+    fn plain(&self, data: &[u8]) -> Result<(), std::io::Error> {
+      send_plain(self, data)
+    }
+
+    fn duplicate(&self, data: Vec<u8>, conf: &core::AuxConfig) -> Result<(), std::io::Error> {
+      send_duplicate(self, data, conf)
+    }
+
+    fn fake(&self, data: Vec<u8>, conf: &core::AuxConfig) {
+      send_drop(self, data, conf)
+    }
+
+    fn oob(&self, data: Vec<u8>) {
+      write_oob_multiplex(self, data)
+    }
+
+    fn disable_sack(&self) {
+      disable_sack(self)
+    }
+  }
+
   pub fn check_whitelist(config: &Option<Vec<String>>, sni_data: &(u32, u32), data: &[u8]) -> bool {
     if let Some(whitelist_sni_list) = config {
         if sni_data != &(0, 0) {
@@ -240,6 +250,8 @@ pub mod utils {
   }
 
   pub fn slice_packet(source: Vec<u8>, index: u64) -> Vec<Vec<u8>> {
+    trace::note(format!("sliced {} bytes at {}", source.len(), index));
+
     let mut current_index: u64 = 0;
 
     let mut alpha: Vec<u8> = Vec::new();
@@ -293,27 +305,6 @@ pub mod utils {
       (0..len).map(|_| rand.next_rand()).collect()
   }
 
-  pub fn parse_sni_index(source: Vec<u8>) -> (u32, u32) {
-      if source.is_empty() || source[0] != 0x16 { return (0, 0) };
-      if source.len() < 48 { return (0, 0) };
-      if source.len() <= 5 || source[5] != 0x01 { return (0, 0) };
-
-      (0..source.len().saturating_sub(8)).find_map(|i| {
-          if source[i] == 0x00 && source[i + 1] == 0x00 && source[i + 7] == 0x00 && (source[i + 3] as isize - source[i + 5] as isize) == 2 {
-              let len = source[i + 8] as usize;
-
-              let start = i + 9;
-              let end = start + len as usize;
-            
-              if end <= source.len() && len > 0 && len < 256 {
-                  return Some((start as u32, end as u32));
-              }
-          }
-
-          None
-      }).unwrap_or((0, 0))
-  }
-
   // TODO: This is ugly, but fast. Make it less crude
 
   fn find_ip(data: Vec<u8>) -> Option<String> {
@@ -368,6 +359,7 @@ pub mod utils {
       let mut response_data = Vec::new();
 
       easy.url(&cf_dns.replace("{}", &domain)).map_err(|n| n.to_string())?;
+      easy.timeout(core::config().handshake_timeout).map_err(|n| n.to_string())?;
 
       easy.http_headers({
           let mut headers = curl::easy::List::new();
@@ -398,6 +390,10 @@ pub mod utils {
     let data1 = oob_data.as_slice();
     let oob_len = oob_data.len();
 
+    event!(Debug, "segment", kind = "oob", len = oob_len, ttl = socket.ttl().unwrap_or_default());
+
+    trace::emit("oob", socket.ttl().unwrap_or_default(), true, data1);
+
     let fd = socket.as_raw_fd();
 
     let _ = unsafe {
@@ -413,6 +409,10 @@ pub mod utils {
       let data1 = oob_data.as_slice();
       let oob_len = oob_data.len();
 
+      event!(Debug, "segment", kind = "oob", len = oob_len, ttl = socket.ttl().unwrap_or_default());
+
+      trace::emit("oob", socket.ttl().unwrap_or_default(), true, data1);
+
       let rs: RawSocket = socket.as_raw_socket();
 
       let _ = unsafe {
@@ -0,0 +1,242 @@
+use crate::core::{AuxConfig, NetworkProtocol, Strategy, WeakRange};
+
+use serde_json::{json, Value};
+
+const HOST_SAMPLE: usize = 3;
+
+fn position(strategy: &Strategy) -> String {
+    format!("{}+{}{}{}",
+        strategy.base_index,
+        if strategy.add_sni { "s" } else { "" },
+        if strategy.add_host { "h" } else { "" },
+        if strategy.subtract { " (auto)" } else { "" })
+}
+
+fn protocol(strategy: &Strategy) -> &'static str {
+    match strategy.filter_protocol {
+        Some(NetworkProtocol::TCP) => "tcp",
+        Some(NetworkProtocol::UDP) => "udp",
+        None => "any"
+    }
+}
+
+// A range without an end matches every port from its start upwards.
+
+fn range(range: &Option<WeakRange>) -> String {
+    match range {
+        Some(WeakRange { start, end: Some(end) }) if start == end => start.to_string(),
+        Some(WeakRange { start, end: Some(end) }) => format!("{}-{}", start, end),
+        Some(WeakRange { start, end: None }) => format!("{}-", start),
+        None => "any".to_string()
+    }
+}
+
+fn trigger(strategy: &Strategy, packet_hop: u64) -> String {
+    let trigger = &strategy.trigger;
+    let mut parts: Vec<String> = Vec::new();
+
+    if let Some(ref writes) = trigger.writes {
+        parts.push(format!("writes {}", range(&Some(writes.clone()))));
+    }
+
+    if !trigger.offsets.is_empty() {
+        parts.push(format!("offsets {}", trigger.offsets.iter().map(u64::to_string).collect::<Vec<String>>().join(",")));
+    }
+
+    if !trigger.after.is_empty() {
+        parts.push(format!("after {}ms", trigger.after.iter().map(|after| after.as_millis().to_string()).collect::<Vec<String>>().join(",")));
+    }
+
+    if parts.is_empty() {
+        parts.push(format!("writes 1-{}", packet_hop));
+    }
+
+    // Fallback sets only run when a connection is retried after being blocked.
+
+    if strategy.fallback > 0 {
+        parts.push(format!("fallback {}", strategy.fallback));
+    }
+
+    parts.join(", ")
+}
+
+fn hosts(strategy: &Strategy) -> (usize, Vec<String>) {
+    let list = strategy.filter_sni.clone().unwrap_or_default();
+
+    (list.len(), list.into_iter().take(HOST_SAMPLE).collect())
+}
+
+fn fake_payload(config: &AuxConfig) -> String {
+    if config.fake_packet_override_data.active {
+        format!("{} custom bytes", config.fake_packet_override_data.data.len())
+    } else if config.fake_packet_send_http {
+        format!("HTTP request for {}", config.fake_packet_host)
+    } else {
+        format!("original with SNI {}", config.fake_packet_sni)
+    }
+}
+
+pub fn table(config: &AuxConfig) -> String {
+    let mut rows: Vec<[String; 8]> = vec![[
+        "#", "enabled", "method", "position", "protocol", "ports", "trigger", "hosts"
+    ].map(String::from)];
+
+    for (index, (enabled, strategy)) in config.strategies.iter().map(|strategy| (strategy.active, &strategy.data)).enumerate() {
+        let (count, sample) = hosts(strategy);
+
+        let hosts = match count {
+            0 => "any".to_string(),
+            _ if count > sample.len() => format!("{} ({}, ...)", count, sample.join(", ")),
+            _ => format!("{} ({})", count, sample.join(", "))
+        };
+
+        rows.push([
+            (index + 1).to_string(),
+            if enabled { "yes" } else { "no" }.to_string(),
+            strategy.method.info().map(|info| info.name).unwrap_or("none").to_string(),
+            position(strategy),
+            protocol(strategy).to_string(),
+            range(&strategy.filter_port),
+            trigger(strategy, config.packet_hop),
+            hosts
+        ]);
+    }
+
+    let widths: Vec<usize> = (0..8)
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+
+    let mut output = format!("Listening on {}:{}\n\n", config.bind_host, config.bind_port);
+
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+
+    if config.strategies.is_empty() {
+        output.push_str("(no strategies, connections are relayed unchanged)\n");
+    }
+
+    output.push_str(&format!("\nFake payload: {}, ttl {}{}\n",
+        fake_payload(config),
+        config.fake_packet_ttl,
+        if config.fake_as_oob { ", sent out-of-band" } else { "" }));
+
+    if config.fake_clienthello {
+        output.push_str(&format!("Fake ClientHello: SNI {}\n", config.fake_clienthello_sni));
+    }
+
+    output.push_str(&format!("TTL: default {}, disorder {}; out-of-band byte {}; desynced writes per connection {}\n",
+        config.default_ttl,
+        config.disorder_packet_ttl,
+        config.out_of_band_charid,
+        config.packet_hop));
+
+    output
+}
+
+pub fn json(config: &AuxConfig) -> Value {
+    let rules: Vec<Value> = config.strategies
+        .iter()
+        .map(|strategy| (strategy.active, &strategy.data))
+        .map(|(enabled, strategy)| {
+            let (count, sample) = hosts(strategy);
+
+            json!({
+                "enabled": enabled,
+                "method": strategy.method.info().map(|info| info.name).unwrap_or("none"),
+                "position": position(strategy),
+                "fallback": strategy.fallback,
+                "protocol": protocol(strategy),
+                "ports": range(&strategy.filter_port),
+                "trigger": {
+                    "writes": strategy.trigger.writes.as_ref().map(|writes| range(&Some(writes.clone()))),
+                    "offsets": strategy.trigger.offsets,
+                    "after_ms": strategy.trigger.after.iter().map(|after| after.as_millis() as u64).collect::<Vec<u64>>()
+                },
+                "hosts": {
+                    "count": count,
+                    "sample": sample
+                }
+            })
+        })
+        .collect();
+
+    json!({
+        "bind": format!("{}:{}", config.bind_host, config.bind_port),
+        "fake": {
+            "payload": fake_payload(config),
+            "ttl": config.fake_packet_ttl,
+            "as_oob": config.fake_as_oob,
+            "clienthello_sni": if config.fake_clienthello { Some(&config.fake_clienthello_sni) } else { None },
+            "random": config.fake_packet_random,
+            "reversed": config.fake_packet_reversed
+        },
+        "ttl": {
+            "default": config.default_ttl,
+            "disorder": config.disorder_packet_ttl
+        },
+        "out_of_band_charid": config.out_of_band_charid,
+        "packet_hop": config.packet_hop,
+        "rules": rules
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AuxConfig {
+        let args = [
+            "--filter_port", "443", "--filter_writes", "2", "--filter_sni", "a.test,b.test,c.test,d.test",
+            "--dpi_bypass_strategies", "tcp_split", "1+s",
+            "--reset_sni_filter", "--reset_desync_triggers", "--filter_port", "",
+            "--dpi_bypass_strategies", "tcp_disorder", "3",
+        ].map(String::from).to_vec();
+
+        let mut config = crate::core::parse_args_from(args).unwrap();
+
+        config.strategies[1].active = false;
+        config
+    }
+
+    #[test]
+    fn table_lists_every_rule_with_its_state() {
+        let output = table(&config());
+        let rules: Vec<&str> = output.lines().skip(2).take(3).collect();
+
+        assert_eq!(rules, [
+            "#  enabled  method        position  protocol  ports  trigger     hosts",
+            "1  yes      tcp_split     1+s       any       443-   writes 2    4 (a.test, b.test, c.test, ...)",
+            "2  no       tcp_disorder  3+        any       any    writes 1-1  any",
+        ]);
+    }
+
+    #[test]
+    fn json_lists_every_rule_with_its_state() {
+        let output = json(&config());
+        let rules = output["rules"].as_array().unwrap();
+
+        assert_eq!(rules.len(), 2);
+
+        assert_eq!(rules[0]["enabled"], true);
+        assert_eq!(rules[0]["method"], "tcp_split");
+        assert_eq!(rules[0]["position"], "1+s");
+        assert_eq!(rules[0]["ports"], "443-");
+        assert_eq!(rules[0]["trigger"]["writes"], "2");
+        assert_eq!(rules[0]["hosts"], json!({ "count": 4, "sample": ["a.test", "b.test", "c.test"] }));
+
+        assert_eq!(rules[1]["enabled"], false);
+        assert_eq!(rules[1]["method"], "tcp_disorder");
+        assert_eq!(rules[1]["ports"], "any");
+        assert_eq!(rules[1]["trigger"]["writes"], Value::Null);
+        assert_eq!(rules[1]["hosts"]["count"], 0);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::core::AuxConfig;
+use crate::event;
+use crate::metrics;
+use crate::outcome::Outcome;
+
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time;
+
+// How long a blocked attempt waits for the upstream thread to finish its hooked write before
+// it gives up on retrying.
+
+const GATE_TIMEOUT: time::Duration = time::Duration::from_millis(200);
+
+#[derive(Default)]
+struct State {
+    order: Vec<usize>,
+    waiting: bool,
+    set: Option<usize>,
+    server: Option<Option<TcpStream>>,
+    done: bool,
+}
+
+// Coordinates the two relay threads of a connection while its first response is classified.
+// The upstream thread stops after the hooked write and waits; the downstream thread either
+// lets it go on, or asks it to reconnect and replay the client's first bytes with the next
+// fallback set and hands over the new socket.
+
+pub struct Handoff {
+    pub addr: SocketAddr,
+    retries: usize,
+    deadline: time::Instant,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+pub fn fallback_sets(config: &AuxConfig) -> usize {
+    config.strategies
+        .iter()
+        .filter(|strategy| strategy.active)
+        .map(|strategy| strategy.data.fallback)
+        .max()
+        .unwrap_or(0)
+}
+
+impl Handoff {
+    pub fn new(addr: SocketAddr, config: &AuxConfig) -> Option<Arc<Handoff>> {
+        let retries = config.fallback_retries.min(fallback_sets(config));
+
+        (retries > 0).then(|| Arc::new(Handoff {
+            addr,
+            retries,
+            deadline: time::Instant::now() + config.fallback_budget,
+            state: Mutex::new(State { order: (0..=fallback_sets(config)).collect(), ..State::default() }),
+            changed: Condvar::new(),
+        }))
+    }
+
+    // Starts from a set learned for the host instead of the rule's own strategies, the others
+    // follow in their usual order. Sets the rules no longer have are ignored.
+
+    pub fn start(&self, set: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.order.contains(&set) {
+            return false;
+        }
+
+        state.order.retain(|other| *other != set);
+        state.order.insert(0, set);
+
+        true
+    }
+
+    // Upstream side: blocks until the first response is settled. Returns the fallback set to
+    // retry with, or None once the connection goes on with the socket it has.
+
+    pub fn wait(&self) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        state.waiting = true;
+
+        self.changed.notify_all();
+
+        let mut state = self.changed.wait_while(state, |state| !state.done && state.set.is_none()).unwrap();
+
+        state.waiting = false;
+        state.set.take()
+    }
+
+    pub fn replaced(&self, server: Option<TcpStream>) {
+        self.state.lock().unwrap().server = Some(server);
+
+        self.changed.notify_all();
+    }
+
+    // Downstream side: asks for the `attempt`th retry after `outcome`, returns the reader of the
+    // new upstream socket, or None when the retries or the time budget are used up, or the
+    // upstream thread isn't waiting for a verdict (nothing hooked yet, or it already failed).
+
+    pub fn retry(&self, attempt: usize, outcome: Outcome) -> Option<TcpStream> {
+        if attempt > self.retries || time::Instant::now() >= self.deadline {
+            return None;
+        }
+
+        let state = self.state.lock().unwrap();
+
+        let (mut state, _) = self.changed.wait_timeout_while(state, GATE_TIMEOUT, |state| !state.waiting).unwrap();
+
+        let &set = state.order.get(attempt).filter(|_| state.waiting)?;
+
+        event!(Info, "fallback", attempt = attempt, set = set, after = outcome.to_string());
+
+        metrics::fallback_retry();
+
+        state.set = Some(set);
+        state.server = None;
+
+        self.changed.notify_all();
+
+        let mut state = self.changed.wait_while(state, |state| state.server.is_none()).unwrap();
+
+        state.server.take().flatten()
+    }
+
+    pub fn finish(&self) {
+        self.state.lock().unwrap().done = true;
+
+        self.changed.notify_all();
+    }
+}
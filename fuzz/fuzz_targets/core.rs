@@ -0,0 +1,10 @@
+// Stands in for the crate's core module in the tamper targets: tamper only reads these flags
+// from the configuration.
+
+#[derive(Default)]
+pub struct AuxConfig {
+    pub http_host_cmix: bool,
+    pub http_host_rmspace: bool,
+    pub http_host_space: bool,
+    pub http_domain_cmix: bool
+}
@@ -0,0 +1,31 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+mod core;
+
+#[allow(dead_code)]
+#[path = "../../tamper/mod.rs"]
+mod tamper;
+
+// The first byte picks the edits, the rest is what the client sent.
+
+fuzz_target!(|data: &[u8]| {
+    let [flags, ref request @ ..] = *data else {
+        return;
+    };
+
+    let conf = core::AuxConfig {
+        http_host_cmix: flags & 1 != 0,
+        http_host_rmspace: flags & 2 != 0,
+        http_host_space: flags & 4 != 0,
+        http_domain_cmix: flags & 8 != 0
+    };
+
+    let edited = tamper::edit_http(request.to_vec(), &conf);
+
+    if flags & 0x0f == 0 {
+        assert_eq!(edited, request);
+    }
+});
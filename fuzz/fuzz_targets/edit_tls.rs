@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+mod core;
+
+#[allow(dead_code)]
+#[path = "../../tamper/mod.rs"]
+mod tamper;
+
+// The first two bytes are the position, the rest is what the client sent.
+
+fuzz_target!(|data: &[u8]| {
+    let [high, low, ref stream @ ..] = *data else {
+        return;
+    };
+
+    let edited = tamper::edit_tls(stream.to_vec(), u16::from_be_bytes([high, low]) as usize);
+
+    // Cutting a record adds the header of the second part and nothing else
+
+    assert!(edited == stream || edited.len() == stream.len() + 5);
+});
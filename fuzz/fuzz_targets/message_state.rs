@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../parse/mod.rs"]
+mod parse;
+
+use parse::MessageState;
+
+fuzz_target!(|data: &[u8]| {
+    // Reassembly stops at the first complete message, so one can't turn partial again once more
+    // bytes arrive
+
+    if parse::message_state(data) == MessageState::Complete {
+        assert_eq!(parse::message_state(&[data, b"\0"].concat()), MessageState::Complete);
+    }
+});
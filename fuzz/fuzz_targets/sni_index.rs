@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../parse/mod.rs"]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    let (start, end) = parse::parse_sni_index(data.to_vec());
+
+    assert!((start, end) == (0, 0) || (start < end && end as usize <= data.len()));
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../parse/mod.rs"]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(request) = parse::request(data) else {
+        return;
+    };
+
+    // The host is borrowed from the request, with the two port bytes still after it
+
+    let start = request.host.as_ptr() as usize - data.as_ptr() as usize;
+
+    assert!(start + request.host.len() + 2 <= data.len());
+
+    match request.addr_type {
+        1 => assert_eq!(request.host.len(), 4),
+        4 => assert_eq!(request.host.len(), 16),
+        _ => assert_eq!(request.addr_type, 3)
+    }
+});
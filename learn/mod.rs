@@ -0,0 +1,305 @@
+use crate::core::AuxConfig;
+use crate::event;
+use crate::fallback::fallback_sets;
+use crate::outcome::Outcome;
+use crate::relay::SessionInfo;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::time;
+
+// Which strategy set last got a host through, so that the next connection to it starts there
+// instead of being blocked and falling back again. Keyed by "<host>:<port>", where the host is
+// the SNI when there is one and the SOCKS target otherwise.
+
+// A set is remembered by its strategies rather than its number, since a reload may reorder or
+// change the fallback sets. Entries whose strategies no set has any more are dropped.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    #[serde(default)]
+    strategies: String,
+    expires: u64,
+    failures: u32,
+}
+
+struct Cache {
+    path: String,
+    entries: BTreeMap<String, Entry>,
+    changes: u64,
+}
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+// The number of the last change written, so that a slow writer never replaces a newer file.
+
+static SAVED: Mutex<u64> = Mutex::new(0);
+
+fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// The target is already "<host>:<port>", only the host is swapped for the SNI when known.
+
+pub fn key(sni: Option<&str>, target: &str) -> String {
+    match (sni, target.rsplit_once(':')) {
+        (Some(sni), Some((_, port))) => format!("{}:{}", sni, port),
+        _ => target.to_string()
+    }
+}
+
+// The strategies of a set as they'd be written on the command line, e.g. "tcp_split 1+s".
+
+fn strategies(config: &AuxConfig, set: usize) -> String {
+    config.strategies
+        .iter()
+        .filter(|strategy| strategy.active && strategy.data.fallback == set)
+        .map(|strategy| format!("{} {}+{}{}",
+            strategy.data.method.info().map(|info| info.name).unwrap_or("none"),
+            strategy.data.base_index,
+            if strategy.data.add_sni { "s" } else { "" },
+            if strategy.data.add_host { "h" } else { "" }))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn find_set(config: &AuxConfig, entry: &Entry) -> Option<usize> {
+    (0..=fallback_sets(config)).find(|set| strategies(config, *set) == entry.strategies)
+}
+
+// A missing file is an empty cache. Expired entries are dropped on the way in.
+
+pub fn open(path: &str) -> io::Result<usize> {
+    let entries: BTreeMap<String, Entry> = match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(error) => return Err(error)
+    };
+
+    let now = now();
+    let entries: BTreeMap<String, Entry> = entries.into_iter().filter(|(_, entry)| entry.expires > now).collect();
+    let count = entries.len();
+
+    *CACHE.lock().unwrap() = Some(Cache { path: path.to_string(), entries, changes: 0 });
+
+    Ok(count)
+}
+
+// Called with a copy of the entries after the cache lock is released, so connections never
+// wait on the disk. Written to a temporary file first, so that a crash never leaves half a
+// cache behind.
+
+fn save(path: &str, entries: &BTreeMap<String, Entry>, change: u64) {
+    let mut saved = SAVED.lock().unwrap();
+
+    if *saved >= change {
+        return;
+    }
+
+    let temporary = format!("{}.tmp", path);
+
+    let result = serde_json::to_string_pretty(entries)
+        .map_err(io::Error::other)
+        .and_then(|text| fs::write(&temporary, text))
+        .and_then(|_| fs::rename(&temporary, path));
+
+    match result {
+        Ok(()) => *saved = change,
+        Err(error) => event!(Warn, "learned strategies not saved", path = path, error = error.to_string())
+    }
+}
+
+// Runs `change` on the entries, and saves a copy of them if it says it changed anything.
+
+fn update(change: impl FnOnce(&mut BTreeMap<String, Entry>) -> bool) {
+    let copy = {
+        let mut cache = CACHE.lock().unwrap();
+
+        let Some(cache) = cache.as_mut() else {
+            return;
+        };
+
+        if !change(&mut cache.entries) {
+            return;
+        }
+
+        cache.changes += 1;
+
+        (cache.path.clone(), cache.entries.clone(), cache.changes)
+    };
+
+    save(&copy.0, &copy.1, copy.2);
+}
+
+pub fn lookup(key: &str, config: &AuxConfig) -> Option<usize> {
+    let mut found = None;
+
+    update(|entries| {
+        let Some(entry) = entries.get(key).filter(|entry| entry.expires > now()) else {
+            return false;
+        };
+
+        found = find_set(config, entry);
+
+        if found.is_none() {
+            event!(Info, "unlearned", key = key, strategies = entry.strategies, reason = "set no longer configured");
+
+            entries.remove(key);
+        }
+
+        found.is_none()
+    });
+
+    found
+}
+
+// A success stores the set that got through. A blocked attempt with the learned set counts as
+// a failure, and after --learn_demote_after of them the entry is dropped, so the host starts
+// from the rule's own strategies again. Anything else says nothing about the strategies.
+// Returns whether the entries changed.
+
+fn learn(entries: &mut BTreeMap<String, Entry>, key: String, set: usize, outcome: Outcome, config: &AuxConfig, now: u64) -> bool {
+    let used = strategies(config, set);
+    let learned = entries.get(&key).filter(|entry| entry.expires > now).map(|entry| entry.strategies == used);
+
+    match (outcome, learned) {
+        (Outcome::Success, Some(true)) => {
+            if let Some(entry) = entries.get_mut(&key) {
+                entry.failures = 0;
+                entry.expires = now + config.learn_expiry.as_secs();
+            }
+        },
+        (Outcome::Success, None) if set == 0 => return false,
+        (Outcome::Success, _) => {
+            event!(Info, "learned", key = key, set = set, strategies = used);
+
+            entries.insert(key, Entry { strategies: used, expires: now + config.learn_expiry.as_secs(), failures: 0 });
+        },
+        (outcome, Some(true)) if outcome.is_blocked() => {
+            let Some(entry) = entries.get_mut(&key) else {
+                return false;
+            };
+
+            entry.failures += 1;
+
+            if entry.failures >= config.learn_demote_after {
+                event!(Info, "unlearned", key = key, strategies = used, failures = entry.failures);
+
+                entries.remove(&key);
+            }
+        },
+        _ => return false
+    }
+
+    true
+}
+
+pub fn observe(info: &SessionInfo, outcome: Outcome, config: &AuxConfig) {
+    let key = key(info.sni.as_deref(), &info.target);
+
+    update(|entries| learn(entries, key, info.set, outcome, config, now()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core;
+
+    const NOW: u64 = 1_000_000;
+
+    fn config(args: &str) -> AuxConfig {
+        core::parse_args_from(args.split_whitespace().map(String::from).collect()).unwrap()
+    }
+
+    fn fallbacks() -> AuxConfig {
+        config("--learn_demote_after 2 --dpi_bypass_strategies tcp_split 1+s --fallback_strategies tcp_disorder 2+ --fallback_strategies tcp_split 3+s")
+    }
+
+    fn learned(config: &AuxConfig, set: usize) -> BTreeMap<String, Entry> {
+        let mut entries = BTreeMap::new();
+
+        assert!(learn(&mut entries, "example.com:443".to_string(), set, Outcome::Success, config, NOW));
+
+        entries
+    }
+
+    #[test]
+    fn success_with_a_fallback_set_is_learned() {
+        let config = fallbacks();
+        let entries = learned(&config, 1);
+        let entry = &entries["example.com:443"];
+
+        assert_eq!(entry.strategies, "tcp_disorder 2+");
+        assert_eq!(entry.expires, NOW + config.learn_expiry.as_secs());
+        assert_eq!(find_set(&config, entry), Some(1));
+    }
+
+    #[test]
+    fn success_with_the_rules_own_strategies_learns_nothing() {
+        let mut entries = BTreeMap::new();
+
+        assert!(!learn(&mut entries, "example.com:443".to_string(), 0, Outcome::Success, &fallbacks(), NOW));
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn success_with_the_learned_set_resets_failures() {
+        let config = fallbacks();
+        let mut entries = learned(&config, 2);
+
+        assert!(learn(&mut entries, "example.com:443".to_string(), 2, Outcome::Reset, &config, NOW));
+        assert_eq!(entries["example.com:443"].failures, 1);
+
+        assert!(learn(&mut entries, "example.com:443".to_string(), 2, Outcome::Success, &config, NOW + 60));
+        assert_eq!(entries["example.com:443"].failures, 0);
+        assert_eq!(entries["example.com:443"].expires, NOW + 60 + config.learn_expiry.as_secs());
+    }
+
+    #[test]
+    fn blocked_attempts_with_the_learned_set_demote_it() {
+        let config = fallbacks();
+        let mut entries = learned(&config, 1);
+
+        // Only blocks with the learned set count.
+
+        assert!(!learn(&mut entries, "example.com:443".to_string(), 1, Outcome::TlsAlert, &config, NOW));
+        assert!(!learn(&mut entries, "example.com:443".to_string(), 2, Outcome::Reset, &config, NOW));
+        assert_eq!(entries["example.com:443"].failures, 0);
+
+        assert!(learn(&mut entries, "example.com:443".to_string(), 1, Outcome::Timeout, &config, NOW));
+        assert!(learn(&mut entries, "example.com:443".to_string(), 1, Outcome::BlockPage, &config, NOW));
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn expired_entries_are_ignored_and_replaced() {
+        let config = fallbacks();
+        let mut entries = learned(&config, 1);
+        let expired = NOW + config.learn_expiry.as_secs();
+
+        assert!(!learn(&mut entries, "example.com:443".to_string(), 1, Outcome::Reset, &config, expired));
+        assert_eq!(entries["example.com:443"].failures, 0);
+
+        assert!(learn(&mut entries, "example.com:443".to_string(), 2, Outcome::Success, &config, expired));
+        assert_eq!(entries["example.com:443"].strategies, "tcp_split 3+s");
+    }
+
+    #[test]
+    fn entries_follow_their_set_across_reloads() {
+        let entries = learned(&fallbacks(), 1);
+        let entry = &entries["example.com:443"];
+
+        let reordered = config("--dpi_bypass_strategies tcp_split 1+s --fallback_strategies tcp_split 3+s --fallback_strategies tcp_disorder 2+");
+        let changed = config("--dpi_bypass_strategies tcp_split 1+s --fallback_strategies tcp_disorder 3+");
+
+        assert_eq!(find_set(&reordered, entry), Some(2));
+        assert_eq!(find_set(&changed, entry), None);
+    }
+}
@@ -0,0 +1,280 @@
+use serde_json::{Map, Value};
+
+use std::cell::Cell;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace"
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Level, String> {
+        Level::ALL
+            .into_iter()
+            .find(|level| level.name() == text)
+            .ok_or(format!("unknown log level `{}`, expected error, warn, info, debug or trace", text))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Text => "text",
+            Format::Json => "json"
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Format, String> {
+        match text {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format `{}`, expected text or json", text))
+        }
+    }
+}
+
+struct Output {
+    format: Format,
+    writer: Box<dyn Write + Send>,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static OUTPUT: Mutex<Option<Output>> = Mutex::new(None);
+
+thread_local! {
+    static CONNECTION: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+// Until `init` runs, events go to stderr as text at the info level.
+
+pub fn init(level: Level, format: Format, file: &str) -> io::Result<()> {
+    let writer: Box<dyn Write + Send> = if file.is_empty() {
+        Box::new(io::stderr())
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(file)?)
+    };
+
+    LEVEL.store(level as u8, Ordering::Relaxed);
+
+    *OUTPUT.lock().unwrap() = Some(Output { format, writer });
+
+    Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// Every event logged from a thread carries the ID of the connection it is handling.
+
+pub fn set_connection(id: u64) {
+    CONNECTION.with(|connection| connection.set(Some(id)));
+}
+
+fn timestamp() -> String {
+    timestamp_at(time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default())
+}
+
+fn timestamp_at(now: time::Duration) -> String {
+    let seconds = now.as_secs();
+    let days = (seconds / 86400) as i64;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day,
+        seconds % 86400 / 3600, seconds % 3600 / 60, seconds % 60,
+        now.subsec_millis())
+}
+
+// Anything a client controls, like a SOCKS domain or an SNI, may contain a line break that
+// would start a forged log line, so only plain words go out unquoted.
+
+fn text_value(value: &Value) -> String {
+    match value {
+        Value::String(text) if !text.is_empty() && !text.contains([' ', '"', '=']) && !text.chars().any(char::is_control) => text.clone(),
+        other => other.to_string()
+    }
+}
+
+fn line(format: Format, timestamp: String, level: Level, connection: Option<u64>, event: &str, fields: &[(&str, Value)]) -> String {
+    match format {
+        Format::Text => {
+            let mut line = format!("{} {:5}", timestamp, level.name().to_uppercase());
+
+            if let Some(id) = connection {
+                line.push_str(&format!(" [{}]", id));
+            }
+
+            line.push(' ');
+            line.push_str(event);
+
+            for (key, value) in fields {
+                line.push_str(&format!(" {}={}", key, text_value(value)));
+            }
+
+            line
+        },
+        Format::Json => {
+            let mut object = Map::new();
+
+            object.insert("ts".to_string(), Value::from(timestamp));
+            object.insert("level".to_string(), Value::from(level.name()));
+
+            if let Some(id) = connection {
+                object.insert("conn".to_string(), Value::from(id));
+            }
+
+            object.insert("event".to_string(), Value::from(event));
+
+            for (key, value) in fields {
+                object.insert(key.to_string(), value.clone());
+            }
+
+            Value::Object(object).to_string()
+        }
+    }
+}
+
+pub fn emit(level: Level, event: &str, fields: &[(&str, Value)]) {
+    let connection = CONNECTION.with(Cell::get);
+    let format = OUTPUT.lock().unwrap().as_ref().map(|output| output.format).unwrap_or(Format::Text);
+
+    let line = line(format, timestamp(), level, connection, event, fields);
+
+    let mut output = OUTPUT.lock().unwrap();
+
+    match output.as_mut() {
+        Some(output) => {
+            let _ = writeln!(output.writer, "{}", line);
+            let _ = output.writer.flush();
+        },
+        None => eprintln!("{}", line)
+    }
+}
+
+#[macro_export]
+macro_rules! event {
+    ($level:ident, $event:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::logging::enabled($crate::logging::Level::$level) {
+            $crate::logging::emit($crate::logging::Level::$level, $event, &[$((stringify!($key), serde_json::json!($value))),*]);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    const TS: &str = "2024-02-29T23:59:59.000Z";
+
+    #[test]
+    fn text_lines_quote_everything_but_plain_words() {
+        let fields = [
+            ("host", json!("example.test")),
+            ("sni", json!("a.test\n2024-02-29T23:59:59.000Z ERROR forged")),
+            ("tab", json!("a\tb")),
+            ("space", json!("a b")),
+            ("empty", json!("")),
+            ("len", json!(517)),
+        ];
+
+        let text = line(Format::Text, TS.to_string(), Level::Warn, Some(7), "connect", &fields);
+
+        assert_eq!(text, format!("{} WARN  [7] connect host=example.test sni=\"a.test\\n{} ERROR forged\" tab=\"a\\tb\" space=\"a b\" empty=\"\" len=517", TS, TS));
+        assert_eq!(line(Format::Text, TS.to_string(), Level::Info, None, "ready", &[]), format!("{} INFO  ready", TS));
+    }
+
+    #[test]
+    fn json_lines_are_one_object() {
+        let text = line(Format::Json, TS.to_string(), Level::Error, Some(7), "connect", &[("sni", json!("a\nb")), ("len", json!(517))]);
+
+        assert!(!text.contains('\n'));
+        assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), json!({ "ts": TS, "level": "error", "conn": 7, "event": "connect", "sni": "a\nb", "len": 517 }));
+
+        let text = line(Format::Json, TS.to_string(), Level::Info, None, "ready", &[]);
+
+        assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), json!({ "ts": TS, "level": "info", "event": "ready" }));
+    }
+
+    #[test]
+    fn only_levels_up_to_the_configured_one_are_enabled() {
+        assert_eq!("debug".parse::<Level>(), Ok(Level::Debug));
+        assert!("verbose".parse::<Level>().is_err());
+
+        let configured = LEVEL.load(Ordering::Relaxed);
+
+        LEVEL.store(Level::Warn as u8, Ordering::Relaxed);
+
+        let enabled: Vec<bool> = Level::ALL.iter().map(|level| enabled(*level)).collect();
+
+        LEVEL.store(configured, Ordering::Relaxed);
+
+        assert_eq!(enabled, [true, true, false, false, false]);
+    }
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        let at = |seconds: u64, millis: u64| timestamp_at(time::Duration::from_millis(seconds * 1000 + millis));
+
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951786123, 45), "2000-02-29T01:02:03.045Z");
+        assert_eq!(at(1709251199, 999), "2024-02-29T23:59:59.999Z");
+        assert_eq!(at(4102444800, 0), "2100-01-01T00:00:00.000Z");
+    }
+}
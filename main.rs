@@ -1,7 +1,23 @@
 mod desync;
 mod core;
 mod socks;
+mod relay;
+mod signals;
+mod config;
+mod explain;
+mod presets;
+mod logging;
+mod metrics;
+mod outcome;
+mod fallback;
+mod learn;
+mod control;
+mod trace;
+mod simulate;
+mod probe;
 mod tamper;
+mod parse;
+mod tls;
 
 use crate::desync::split::split;
 use crate::desync::disorder::disorder;
@@ -11,24 +27,27 @@ use crate::desync::disoob::disoob;
 use crate::desync::utils::utils;
 
 use utils::IpParser;
+use utils::Emitter;
 
 use crate::utils::Random;
 
 use core::Strategies;
 use core::Strategy;
 use core::AuxConfig;
+use core::StreamPosition;
 
 use std::net::TcpListener;
 use std::net::TcpStream;
-use std::io::Write;
+use std::net::SocketAddr;
 
+use std::sync::Arc;
 use std::thread;
 use std::time;
 
-fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, current_data: &'a mut Vec<u8>, sni_data: &'a (u32, u32)) {
+fn execute_l4_bypasses<'a, E: Emitter>(socket: &'a E, config: &'a AuxConfig, current_data: &'a mut Vec<u8>, sni_data: &'a (u32, u32), position: &'a StreamPosition) {
   if sni_data != &(0, 0) &&
     config.fake_clienthello {
-    utils::send_drop(&socket, [&[0x16, 0x03, 0x01, 0x00, 0xa5,
+    socket.fake([&[0x16, 0x03, 0x01, 0x00, 0xa5,
         0x01, 0x00, 0x00, 0xa1, 0x03, 0x03, 
 
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -44,49 +63,77 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
 
         0x00, 16, 
 
-        0x00, 0x00, 0x00, 0x28], config.fake_clienthello_sni.as_bytes()].concat());
+        0x00, 0x00, 0x00, 0x28], config.fake_clienthello_sni.as_bytes()].concat(), config);
   }
   
-  for strategy_raw in &config.strategies {
+  for (index, strategy_raw) in config.strategies.iter().enumerate() {
     let strategy: Strategy = strategy_raw.data.clone();
+    let name: &str = strategy.method.info().map(|info| info.name).unwrap_or("none");
+
+    if !strategy_raw.active {
+      event!(Debug, "strategy skipped", rule = index + 1, strategy = name, reason = "disabled");
+
+      continue;
+    }
+
+    if strategy.fallback != config.attempt {
+      event!(Trace, "strategy skipped", rule = index + 1, strategy = name, reason = "fallback");
+
+      continue;
+    }
+
+    if !strategy.trigger.fires(config.packet_hop, position) {
+      event!(Trace, "strategy skipped", rule = index + 1, strategy = name, reason = "trigger");
+
+      continue;
+    }
 
     if strategy.add_sni && sni_data == &(0, 0) {
+      event!(Debug, "strategy skipped", rule = index + 1, strategy = name, reason = "no sni");
+
       continue;
     }
 
     if !utils::check_whitelist(&strategy.filter_sni, sni_data, current_data.as_slice()) {
+        event!(Debug, "strategy skipped", rule = index + 1, strategy = name, reason = "sni filter");
+
         continue;
     }
 
     if let Some(ref protocol) = strategy.filter_protocol {
         if protocol != &core::NetworkProtocol::TCP {
+            event!(Debug, "strategy skipped", rule = index + 1, strategy = name, reason = "protocol filter");
+
             continue;
         }
     }
 
-    if let Ok(addr) = socket.peer_addr() {
+    if let Some(addr_port) = socket.peer_port() {
         if let Some(ref port) = strategy.filter_port {
-            let addr_port: u16 = addr.port();
 
-            if let Some(end_port) = port.end {
-                if addr_port > end_port {
-                    continue;
-                }
-            }
+            if port.end.is_some_and(|end_port| addr_port > end_port) || addr_port < port.start {
+                event!(Debug, "strategy skipped", rule = index + 1, strategy = name, reason = "port filter", port = addr_port);
 
-            if addr_port < port.start {
                 continue;
             }
         }
     }
 
+    event!(Debug, "strategy matched", rule = index + 1, strategy = name, write = position.write, len = current_data.len());
+
+    metrics::strategy_applied(&strategy.method);
+
+    socket.rule(Some(format!("rule #{} {}", index + 1, name)));
+
+    relay::with_current(|info| info.strategies.push(name.to_string()));
+
     match strategy.method {
       Strategies::NONE => { },
       Strategies::SPLIT => {
         let send_data: Vec<Vec<u8>> = split::get_split_packet(&current_data, strategy, &sni_data);
 
         if send_data.len() > 1 {
-          let _ = socket.write_all(&send_data[0]);
+          let _ = socket.plain(&send_data[0]);
 
           *current_data = send_data[1].clone();
         }
@@ -95,7 +142,7 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         let send_data: Vec<Vec<u8>> = disorder::get_split_packet(&current_data, strategy, &sni_data);
 
         if send_data.len() > 1 {
-          let _ = utils::send_duplicate(&socket, send_data[0].clone());
+          let _ = socket.duplicate(send_data[0].clone(), config);
 
           *current_data = send_data[1].clone();
         }
@@ -104,9 +151,9 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         let send_data: Vec<Vec<u8>> = disorder::get_split_packet(&current_data, strategy, &sni_data);
 
         if send_data.len() > 1 {
-          let _ = socket.write_all(&send_data[0]);
+          let _ = socket.plain(&send_data[0]);
 
-          let _ = utils::send_duplicate(&socket, send_data[1].clone());
+          let _ = socket.duplicate(send_data[1].clone(), config);
 
           *current_data = vec![];
         }
@@ -115,8 +162,8 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         let send_data: Vec<Vec<u8>> = fake::get_split_packet(&current_data, strategy, &sni_data);
         
         if send_data.len() > 1 {
-          let _ = utils::send_duplicate(&socket, send_data[0].clone());
-          utils::send_drop(&socket, fake::get_fake_packet(send_data[if core::parse_args().fake_packet_reversed { 0 } else { 1 }].clone()));
+          let _ = socket.duplicate(send_data[0].clone(), config);
+          socket.fake(fake::get_fake_packet(send_data[if config.fake_packet_reversed { 0 } else { 1 }].clone(), config), config);
 
           *current_data = send_data[1].clone();
         }
@@ -125,9 +172,9 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         let send_data: Vec<Vec<u8>> = fake::get_split_packet(&current_data, strategy, &sni_data);
         
         if send_data.len() > 1 {
-          let _ = socket.write_all(&send_data[0]);
+          let _ = socket.plain(&send_data[0]);
 
-          utils::send_drop(&socket, fake::get_fake_packet(send_data[if core::parse_args().fake_packet_reversed { 0 } else { 1 }].clone()));
+          socket.fake(fake::get_fake_packet(send_data[if config.fake_packet_reversed { 0 } else { 1 }].clone(), config), config);
 
           *current_data = send_data[1].clone();
         }
@@ -136,9 +183,9 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         let send_data: Vec<Vec<u8>> = fake::get_split_packet(&current_data, strategy, &sni_data);
         
         if send_data.len() > 1 {
-          let _ = socket.write_all(&send_data[0]);
+          let _ = socket.plain(&send_data[0]);
 
-          utils::send_drop(&socket, fake::get_fake_packet(send_data[1].clone()));
+          socket.fake(fake::get_fake_packet(send_data[1].clone(), config), config);
 
           *current_data = send_data[1].clone();
         }
@@ -147,11 +194,11 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         let send_data: Vec<Vec<u8>> = disorder::get_split_packet(&current_data, strategy, &sni_data);
 
         if send_data.len() > 1 {
-          let _ = socket.write_all(&send_data[0]);
+          let _ = socket.plain(&send_data[0]);
 
-          utils::send_drop(&socket, fake::get_fake_packet(send_data[1].clone()));
+          socket.fake(fake::get_fake_packet(send_data[1].clone(), config), config);
 
-          let _ = utils::send_duplicate(&socket, send_data[1].clone());
+          let _ = socket.duplicate(send_data[1].clone(), config);
 
           *current_data = vec![];
         }
@@ -160,17 +207,17 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         let send_data: Vec<Vec<u8>> = fake::get_split_packet(&current_data, strategy, &sni_data);
         
         if send_data.len() > 1 {
-          utils::send_drop(&socket, fake::get_fake_packet(send_data[if core::parse_args().fake_packet_reversed { 0 } else { 1 }].clone()));
+          socket.fake(fake::get_fake_packet(send_data[if config.fake_packet_reversed { 0 } else { 1 }].clone(), config), config);
 
-          let _ = socket.write_all(&send_data[0]);
+          let _ = socket.plain(&send_data[0]);
 
-          utils::send_drop(&socket, fake::get_fake_packet(send_data[if core::parse_args().fake_packet_reversed { 0 } else { 1 }].clone()));
+          socket.fake(fake::get_fake_packet(send_data[if config.fake_packet_reversed { 0 } else { 1 }].clone(), config), config);
 
           *current_data = send_data[1].clone();
         }
       },
       Strategies::MELTDOWN => {
-          let _ = utils::send_duplicate(&socket, current_data.clone());
+          let _ = socket.duplicate(current_data.clone(), config);
 
           *current_data = vec![];
       },
@@ -182,9 +229,9 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         if send_data.len() > 1 {
           let mut ax_part: Vec<u8> = send_data[0].clone();
 
-          ax_part.push(core::parse_args().out_of_band_charid.into());
+          ax_part.push(config.out_of_band_charid.into());
 
-          utils::write_oob_multiplex(&socket, ax_part);
+          socket.oob(ax_part);
 
           *current_data = send_data[1].clone();
         }
@@ -195,12 +242,12 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
           if send_data.len() > 1 {
               let ax_part: Vec<u8> = send_data[0].clone();
 
-              let _ = socket.write_all(&ax_part);
+              let _ = socket.plain(&ax_part);
 
-              let oob_part = core::parse_args().oob_streamhell_data.clone();
+              let oob_part = config.oob_streamhell_data.clone();
 
               for byte in oob_part.as_bytes() {
-                  utils::write_oob_multiplex(&socket, vec![*byte]);
+                  socket.oob(vec![*byte]);
               }
 
               *current_data = send_data[1].clone();
@@ -212,11 +259,11 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         if send_data.len() > 1 {
           let mut ax_part: Vec<u8> = send_data[0].clone();
 
-          ax_part.push(core::parse_args().out_of_band_charid.into());
+          ax_part.push(config.out_of_band_charid.into());
 
-          let _ = utils::set_ttl_raw(&socket, 1);
-          utils::write_oob_multiplex(&socket, ax_part);
-          let _ = utils::set_ttl_raw(&socket, core::parse_args().default_ttl.into());
+          socket.set_ttl(1);
+          socket.oob(ax_part);
+          socket.set_ttl(config.default_ttl.into());
 
           *current_data = send_data[1].clone();
         }
@@ -227,40 +274,43 @@ fn execute_l4_bypasses<'a>(mut socket: &'a TcpStream, config: &'a AuxConfig, cur
         if send_data.len() > 1 {
           let mut ax_part: Vec<u8> = send_data[0].clone();
 
-          ax_part.push(core::parse_args().out_of_band_charid.into());
+          ax_part.push(config.out_of_band_charid.into());
 
-          let _ = utils::set_ttl_raw(&socket, 1);
-          utils::write_oob_multiplex(&socket, ax_part);
-          let _ = utils::set_ttl_raw(&socket, core::parse_args().default_ttl.into());
+          socket.set_ttl(1);
+          socket.oob(ax_part);
+          socket.set_ttl(config.default_ttl.into());
 
-          let _ = utils::send_duplicate(&socket, send_data[1].clone());
+          let _ = socket.duplicate(send_data[1].clone(), config);
 
           *current_data = vec![];
         }
       },
       Strategies::FRAGTLS => {
-        if strategy.add_sni {
-            let (sni_start, _sni_end) = &sni_data;
+        let (sni_start, _sni_end) = &sni_data;
+        let index = strategy.base_index + if strategy.add_sni { *sni_start as i64 } else { 0 };
 
-            *current_data = tamper::edit_tls(current_data.to_vec(), (strategy.base_index + (*sni_start as i64)).try_into().unwrap());
-        } else {
-            *current_data = tamper::edit_tls(current_data.to_vec(), strategy.base_index.try_into().unwrap());
-        } 
+        // A position before the start of the data, say 0-1, leaves the record as it is
+
+        if let Ok(index) = usize::try_from(index) {
+          *current_data = tamper::edit_tls(current_data.to_vec(), index);
+        }
       }
     }
   } 
 
+  socket.rule(None);
+
   if config.disable_sack {
-    utils::disable_sack(&socket);
+    socket.disable_sack();
   }
 
   if config.fake_packet_random {
-    utils::send_drop(&socket, utils::make_random_vec(32 as usize, 0xDEAD));
+    socket.fake(utils::make_random_vec(32 as usize, 0xDEAD), config);
   }
 }
 
-fn execute_l5_bypasses(data: &[u8]) -> Vec<u8> {
-    let current_data = tamper::edit_http(data.to_vec());
+fn execute_l5_bypasses(data: &[u8], config: &AuxConfig) -> Vec<u8> {
+    let current_data = tamper::edit_http(data.to_vec(), config);
 
     current_data
 }
@@ -288,31 +338,248 @@ fn execute_l7_bypasses(config: &AuxConfig) {
   }
 }
 
-fn client_hook(socket: &TcpStream, data: &[u8]) -> Vec<u8> { 
-  let config = core::parse_args();
-
+fn client_hook(socket: &TcpStream, data: &[u8], position: &StreamPosition, config: &AuxConfig) -> Vec<u8> { 
   let sni_data = utils::parse_sni_index(Vec::from(data)); 
 
-  let mut l5_data = execute_l5_bypasses(data);
+  if sni_data != (0, 0) {
+    let sni = String::from_utf8_lossy(&data[sni_data.0 as usize..sni_data.1 as usize]).to_string();
+
+    event!(Info, "sni", sni = sni);
+
+    relay::with_current(|info| info.sni = Some(sni));
+  } else if data.first() == Some(&0x16) {
+    event!(Debug, "no sni in TLS record", len = data.len());
+
+    metrics::sni_missing();
+  }
+
+  let mut l5_data = execute_l5_bypasses(data, config);
+
+  execute_l4_bypasses(socket, config, &mut l5_data, &sni_data, position);
+
+  // Whatever the strategies left over is relayed as an ordinary write
 
-  execute_l4_bypasses(&socket, &config, &mut l5_data, &sni_data);
+  if !l5_data.is_empty() {
+    event!(Debug, "segment", kind = "plain", len = l5_data.len(), ttl = socket.ttl().unwrap_or_default());
+
+    trace::emit("plain", socket.ttl().unwrap_or_default(), false, &l5_data);
+  }
   
-  execute_l7_bypasses(&config);
+  execute_l7_bypasses(config);
 
   l5_data
 }
 
+fn modification_times(paths: &[String]) -> Vec<Option<time::SystemTime>> {
+  paths
+      .iter()
+      .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+      .collect()
+}
+
+// Rebuilds the configuration on SIGHUP or whenever one of the files it was read from changes.
+// A failed rebuild keeps serving with the previous snapshot.
+
+fn reload_watcher() {
+  let mut sources: Vec<String> = core::config().source_files.clone();
+  let mut mtimes = modification_times(&sources);
+
+  while !signals::shutdown_requested() {
+    thread::sleep(time::Duration::from_secs(1));
+
+    let changed: bool = modification_times(&sources) != mtimes;
+
+    if !signals::take_reload_request() && !changed {
+      continue;
+    }
+
+    match core::reload() {
+      Ok(config) => {
+        if let Err(error) = logging::init(config.log_level, config.log_format, &config.log_file) {
+          event!(Error, "log file unavailable, keeping the previous output", path = config.log_file, error = error.to_string());
+        }
+
+        event!(Info, "configuration reloaded", strategies = config.strategies.len());
+
+        sources = config.source_files.clone();
+      },
+      Err(errors) => {
+        event!(Error, "configuration reload failed, keeping the previous one", errors = errors.len());
+
+        config::report(&errors);
+      }
+    }
+
+    mtimes = modification_times(&sources);
+  }
+}
+
 fn main() -> std::io::Result<()> {
-    let config: AuxConfig = core::parse_args();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("convert") {
+        return config::convert_command(&args[1..]);
+    }
 
-    println!("{:#?}", config);
+    if args.first().map(String::as_str) == Some("list-strategies") {
+        config::list_strategies_command();
+
+        return Ok(());
+    }
+
+    if args.first().map(String::as_str) == Some("list-presets") {
+        return config::list_presets_command();
+    }
+
+    if args.first().map(String::as_str) == Some("simulate") {
+        return simulate::simulate_command(&args[1..]);
+    }
+
+    if args.first().map(String::as_str) == Some("probe") {
+        return probe::probe_command(&args[1..]);
+    }
+
+    if args.first().map(String::as_str) == Some("ctl") {
+        return control::ctl_command(&args[1..]);
+    }
+
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        config::help_command();
+
+        return Ok(());
+    }
+
+    if args.iter().any(|arg| arg == "--version" || arg == "-V") {
+        println!("waterfall {}", env!("CARGO_PKG_VERSION"));
+
+        return Ok(());
+    }
+
+    if args.iter().any(|arg| arg == "--check-config") {
+        match core::parse_args() {
+            Ok(config) => println!("Configuration OK: {} strategies", config.strategies.len()),
+            Err(errors) => {
+                config::report(&errors);
+
+                std::process::exit(2);
+            }
+        }
+
+        return Ok(());
+    }
+
+    if args.iter().any(|arg| arg == "--explain" || arg == "--print-rules") {
+        let config: Arc<AuxConfig> = core::config();
+
+        if args.iter().any(|arg| arg == "--json") {
+            println!("{}", serde_json::to_string_pretty(&explain::json(&config)).map_err(std::io::Error::other)?);
+        } else {
+            print!("{}", explain::table(&config));
+        }
+
+        return Ok(());
+    }
+
+    let config: Arc<AuxConfig> = core::config();
+
+    if let Err(error) = logging::init(config.log_level, config.log_format, &config.log_file) {
+        eprintln!("error: can't open log file {}: {}", config.log_file, error);
+
+        std::process::exit(2);
+    }
+
+    print!("{}", explain::table(&config));
+
+    if !config.metrics_addr.is_empty() {
+        if let Err(error) = metrics::serve(&config.metrics_addr) {
+            eprintln!("error: can't serve metrics on {}: {}", config.metrics_addr, error);
+
+            std::process::exit(2);
+        }
+    }
+
+    if !config.learn_file.is_empty() {
+        match learn::open(&config.learn_file) {
+            Ok(count) => event!(Info, "learned strategies loaded", path = config.learn_file, entries = count),
+            Err(error) => {
+                eprintln!("error: can't read learned strategies from {}: {}", config.learn_file, error);
+
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if !config.control_addr.is_empty() {
+        if let Err(error) = control::serve(&config.control_addr, &config.control_token) {
+            eprintln!("error: can't serve the control API on {}: {}", config.control_addr, error);
+
+            std::process::exit(2);
+        }
+    }
 
     let listener: TcpListener = TcpListener::bind(format!("{}:{}", config.bind_host, config.bind_port).replace("\"", "").replace("\"", "")).unwrap();
 
+    signals::install();
+
+    thread::spawn(reload_watcher);
+
+    let mut wake_addr: SocketAddr = listener.local_addr()?;
+
+    if wake_addr.ip().is_unspecified() {
+        wake_addr.set_ip(if wake_addr.is_ipv4() { [127, 0, 0, 1].into() } else { std::net::Ipv6Addr::LOCALHOST.into() });
+    }
+
+    // accept() can't be interrupted portably, so a watcher pokes the listener once a signal arrives
+
+    thread::spawn(move || {
+        while !signals::shutdown_requested() {
+            thread::sleep(time::Duration::from_millis(100));
+        }
+
+        let _ = TcpStream::connect(wake_addr);
+    });
+
     for stream in listener.incoming() {
-        socks::socks5_proxy(&mut (stream?), client_hook);
+        if signals::shutdown_requested() {
+            break;
+        }
+
+        // Running out of descriptors must not end the process and cut every relay with it.
+
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                event!(Warn, "accept failed", error = error.to_string());
+
+                thread::sleep(time::Duration::from_millis(100));
+
+                continue;
+            }
+        };
+
+        let snapshot = core::config();
+        let id = relay::next_id();
+        let accepted = relay::accepted(id);
+
+        metrics::accepted();
+
+        thread::spawn(move || {
+            let _accepted = accepted;
+
+            logging::set_connection(id);
+
+            event!(Debug, "accept", peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default());
+
+            socks::socks5_proxy(&mut stream, id, snapshot, client_hook);
+        });
     }
 
+    drop(listener);
+
+    let (drained, aborted) = relay::drain(core::config().shutdown_grace);
+
+    event!(Info, "shutdown", drained = drained, aborted = aborted);
+
     Ok(())
 }
 
@@ -0,0 +1,332 @@
+use crate::core::{Strategies, STRATEGIES};
+use crate::event;
+use crate::outcome::Outcome;
+use crate::relay::Direction;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+// Upper bounds in seconds, the same for every latency histogram.
+
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: time::Duration) {
+        let seconds = elapsed.as_secs_f64();
+
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+
+        let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(output, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(output, "{}_count {}", name, count);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    Handshake,
+    Resolve,
+    Connect
+}
+
+impl Failure {
+    const ALL: [Failure; 3] = [Failure::Handshake, Failure::Resolve, Failure::Connect];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Failure::Handshake => "handshake",
+            Failure::Resolve => "resolve",
+            Failure::Connect => "connect"
+        }
+    }
+}
+
+static ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static FAILED: [AtomicU64; Failure::ALL.len()] = [const { AtomicU64::new(0) }; Failure::ALL.len()];
+static DOH_FAILURES: AtomicU64 = AtomicU64::new(0);
+static DOH_LATENCY: Histogram = Histogram::new();
+static CONNECT_LATENCY: Histogram = Histogram::new();
+static STRATEGY_APPLIED: [AtomicU64; STRATEGIES.len()] = [const { AtomicU64::new(0) }; STRATEGIES.len()];
+static RELAYED: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+static SNI_FAILURES: AtomicU64 = AtomicU64::new(0);
+static RESETS_AFTER_DESYNC: AtomicU64 = AtomicU64::new(0);
+static FALLBACK_RETRIES: AtomicU64 = AtomicU64::new(0);
+
+// Keyed by outcome and the strategies applied to the connection, in the order they ran.
+// Only combinations that actually occurred are stored.
+
+static OUTCOMES: Mutex<BTreeMap<(String, String), u64>> = Mutex::new(BTreeMap::new());
+
+pub fn accepted() {
+    ACCEPTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn failed(failure: Failure) {
+    FAILED[failure as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn doh_resolved(elapsed: time::Duration, ok: bool) {
+    DOH_LATENCY.observe(elapsed);
+
+    if !ok {
+        DOH_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn connected(elapsed: time::Duration) {
+    CONNECT_LATENCY.observe(elapsed);
+}
+
+pub fn strategy_applied(method: &Strategies) {
+    if let Some(index) = STRATEGIES.iter().position(|info| info.method == *method) {
+        STRATEGY_APPLIED[index].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn relayed(direction: Direction, bytes: u64) {
+    RELAYED[direction as usize].fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn sni_missing() {
+    SNI_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn reset_after_desync() {
+    RESETS_AFTER_DESYNC.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn fallback_retry() {
+    FALLBACK_RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn outcome(outcome: Outcome, strategies: &[String]) {
+    let strategies = if strategies.is_empty() { "none".to_string() } else { strategies.join("+") };
+
+    *OUTCOMES.lock().unwrap().entry((outcome.to_string(), strategies)).or_insert(0) += 1;
+}
+
+fn counter(output: &mut String, name: &str, help: &str, samples: &[(String, u64)]) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} counter", name);
+
+    for (labels, value) in samples {
+        let _ = writeln!(output, "{}{} {}", name, labels, value);
+    }
+}
+
+pub fn render() -> String {
+    let mut output = String::new();
+
+    counter(&mut output, "waterfall_connections_accepted_total", "Client connections accepted",
+        &[(String::new(), ACCEPTED.load(Ordering::Relaxed))]);
+
+    counter(&mut output, "waterfall_connections_failed_total", "Connections that failed before relaying, by stage",
+        &Failure::ALL.map(|failure| (format!("{{stage=\"{}\"}}", failure.label()), FAILED[failure as usize].load(Ordering::Relaxed))));
+
+    counter(&mut output, "waterfall_doh_failures_total", "DNS-over-HTTPS lookups that returned no address",
+        &[(String::new(), DOH_FAILURES.load(Ordering::Relaxed))]);
+
+    DOH_LATENCY.render(&mut output, "waterfall_doh_duration_seconds", "DNS-over-HTTPS lookup latency");
+    CONNECT_LATENCY.render(&mut output, "waterfall_connect_duration_seconds", "Upstream connect latency");
+
+    counter(&mut output, "waterfall_strategy_applied_total", "Hooked writes a strategy was applied to",
+        &STRATEGIES
+            .iter()
+            .zip(&STRATEGY_APPLIED)
+            .map(|(info, count)| (format!("{{strategy=\"{}\"}}", info.name), count.load(Ordering::Relaxed)))
+            .collect::<Vec<(String, u64)>>());
+
+    counter(&mut output, "waterfall_relayed_bytes_total", "Bytes relayed, by direction",
+        &[Direction::Upstream, Direction::Downstream].map(|direction| (format!("{{direction=\"{}\"}}", direction), RELAYED[direction as usize].load(Ordering::Relaxed))));
+
+    counter(&mut output, "waterfall_sni_extraction_failures_total", "Hooked TLS records without a readable SNI",
+        &[(String::new(), SNI_FAILURES.load(Ordering::Relaxed))]);
+
+    counter(&mut output, "waterfall_resets_after_desync_total", "Connections reset within --reset_window of a desynced write",
+        &[(String::new(), RESETS_AFTER_DESYNC.load(Ordering::Relaxed))]);
+
+    counter(&mut output, "waterfall_connection_outcomes_total", "Connections by how the server answered the desynced write, and the strategies applied",
+        &OUTCOMES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((outcome, strategies), count)| (format!("{{outcome=\"{}\",strategies=\"{}\"}}", outcome, strategies), *count))
+            .collect::<Vec<(String, u64)>>());
+
+    counter(&mut output, "waterfall_fallback_retries_total", "Blocked connections reconnected with a fallback strategy set",
+        &[(String::new(), FALLBACK_RETRIES.load(Ordering::Relaxed))]);
+
+    output
+}
+
+fn respond(stream: TcpStream) -> io::Result<()> {
+    let mut request_line = String::new();
+
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<&str>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string())
+    };
+
+    write!(&stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)
+}
+
+// The listener is bound up front so that a bad address fails at startup, not in the background.
+
+pub fn serve(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+
+    event!(Info, "metrics listening", addr = addr);
+
+    // A scrape that stalls must not hold up the next one.
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(time::Duration::from_secs(5)));
+
+                if let Err(error) = respond(stream) {
+                    event!(Debug, "metrics request failed", error = error.to_string());
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    // The value of the sample `series`, a metric name with its labels; 0 when it isn't there.
+
+    fn value(output: &str, series: &str) -> f64 {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .map_or(0.0, |value| value.parse().unwrap())
+    }
+
+    #[test]
+    fn histograms_render_cumulative_buckets() {
+        let histogram = Histogram::new();
+
+        for millis in [3, 30, 30, 2000, 20000] {
+            histogram.observe(time::Duration::from_millis(millis));
+        }
+
+        let mut output = String::new();
+
+        histogram.render(&mut output, "test_seconds", "Test latency");
+
+        let buckets: Vec<f64> = BUCKETS
+            .iter()
+            .map(|bound| value(&output, &format!("test_seconds_bucket{{le=\"{}\"}}", bound)))
+            .collect();
+
+        assert!(output.starts_with("# HELP test_seconds Test latency\n# TYPE test_seconds histogram\n"));
+        assert_eq!(buckets, [1.0, 1.0, 1.0, 3.0, 3.0, 3.0, 3.0, 3.0, 4.0, 4.0, 4.0]);
+        assert_eq!(value(&output, "test_seconds_bucket{le=\"+Inf\"}"), 5.0);
+        assert_eq!(value(&output, "test_seconds_count"), 5.0);
+        assert_eq!(value(&output, "test_seconds_sum"), 22.063);
+        assert!(output.ends_with("test_seconds_count 5\n"));
+    }
+
+    // Other tests in the process may count as well, so only the differences are checked.
+
+    #[test]
+    fn recorded_events_show_up_in_the_exposition() {
+        let before = render();
+
+        accepted();
+        accepted();
+        failed(Failure::Connect);
+        connected(time::Duration::from_millis(20));
+        strategy_applied(&Strategies::DISORDER);
+        relayed(Direction::Upstream, 517);
+        outcome(Outcome::TlsAlert, &["tcp_disorder".to_string(), "tls_record_frag".to_string()]);
+
+        let after = render();
+        let added = |series: &str| value(&after, series) - value(&before, series);
+
+        assert_eq!(added("waterfall_connections_accepted_total"), 2.0);
+        assert_eq!(added("waterfall_connections_failed_total{stage=\"connect\"}"), 1.0);
+        assert_eq!(added("waterfall_connect_duration_seconds_bucket{le=\"0.01\"}"), 0.0);
+        assert_eq!(added("waterfall_connect_duration_seconds_bucket{le=\"0.025\"}"), 1.0);
+        assert_eq!(added("waterfall_connect_duration_seconds_bucket{le=\"+Inf\"}"), 1.0);
+        assert_eq!(added("waterfall_connect_duration_seconds_count"), 1.0);
+        assert_eq!(added("waterfall_strategy_applied_total{strategy=\"tcp_disorder\"}"), 1.0);
+        assert_eq!(added("waterfall_relayed_bytes_total{direction=\"upstream\"}"), 517.0);
+        assert_eq!(added("waterfall_connection_outcomes_total{outcome=\"tls_alert\",strategies=\"tcp_disorder+tls_record_frag\"}"), 1.0);
+
+        for family in ["waterfall_connections_accepted_total", "waterfall_connection_outcomes_total"] {
+            assert!(after.contains(&format!("# TYPE {} counter\n", family)), "{} has no TYPE line", family);
+        }
+
+        assert!(after.contains("# TYPE waterfall_connect_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn a_stalled_scrape_does_not_hold_up_the_next() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+        serve(&addr).unwrap();
+
+        let mut stalled = TcpStream::connect(&addr).unwrap();
+
+        write!(stalled, "GET /met").unwrap();
+
+        let started = time::Instant::now();
+        let mut scrape = TcpStream::connect(&addr).unwrap();
+        let mut response = String::new();
+
+        write!(scrape, "GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        scrape.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(started.elapsed() < time::Duration::from_secs(2), "answered after {:?}", started.elapsed());
+    }
+}
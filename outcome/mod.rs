@@ -0,0 +1,260 @@
+use crate::core::AuxConfig;
+use crate::event;
+use crate::fallback::Handoff;
+use crate::learn;
+use crate::metrics;
+use crate::relay::{self, Direction, Session};
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time;
+
+// How often the wait for the first response looks at the clock, the session deadlines and
+// whether the hooked write has gone out yet.
+
+const POLL: time::Duration = time::Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Reset,
+    Timeout,
+    TlsAlert,
+    BlockPage,
+    Closed,
+    Unknown
+}
+
+impl Outcome {
+    // What the DPI does to a connection it caught, as opposed to the server turning it down.
+
+    pub fn is_blocked(&self) -> bool {
+        matches!(self, Outcome::Reset | Outcome::Timeout | Outcome::BlockPage)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Success => "success",
+            Outcome::Reset => "reset",
+            Outcome::Timeout => "timeout",
+            Outcome::TlsAlert => "tls_alert",
+            Outcome::BlockPage => "block_page",
+            Outcome::Closed => "closed",
+            Outcome::Unknown => "unknown"
+        })
+    }
+}
+
+fn http_status(response: &[u8]) -> Option<u16> {
+    if !response.starts_with(b"HTTP/1.") {
+        return None;
+    }
+
+    std::str::from_utf8(response.get(9..12)?).ok()?.parse().ok()
+}
+
+// Block pages are served in place of the real response, either directly or as a redirect to
+// the ISP's stub, so only 200 and 30x are compared against the patterns.
+
+pub fn classify(response: &[u8], block_page_patterns: &[String]) -> Outcome {
+    if let Some(status) = http_status(response) {
+        let text = String::from_utf8_lossy(response).to_lowercase();
+
+        if (status == 200 || (300..400).contains(&status)) && block_page_patterns.iter().any(|pattern| text.contains(pattern.as_str())) {
+            return Outcome::BlockPage;
+        }
+
+        return Outcome::Success;
+    }
+
+    match response {
+        [0x15, 0x03, ..] => Outcome::TlsAlert,
+        [0x16, 0x03, _, _, _, 0x02, ..] => Outcome::Success,
+        [0x14 | 0x16 | 0x17, 0x03, ..] => Outcome::Success,
+        _ => Outcome::Unknown
+    }
+}
+
+fn record(session: &Session, outcome: Outcome, config: &AuxConfig) {
+    let info = {
+        let mut info = session.info.lock().unwrap();
+
+        info.outcome = Some(outcome.to_string());
+        info.clone()
+    };
+
+    let response_ms = session.since_desync().map(|since| since.as_millis() as u64);
+
+    event!(Info, "outcome", outcome = outcome.to_string(), strategies = info.strategies, set = info.set, response_ms = response_ms);
+
+    metrics::outcome(outcome, &info.strategies);
+
+    learn::observe(&info, outcome, config);
+}
+
+// A reset is reported to a single call on the socket, and when that was a send of the hook,
+// reads only see EOF afterwards. The socket state still tells: a FIN leaves it in CLOSE_WAIT,
+// a reset in CLOSE.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn was_reset(socket: &TcpStream) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    const TCP_CLOSE: u8 = 7;
+
+    // tcpi_state is the first byte of struct tcp_info, the kernel copies no more than asked for
+    let mut info = [0u8; 8];
+    let mut len = info.len() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, info.as_mut_ptr() as *mut libc::c_void, &mut len)
+    };
+
+    result == 0 && len > 0 && info[0] == TCP_CLOSE
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn was_reset(_socket: &TcpStream) -> bool {
+    false
+}
+
+struct Response {
+    outcome: Outcome,
+    data: io::Result<Vec<u8>>,
+}
+
+// Waits for the first bytes from the server and classifies them. Nothing is passed on yet, so
+// that a blocked attempt can be retried without the client ever seeing it.
+
+fn await_response(session: &Session, mut from: &TcpStream, config: &AuxConfig) -> io::Result<Response> {
+    let mut buffer = vec![0u8; 16384];
+
+    loop {
+        session.check()?;
+
+        from.set_read_timeout(Some(POLL))?;
+
+        let (outcome, data) = match from.read(&mut buffer) {
+            Ok(0) if session.in_reset_window() && was_reset(from) => (Outcome::Reset, Ok(vec![])),
+            Ok(0) => (Outcome::Closed, Ok(vec![])),
+            Ok(received) => (classify(&buffer[..received], &config.block_page_patterns), Ok(buffer[..received].to_vec())),
+            Err(error) if relay::is_timeout(&error) => {
+                if session.since_desync().is_none_or(|since| since < config.response_timeout) {
+                    continue;
+                }
+
+                (Outcome::Timeout, Ok(vec![]))
+            },
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) if error.kind() == io::ErrorKind::ConnectionReset && session.in_reset_window() => (Outcome::Reset, Err(error)),
+            Err(error) => (Outcome::Closed, Err(error))
+        };
+
+        record(session, outcome, config);
+
+        return Ok(Response { outcome, data });
+    }
+}
+
+// The same classification for a connection without a relay session, as `waterfall probe` opens
+// them: nothing else is sent on the socket, so any reset before the response counts as one.
+
+pub fn probe(mut socket: &TcpStream, config: &AuxConfig) -> Outcome {
+    let mut buffer = vec![0u8; 16384];
+
+    if socket.set_read_timeout(Some(config.response_timeout)).is_err() {
+        return Outcome::Closed;
+    }
+
+    loop {
+        return match socket.read(&mut buffer) {
+            Ok(0) if was_reset(socket) => Outcome::Reset,
+            Ok(0) => Outcome::Closed,
+            Ok(received) => classify(&buffer[..received], &config.block_page_patterns),
+            Err(error) if relay::is_timeout(&error) => Outcome::Timeout,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) if error.kind() == io::ErrorKind::ConnectionReset => Outcome::Reset,
+            Err(_) => Outcome::Closed
+        };
+    }
+}
+
+// Settles the first response of a connection, retrying blocked attempts through `handoff` when
+// fallback sets are configured, and passes the response that was kept on to the client. The
+// rest of the stream is left to the ordinary relay copy, which continues from the returned
+// socket with the number of bytes relayed so far.
+
+pub fn first_response(session: &Session, mut from: TcpStream, mut to: &TcpStream, config: &AuxConfig, handoff: Option<&Handoff>) -> io::Result<(u64, TcpStream)> {
+    if config.response_timeout.is_zero() {
+        return Ok((0, from));
+    }
+
+    let mut attempt = 0;
+
+    let response = loop {
+        let response = await_response(session, &from, config)?;
+
+        match handoff.filter(|_| response.outcome.is_blocked()).and_then(|handoff| handoff.retry(attempt + 1, response.outcome)) {
+            Some(server) => {
+                from = server;
+                attempt += 1;
+            },
+            None => break response
+        }
+    };
+
+    let data = response.data?;
+
+    to.write_all(&data)?;
+
+    session.relayed(Direction::Downstream, data.len() as u64);
+    session.touch();
+
+    Ok((data.len() as u64, from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_are_classified() {
+        let patterns = vec!["warning.rt.ru".to_string(), "blocked.mts.ru".to_string()];
+
+        let cases: &[(&[u8], Outcome)] = &[
+            (b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", Outcome::Success),
+            (b"HTTP/1.1 200 OK\r\n\r\n<iframe src=\"http://Warning.RT.ru/?id=1\">", Outcome::BlockPage),
+            (b"HTTP/1.1 302 Found\r\nLocation: http://blocked.mts.ru/\r\n\r\n", Outcome::BlockPage),
+            (b"HTTP/1.0 307 Temporary Redirect\r\nLocation: https://warning.rt.ru\r\n\r\n", Outcome::BlockPage),
+            (b"HTTP/1.1 301 Moved Permanently\r\nLocation: https://example.com/\r\n\r\n", Outcome::Success),
+            (b"HTTP/1.1 404 Not Found\r\n\r\nsee warning.rt.ru", Outcome::Success),
+            (b"HTTP/1.1 451 Unavailable For Legal Reasons\r\n\r\nwarning.rt.ru", Outcome::Success),
+            (b"HTTP/2 200\r\n\r\n", Outcome::Unknown),
+            (b"HTTP/1.1 2", Outcome::Unknown),
+            (&[0x16, 0x03, 0x03, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00], Outcome::Success),
+            (&[0x16, 0x03, 0x03, 0x00, 0x04, 0x0b, 0x00, 0x00, 0x00], Outcome::Success),
+            (&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01], Outcome::Success),
+            (&[0x17, 0x03, 0x03, 0x00, 0x10], Outcome::Success),
+            (&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28], Outcome::TlsAlert),
+            (&[0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x46], Outcome::TlsAlert),
+            (&[], Outcome::Unknown),
+            (&[0x16], Outcome::Unknown),
+            (&[0x15, 0x03], Outcome::TlsAlert),
+            (&[0x16, 0x03, 0x03], Outcome::Success),
+            (&[0x16, 0x02, 0x00, 0x00, 0x04, 0x02], Outcome::Unknown),
+            (b"SSH-2.0-OpenSSH_9.6\r\n", Outcome::Unknown),
+        ];
+
+        for (response, outcome) in cases {
+            assert_eq!(classify(response, &patterns), *outcome, "{:?}", String::from_utf8_lossy(response));
+        }
+    }
+
+    #[test]
+    fn without_patterns_every_http_response_is_a_success() {
+        assert_eq!(classify(b"HTTP/1.1 200 OK\r\n\r\nwarning.rt.ru", &[]), Outcome::Success);
+    }
+}
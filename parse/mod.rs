@@ -0,0 +1,131 @@
+// Parsers for bytes that arrive from the client. None of them may panic, whatever the input,
+// and none of them depend on the rest of the crate, so the fuzz targets in fuzz/ build this
+// file on its own.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    Truncated,
+    Version(u8),
+    Command(u8),
+    AddressType(u8)
+}
+
+impl RequestError {
+    // The REP field of the SOCKS5 reply that turns the request down.
+
+    pub fn reply(&self) -> u8 {
+        match self {
+            RequestError::Command(_) => 0x07,
+            RequestError::AddressType(_) => 0x08,
+            _ => 0x01
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Truncated => write!(f, "truncated SOCKS request"),
+            RequestError::Version(version) => write!(f, "unsupported SOCKS version {}", version),
+            RequestError::Command(command) => write!(f, "unsupported SOCKS command {}", command),
+            RequestError::AddressType(addr_type) => write!(f, "unsupported SOCKS address type {}", addr_type)
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub addr_type: u8,
+    pub host: &'a [u8],
+    pub port: u16
+}
+
+// A SOCKS5 CONNECT request: VER CMD RSV ATYP DST.ADDR DST.PORT, where DST.ADDR is 4 bytes for
+// IPv4, 16 for IPv6, or a length byte and that many bytes of domain name.
+
+pub fn request(buffer: &[u8]) -> Result<Request<'_>, RequestError> {
+    let [version, command, _, addr_type, ref rest @ ..] = *buffer else {
+        return Err(RequestError::Truncated);
+    };
+
+    if version != 5 {
+        return Err(RequestError::Version(version));
+    }
+
+    if command != 1 {
+        return Err(RequestError::Command(command));
+    }
+
+    let (host, rest) = match addr_type {
+        1 => rest.split_at_checked(4),
+        3 => rest.split_first().and_then(|(len, rest)| rest.split_at_checked(*len as usize)),
+        4 => rest.split_at_checked(16),
+        _ => return Err(RequestError::AddressType(addr_type))
+    }.ok_or(RequestError::Truncated)?;
+
+    let [high, low, ..] = *rest else {
+        return Err(RequestError::Truncated);
+    };
+
+    Ok(Request { addr_type, host, port: u16::from_be_bytes([high, low]) })
+}
+
+// Where the server name sits in a ClientHello, as (start, end), or (0, 0) without one. Looks
+// for the server_name extension header: type 0, extension length 2 more than the list length,
+// and a name length below 256.
+
+pub fn parse_sni_index(source: Vec<u8>) -> (u32, u32) {
+    if source.len() < 48 || source[0] != 0x16 || source[5] != 0x01 {
+        return (0, 0);
+    }
+
+    source.windows(9).enumerate().find_map(|(i, window)| {
+        let [0x00, 0x00, _, extension_len, _, list_len, _, 0x00, len] = *window else {
+            return None;
+        };
+
+        let start = i + 9;
+        let end = start + len as usize;
+
+        (extension_len as isize - list_len as isize == 2 && len > 0 && end <= source.len()).then_some((start as u32, end as u32))
+    }).unwrap_or((0, 0))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageState {
+    Complete,
+    Partial,
+    Unknown
+}
+
+const HTTP_METHODS: [&[u8]; 9] = [b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE "];
+
+pub fn message_state(data: &[u8]) -> MessageState {
+    if data.first() == Some(&0x16) {
+        if data.len() < 5 {
+            return MessageState::Partial;
+        }
+
+        if data[1] != 0x03 {
+            return MessageState::Unknown;
+        }
+
+        let record_len: usize = 5 + u16::from_be_bytes([data[3], data[4]]) as usize;
+
+        return if data.len() >= record_len { MessageState::Complete } else { MessageState::Partial };
+    }
+
+    if !HTTP_METHODS.iter().any(|method| data.starts_with(method)) {
+        return MessageState::Unknown;
+    }
+
+    if data.windows(4).any(|window| window == b"\r\n\r\n") || data.windows(2).any(|window| window == b"\n\n") {
+        MessageState::Complete
+    } else {
+        MessageState::Partial
+    }
+}
@@ -0,0 +1,44 @@
+use crate::config::Preset;
+
+// Presets and their host lists are compiled into the binary. A preset's version is bumped
+// whenever its rules change, so `--preset youtube@1` keeps failing loudly instead of silently
+// picking up different behaviour.
+
+const PRESETS: &[(&str, &str)] = &[
+    ("youtube", include_str!("youtube.toml")),
+    ("discord", include_str!("discord.toml")),
+    ("tls", include_str!("tls.toml")),
+    ("http", include_str!("http.toml")),
+];
+
+const HOST_LISTS: &[(&str, &str)] = &[
+    ("youtube.txt", include_str!("youtube.txt")),
+    ("discord.txt", include_str!("discord.txt")),
+];
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    PRESETS.iter().map(|(name, _)| *name)
+}
+
+// Host lists are inlined, since there is no file on disk to point --filter_sni at.
+
+pub fn builtin(name: &str) -> Option<Result<Preset, String>> {
+    let (_, text) = PRESETS.iter().find(|(known, _)| *known == name)?;
+
+    Some(toml::from_str::<Preset>(text)
+        .map_err(|error| format!("built-in preset {}: {}", name, error))
+        .and_then(|mut preset| {
+            for rule in &mut preset.rules {
+                for file in rule.host_files.drain(..) {
+                    let (_, hosts) = HOST_LISTS
+                        .iter()
+                        .find(|(known, _)| *known == file)
+                        .ok_or(format!("built-in preset {}: missing host list {}", name, file))?;
+
+                    rule.hosts.extend(hosts.lines().map(str::trim).filter(|host| !host.is_empty()).map(String::from));
+                }
+            }
+
+            Ok(preset)
+        }))
+}
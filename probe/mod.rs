@@ -0,0 +1,364 @@
+use crate::config;
+use crate::core::{self, AuxConfig, StreamPosition, STRATEGIES};
+use crate::event;
+use crate::logging::{self, Level};
+use crate::outcome::{self, Outcome};
+use crate::relay;
+use crate::tls;
+use crate::utils;
+
+use serde_json::json;
+
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::time;
+
+const PROBE_USAGE: &str = "Usage: waterfall probe <domain> [--port <port>] [--connect <host:port>] [--http] [--attempts <n>] [--positions <list>] [--ttls <list>] [--no_stacks] [--json] [FLAGS]
+
+Connects to the domain once per strategy candidate, sends a ClientHello (or an HTTP request
+with --http) through the desync pipeline and classifies the first response. Candidates are
+every TCP strategy at each of --positions, fakes at each of --ttls, and the pairs `auto` can
+stack. Prints the command line fragments that got a response every time, best first. FLAGS
+apply to every candidate, and --response_timeout bounds the wait for each response.";
+
+// Positions every strategy is tried at, and TTLs for the ones that send fakes. Small on
+// purpose: a blocked candidate costs a whole --response_timeout.
+
+const POSITIONS: &str = "1,1+s,2+s";
+const TTLS: &str = "3,5,8";
+
+struct Candidate {
+    fragment: Vec<String>,
+    strategies: usize,
+}
+
+struct Trial {
+    candidate: Candidate,
+    outcomes: Vec<Outcome>,
+    latency: time::Duration,
+}
+
+impl Trial {
+    fn working(&self, attempts: usize) -> bool {
+        self.outcomes.len() == attempts && self.outcomes.iter().all(|outcome| *outcome == Outcome::Success)
+    }
+
+    fn fragment(&self) -> String {
+        self.candidate.fragment.join(" ")
+    }
+
+    fn json(&self, attempts: usize) -> serde_json::Value {
+        json!({
+            "fragment": self.fragment(),
+            "outcomes": self.outcomes.iter().map(Outcome::to_string).collect::<Vec<String>>(),
+            "working": self.working(attempts),
+            "latency_ms": self.latency.as_millis() as u64,
+        })
+    }
+}
+
+fn strategy(names: &str, positions: &str, fake_ttl: Option<&str>) -> Vec<String> {
+    let mut fragment: Vec<String> = Vec::new();
+
+    if let Some(ttl) = fake_ttl {
+        fragment.extend(["--fake_packet_ttl".to_string(), ttl.to_string()]);
+    }
+
+    fragment.extend(["--dpi_bypass_strategies".to_string(), names.to_string()]);
+    fragment.extend(positions.split(' ').map(String::from));
+
+    fragment
+}
+
+// The first candidate is always the baseline without strategies. Strategies that ignore
+// positions are tried once, and the stacks are the pairs `auto` accepts, with the second
+// strategy one byte before the first.
+
+fn candidates(positions: &[String], ttls: &[String], stacks: bool) -> Vec<Candidate> {
+    let tcp: Vec<_> = STRATEGIES
+        .iter()
+        .filter(|info| info.protocol == "tcp")
+        .collect();
+
+    let mut candidates = vec![Candidate { fragment: vec![], strategies: 0 }];
+
+    let mut push = |names: String, position: String, fakes: bool, strategies: usize| {
+        if !fakes {
+            candidates.push(Candidate { fragment: strategy(&names, &position, None), strategies });
+
+            return;
+        }
+
+        for ttl in ttls {
+            candidates.push(Candidate { fragment: strategy(&names, &position, Some(ttl)), strategies });
+        }
+    };
+
+    for info in &tcp {
+        if !info.method.uses_position() {
+            push(info.name.to_string(), "1".to_string(), info.method.sends_fakes(), 1);
+
+            continue;
+        }
+
+        for position in positions {
+            push(info.name.to_string(), position.clone(), info.method.sends_fakes(), 1);
+        }
+    }
+
+    if !stacks {
+        return candidates;
+    }
+
+    for base in tcp.iter().filter(|info| info.method.uses_position()) {
+        for paired in tcp.iter().filter(|paired| core::auto_pairs(&base.method, &paired.method)) {
+            for position in positions {
+                push(format!("{},{}", base.name, paired.name), format!("{} auto", position), base.method.sends_fakes() || paired.method.sends_fakes(), 2);
+            }
+        }
+    }
+
+    candidates
+}
+
+// A TLS 1.3 ClientHello shaped like a browser's, with fresh random, session ID and key share
+// for every connection so that nothing on the path can match a replay.
+
+fn client_hello(domain: &str, seed: u32) -> Vec<u8> {
+    let random = utils::make_random_vec(96, seed);
+
+    let ciphers: &[u16] = &[0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8];
+    let groups: &[u16] = &[0x001d, 0x0017, 0x0018];
+    let signatures: &[u16] = &[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601];
+
+    let extensions = [
+        tls::server_name(domain),
+        tls::extension(0x000a, &tls::with_length(2, &tls::list(groups))),
+        tls::extension(0x000b, &[0x01, 0x00]),
+        tls::extension(0x000d, &tls::with_length(2, &tls::list(signatures))),
+        tls::extension(0x0010, &tls::with_length(2, b"\x02h2\x08http/1.1")),
+        tls::extension(0x002b, &[0x04, 0x03, 0x04, 0x03, 0x03]),
+        tls::extension(0x002d, &[0x01, 0x01]),
+        tls::extension(0x0033, &tls::with_length(2, &[&[0x00, 0x1d][..], &tls::with_length(2, &random[64..96])].concat())),
+    ];
+
+    tls::client_hello(&random[..32], &random[32..64], ciphers, &extensions)
+}
+
+fn http_request(domain: &str) -> Vec<u8> {
+    format!("GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: Mozilla/5.0\r\nAccept: */*\r\nConnection: close\r\n\r\n", domain).into_bytes()
+}
+
+// One connection through the same hook the proxy runs on a client's first write. The time
+// is until the first response, connect included.
+
+fn attempt(addr: SocketAddr, payload: &[u8], config: &AuxConfig) -> (Outcome, time::Duration) {
+    let started = time::Instant::now();
+
+    let socket = match core::connect_socket(addr, config) {
+        Ok(socket) => socket,
+        Err(error) => {
+            event!(Warn, "probe connection failed", addr = addr.to_string(), error = error.to_string());
+
+            return (if relay::is_timeout(&error) { Outcome::Timeout } else { Outcome::Closed }, started.elapsed());
+        }
+    };
+
+    let position = StreamPosition {
+        write: 1,
+        offset: 0,
+        len: payload.len() as u64,
+        ..StreamPosition::default()
+    };
+
+    let rest = crate::client_hook(&socket, payload, &position, config);
+
+    let _ = (&socket).write_all(&rest);
+
+    let outcome = outcome::probe(&socket, config);
+    let elapsed = started.elapsed();
+
+    let _ = socket.shutdown(Shutdown::Both);
+
+    (outcome, elapsed)
+}
+
+fn seed() -> u32 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0)
+}
+
+fn exit_with(message: String) -> ! {
+    eprintln!("error: {}", message);
+
+    std::process::exit(2);
+}
+
+fn list(value: Option<&String>, option: &str) -> Vec<String> {
+    let Some(value) = value else {
+        exit_with(format!("{} expects a comma separated list", option));
+    };
+
+    value.split(',').filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+pub fn probe_command(args: &[String]) -> io::Result<()> {
+    let Some(domain) = args.first().filter(|domain| !domain.starts_with("--")) else {
+        eprintln!("{}", PROBE_USAGE);
+
+        std::process::exit(2);
+    };
+
+    let mut port: Option<u16> = None;
+    let mut connect: Option<String> = None;
+    let mut http = false;
+    let mut attempts: usize = 2;
+    let mut positions: Vec<String> = POSITIONS.split(',').map(String::from).collect();
+    let mut ttls: Vec<String> = TTLS.split(',').map(String::from).collect();
+    let mut stacks = true;
+    let mut json_output = false;
+    let mut flags: Vec<String> = Vec::new();
+
+    let mut args = args[1..].iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => match args.next().and_then(|port| port.parse::<u16>().ok()) {
+                Some(value) => port = Some(value),
+                None => exit_with("--port expects a port number".to_string())
+            },
+            "--connect" => match args.next() {
+                Some(value) => connect = Some(value.clone()),
+                None => exit_with("--connect expects <host:port>".to_string())
+            },
+            "--attempts" => match args.next().and_then(|attempts| attempts.parse::<usize>().ok()).filter(|attempts| *attempts > 0) {
+                Some(value) => attempts = value,
+                None => exit_with("--attempts expects a number above 0".to_string())
+            },
+            "--positions" => positions = list(args.next(), arg),
+            "--ttls" => ttls = list(args.next(), arg),
+            "--http" => http = true,
+            "--no_stacks" => stacks = false,
+            "--json" => json_output = true,
+            _ => flags.push(arg.clone())
+        }
+    }
+
+    let base = core::parse_args_from(flags.clone()).unwrap_or_else(|errors| {
+        config::report(&errors);
+
+        std::process::exit(2);
+    });
+
+    if base.response_timeout.is_zero() {
+        exit_with("probe needs a --response_timeout above 0".to_string());
+    }
+
+    // The hook logs every strategy it applies, which is noise here unless asked for
+
+    let level = if flags.iter().any(|flag| flag == "--log_level") { base.log_level } else { Level::Warn };
+
+    if let Err(error) = logging::init(level, base.log_format, &base.log_file) {
+        exit_with(format!("can't open log file {}: {}", base.log_file, error));
+    }
+
+    let port = port.unwrap_or(if http { 80 } else { 443 });
+
+    let target = connect.unwrap_or_else(|| format!("{}:{}", domain, port));
+
+    let addr = match target.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => exit_with(format!("{} resolves to no address", target)),
+        Err(error) => exit_with(format!("can't resolve {}: {}", target, error))
+    };
+
+    let candidates = candidates(&positions, &ttls, stacks);
+    let total = candidates.len();
+
+    eprintln!("probing {} at {} with {} candidates, {} attempt(s) each", domain, addr, total, attempts);
+
+    let mut trials: Vec<Trial> = Vec::new();
+
+    for (index, candidate) in candidates.into_iter().enumerate() {
+        let config = match core::parse_args_from([flags.clone(), candidate.fragment.clone()].concat()) {
+            Ok(config) => config,
+            Err(errors) => {
+                eprintln!("[{}/{}] {}: skipped, {}", index + 1, total, candidate.fragment.join(" "), errors[0]);
+
+                continue;
+            }
+        };
+
+        let mut trial = Trial { candidate, outcomes: Vec::new(), latency: time::Duration::ZERO };
+
+        // A candidate has to work every time, so the first failure settles it
+
+        while trial.outcomes.len() < attempts && trial.outcomes.last().is_none_or(|outcome| *outcome == Outcome::Success) {
+            let payload = if http { http_request(domain) } else { client_hello(domain, seed()) };
+
+            let (outcome, elapsed) = attempt(addr, &payload, &config);
+
+            trial.outcomes.push(outcome);
+            trial.latency = trial.latency.max(elapsed);
+        }
+
+        let fragment = if trial.candidate.strategies == 0 { "baseline".to_string() } else { trial.fragment() };
+        let outcomes = trial.outcomes.iter().map(Outcome::to_string).collect::<Vec<String>>();
+
+        eprintln!("[{}/{}] {}: {}", index + 1, total, fragment, outcomes.join(", "));
+
+        trials.push(trial);
+    }
+
+    let (baseline, tried) = match trials.first().filter(|trial| trial.candidate.strategies == 0) {
+        Some(_) => {
+            let mut trials = trials;
+            let baseline = trials.remove(0);
+
+            (Some(baseline), trials)
+        },
+        None => (None, trials)
+    };
+
+    // Fewer strategies first, then the faster ones. The sort is stable, so ties keep the
+    // enumeration order, where plain splits come before fakes.
+
+    let mut working: Vec<&Trial> = tried.iter().filter(|trial| trial.working(attempts)).collect();
+
+    working.sort_by_key(|trial| (trial.candidate.strategies, trial.latency));
+
+    if json_output {
+        let report = json!({
+            "domain": domain,
+            "addr": addr.to_string(),
+            "attempts": attempts,
+            "baseline": baseline.as_ref().map(|trial| trial.json(attempts)),
+            "candidates": tried.iter().map(|trial| trial.json(attempts)).collect::<Vec<_>>(),
+            "working": working.iter().map(|trial| trial.fragment()).collect::<Vec<String>>(),
+        });
+
+        println!("{}", serde_json::to_string_pretty(&report).map_err(io::Error::other)?);
+
+        return Ok(());
+    }
+
+    if let Some(baseline) = baseline.as_ref().filter(|trial| trial.working(attempts)) {
+        println!("{} answers without any strategy ({} ms), nothing needs bypassing", domain, baseline.latency.as_millis());
+    }
+
+    if working.is_empty() {
+        println!("no working strategies among {} candidates", tried.len());
+
+        return Ok(());
+    }
+
+    println!("working strategies for {}, best first:", domain);
+
+    for (rank, trial) in working.iter().enumerate() {
+        println!("{:>4}. {}  ({} ms)", rank + 1, trial.fragment(), trial.latency.as_millis());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,481 @@
+use crate::core::AuxConfig;
+use crate::event;
+use crate::logging;
+use crate::metrics;
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::collections::{BTreeMap, BTreeSet};
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+static SESSIONS: Mutex<BTreeMap<u64, Arc<Session>>> = Mutex::new(BTreeMap::new());
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Connections from accept until their thread returns. A session only exists once the SOCKS
+// handshake and the upstream connect are done, so shutdown waits for both.
+
+static ACCEPTED: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+pub struct Accepted(u64);
+
+impl Drop for Accepted {
+    fn drop(&mut self) {
+        ACCEPTED.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn accepted(id: u64) -> Accepted {
+    ACCEPTED.lock().unwrap().insert(id);
+
+    Accepted(id)
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Session>>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Upstream,
+    Downstream
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Upstream => "upstream",
+            Direction::Downstream => "downstream"
+        })
+    }
+}
+
+// What the control API reports about a session besides its byte counts.
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub target: String,
+    pub sni: Option<String>,
+    pub strategies: Vec<String>,
+    pub outcome: Option<String>,
+    // The fallback set in use, 0 for the rules' own strategies
+    pub set: usize,
+}
+
+pub struct Session {
+    pub id: u64,
+    pub info: Mutex<SessionInfo>,
+    client: TcpStream,
+    server: Mutex<TcpStream>,
+    started: time::Instant,
+    last_activity: AtomicU64,
+    closed: AtomicBool,
+    directions: AtomicU8,
+    relayed: [AtomicU64; 2],
+    desynced_at: AtomicU64,
+    reason: Mutex<Option<String>>,
+    idle_timeout: time::Duration,
+    max_lifetime: time::Duration,
+    reset_window: time::Duration,
+}
+
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+pub fn next_id() -> u64 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Session {
+    pub fn open(id: u64, client: &TcpStream, server: &TcpStream, config: &AuxConfig) -> io::Result<Arc<Session>> {
+        let write_timeout = if config.idle_timeout.is_zero() { None } else { Some(config.idle_timeout) };
+
+        client.set_write_timeout(write_timeout)?;
+        server.set_write_timeout(write_timeout)?;
+
+        let session = Arc::new(Session {
+            id,
+            info: Mutex::new(SessionInfo::default()),
+            client: client.try_clone()?,
+            server: Mutex::new(server.try_clone()?),
+            started: time::Instant::now(),
+            last_activity: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            directions: AtomicU8::new(2),
+            relayed: [AtomicU64::new(0), AtomicU64::new(0)],
+            desynced_at: AtomicU64::new(0),
+            reason: Mutex::new(None),
+            idle_timeout: config.idle_timeout,
+            max_lifetime: config.max_lifetime,
+            reset_window: config.reset_window,
+        });
+
+        SESSIONS.lock().unwrap().insert(session.id, session.clone());
+
+        Ok(session)
+    }
+
+    // Binds the session to the calling thread, for log lines and for the hook to report what it
+    // found and did.
+
+    pub fn attach(self: &Arc<Session>) {
+        logging::set_connection(self.id);
+
+        CURRENT.with(|current| *current.borrow_mut() = Some(self.clone()));
+    }
+
+    pub fn age(&self) -> time::Duration {
+        self.started.elapsed()
+    }
+
+    pub fn client_addr(&self) -> String {
+        self.client.peer_addr().map(|addr| addr.to_string()).unwrap_or_default()
+    }
+
+    pub fn relayed_bytes(&self, direction: Direction) -> u64 {
+        self.relayed[direction as usize].load(Ordering::Relaxed)
+    }
+
+    fn idle_for(&self) -> time::Duration {
+        self.started.elapsed().saturating_sub(time::Duration::from_millis(self.last_activity.load(Ordering::Relaxed)))
+    }
+
+    pub fn touch(&self) {
+        self.last_activity.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn relayed(&self, direction: Direction, bytes: u64) {
+        self.relayed[direction as usize].fetch_add(bytes, Ordering::Relaxed);
+
+        metrics::relayed(direction, bytes);
+    }
+
+    // Stored as milliseconds since the session started, plus one so that zero means never.
+
+    pub fn desynced(&self) {
+        let _ = self.desynced_at.compare_exchange(0, self.started.elapsed().as_millis() as u64 + 1, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn since_desync(&self) -> Option<time::Duration> {
+        match self.desynced_at.load(Ordering::Relaxed) {
+            0 => None,
+            desynced_at => Some(self.started.elapsed().saturating_sub(time::Duration::from_millis(desynced_at - 1)))
+        }
+    }
+
+    // Starts over with another upstream connection after a blocked attempt. What the previous
+    // attempt applied and how it ended is dropped, the new attempt reports its own.
+
+    pub fn retry(&self, server: &TcpStream) -> io::Result<()> {
+        server.set_write_timeout(if self.idle_timeout.is_zero() { None } else { Some(self.idle_timeout) })?;
+
+        let previous = std::mem::replace(&mut *self.server.lock().unwrap(), server.try_clone()?);
+        let _ = previous.shutdown(Shutdown::Both);
+
+        self.desynced_at.store(0, Ordering::Relaxed);
+
+        let mut info = self.info.lock().unwrap();
+
+        info.strategies.clear();
+        info.outcome = None;
+
+        Ok(())
+    }
+
+    pub fn in_reset_window(&self) -> bool {
+        self.since_desync().is_some_and(|since| since <= self.reset_window)
+    }
+
+    fn reset_after_desync(&self, error: &io::Error) -> bool {
+        error.kind() == io::ErrorKind::ConnectionReset && self.in_reset_window()
+    }
+
+    pub fn check(&self) -> io::Result<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "relay closed"));
+        }
+
+        if !self.idle_timeout.is_zero() && self.idle_for() >= self.idle_timeout {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"));
+        }
+
+        if !self.max_lifetime.is_zero() && self.started.elapsed() >= self.max_lifetime {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "maximum lifetime reached"));
+        }
+
+        Ok(())
+    }
+
+    // Read timeout for the next blocking read: whichever of the idle and lifetime deadlines
+    // comes first. Waking up early only costs a `check` and another wait.
+
+    fn tick(&self) -> Option<time::Duration> {
+        let idle = (!self.idle_timeout.is_zero()).then(|| self.idle_timeout.saturating_sub(self.idle_for()));
+        let lifetime = (!self.max_lifetime.is_zero()).then(|| self.max_lifetime.saturating_sub(self.started.elapsed()));
+
+        [idle, lifetime]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|timeout| timeout.max(time::Duration::from_millis(1)))
+    }
+
+    pub fn rearm(&self, from: &TcpStream) -> io::Result<()> {
+        self.check()?;
+
+        from.set_read_timeout(self.tick())
+    }
+
+    pub fn close(&self) {
+        if !self.closed.swap(true, Ordering::Relaxed) {
+            let _ = self.client.shutdown(Shutdown::Both);
+            let _ = self.server.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+
+    // Closing from outside shows up as a plain EOF in both directions, so the reason is kept here.
+
+    pub fn abort(&self, reason: &str) {
+        self.reason.lock().unwrap().get_or_insert_with(|| reason.to_string());
+
+        self.close();
+    }
+
+    // EOF is passed on as a FIN to the other side, any error tears down both directions.
+    // The first error is what gets reported as the reason the session closed.
+
+    pub fn finish(&self, to: &TcpStream, result: io::Result<u64>) -> io::Result<u64> {
+        match result {
+            Ok(_) => {
+                let _ = to.shutdown(Shutdown::Write);
+            },
+            Err(ref error) => {
+                let mut reason = self.reason.lock().unwrap();
+
+                if reason.is_none() && self.reset_after_desync(error) {
+                    metrics::reset_after_desync();
+                }
+
+                reason.get_or_insert_with(|| error.to_string());
+
+                drop(reason);
+
+                self.close()
+            }
+        }
+
+        if self.directions.fetch_sub(1, Ordering::AcqRel) == 1 {
+            SESSIONS.lock().unwrap().remove(&self.id);
+
+            let reason = self.reason.lock().unwrap().take().unwrap_or_else(|| "closed by both peers".to_string());
+
+            let [upstream, downstream] = self.relayed.each_ref().map(|bytes| bytes.load(Ordering::Relaxed));
+            let info = self.info.lock().unwrap().clone();
+
+            event!(Info, "close", reason = reason, outcome = info.outcome, strategies = info.strategies,
+                upstream = upstream, downstream = downstream, duration_ms = self.started.elapsed().as_millis() as u64);
+        }
+
+        result
+    }
+
+    pub fn copy(&self, from: &TcpStream, to: &TcpStream, direction: Direction) -> io::Result<u64> {
+        self.rearm(from)?;
+
+        copy(self, from, to, direction)
+    }
+}
+
+pub fn with_current(update: impl FnOnce(&mut SessionInfo)) {
+    CURRENT.with(|current| {
+        if let Some(session) = current.borrow().as_ref() {
+            update(&mut session.info.lock().unwrap());
+        }
+    });
+}
+
+pub fn find(id: u64) -> Option<Arc<Session>> {
+    SESSIONS.lock().unwrap().get(&id).cloned()
+}
+
+pub fn active_sessions() -> Vec<Arc<Session>> {
+    SESSIONS.lock().unwrap().values().cloned().collect()
+}
+
+// Accepted connections still in the handshake, and sessions whose threads are still relaying.
+
+fn open_connections() -> usize {
+    let mut open = ACCEPTED.lock().unwrap().clone();
+
+    open.extend(SESSIONS.lock().unwrap().keys());
+    open.len()
+}
+
+// Waits for the open connections to finish on their own, then closes whatever is left.
+// Returns how many connections were drained and how many had to be aborted; the ones still
+// in the handshake end with the process.
+
+pub fn drain(grace: time::Duration) -> (usize, usize) {
+    let deadline = time::Instant::now() + grace;
+    let active = open_connections();
+
+    while open_connections() > 0 && time::Instant::now() < deadline {
+        thread::sleep(time::Duration::from_millis(50));
+    }
+
+    let remaining = open_connections();
+
+    for session in active_sessions() {
+        session.abort("shutdown grace period expired");
+    }
+
+    (active.saturating_sub(remaining), remaining)
+}
+
+fn copy_userspace(session: &Session, mut from: &TcpStream, mut to: &TcpStream, direction: Direction) -> io::Result<u64> {
+    let mut buffer = [0u8; 8192];
+    let mut total: u64 = 0;
+
+    loop {
+        let received = match from.read(&mut buffer) {
+            Ok(received) => received,
+            Err(error) if is_timeout(&error) => {
+                session.rearm(from)?;
+
+                continue;
+            },
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error)
+        };
+
+        if received == 0 {
+            return Ok(total);
+        }
+
+        to.write_all(&buffer[..received])?;
+
+        session.relayed(direction, received as u64);
+        session.touch();
+        session.check()?;
+
+        total += received as u64;
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SPLICE_CHUNK: usize = 65536;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct Pipe {
+    read: libc::c_int,
+    write: libc::c_int,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Pipe { read: fds[0], write: fds[1] })
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn splice_fd(from: libc::c_int, to: libc::c_int, len: usize) -> io::Result<usize> {
+    loop {
+        let moved = unsafe {
+            libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, libc::SPLICE_F_MOVE)
+        };
+
+        if moved >= 0 {
+            return Ok(moved as usize);
+        }
+
+        let error = io::Error::last_os_error();
+
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+// Moves bytes socket -> pipe -> socket without copying them through userspace.
+// Falls back to a plain copy when the kernel refuses to splice these descriptors.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy(session: &Session, from: &TcpStream, to: &TcpStream, direction: Direction) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let pipe = match Pipe::new() {
+        Ok(pipe) => pipe,
+        Err(_) => return copy_userspace(session, from, to, direction)
+    };
+
+    let from_fd = from.as_raw_fd();
+    let to_fd = to.as_raw_fd();
+
+    let mut total: u64 = 0;
+
+    loop {
+        let received = match splice_fd(from_fd, pipe.write, SPLICE_CHUNK) {
+            Ok(received) => received,
+            Err(error) if is_timeout(&error) => {
+                session.rearm(from)?;
+
+                continue;
+            },
+            Err(error) if total == 0 && matches!(error.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) => {
+                return copy_userspace(session, from, to, direction);
+            },
+            Err(error) => return Err(error)
+        };
+
+        if received == 0 {
+            return Ok(total);
+        }
+
+        let mut pending = received;
+
+        while pending > 0 {
+            let sent = splice_fd(pipe.read, to_fd, pending)?;
+
+            if sent == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+
+            pending -= sent;
+
+            session.relayed(direction, sent as u64);
+        }
+
+        session.touch();
+        session.check()?;
+
+        total += received as u64;
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn copy(session: &Session, from: &TcpStream, to: &TcpStream, direction: Direction) -> io::Result<u64> {
+    copy_userspace(session, from, to, direction)
+}
@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+pub fn take_reload_request() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+// A second signal while draining means the operator is not willing to wait any longer.
+
+#[cfg(unix)]
+extern "C" fn on_terminate(_signal: libc::c_int) {
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_hangup(_signal: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+pub fn install() {
+    let handlers: [(libc::c_int, extern "C" fn(libc::c_int)); 3] = [
+        (libc::SIGTERM, on_terminate),
+        (libc::SIGINT, on_terminate),
+        (libc::SIGHUP, on_hangup),
+    ];
+
+    for (signal, handler) in handlers {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+
+            action.sa_sigaction = handler as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn on_console_event(event: winapi::shared::minwindef::DWORD) -> winapi::shared::minwindef::BOOL {
+    use winapi::um::wincon::{CTRL_C_EVENT, CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_SHUTDOWN_EVENT};
+
+    match event {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT | CTRL_CLOSE_EVENT | CTRL_SHUTDOWN_EVENT => {
+            if SHUTDOWN.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+
+            1
+        },
+        _ => 0
+    }
+}
+
+#[cfg(windows)]
+pub fn install() {
+    use winapi::um::consoleapi::SetConsoleCtrlHandler;
+
+    unsafe {
+        SetConsoleCtrlHandler(Some(on_console_event), 1);
+    }
+}
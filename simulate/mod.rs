@@ -0,0 +1,437 @@
+use crate::config;
+use crate::core::{self, AuxConfig, StreamPosition};
+use crate::utils::{self, Emitter};
+use crate::{execute_l4_bypasses, execute_l5_bypasses};
+
+use serde_json::{json, Value};
+
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io;
+
+struct Segment {
+    rule: Option<String>,
+    kind: &'static str,
+    offset: u64,
+    len: usize,
+    ttl: Option<u32>,
+    oob: bool,
+    payload: usize,
+}
+
+// Stands in for the upstream socket. A TTL of None is whatever the system default is, since
+// nothing has set one yet. Offsets count payload bytes the server ends up receiving: fakes
+// add nothing and the last byte sent with MSG_OOB is urgent data, not stream data.
+
+struct Recorder {
+    port: u16,
+    ttl: Cell<Option<u32>>,
+    rule: RefCell<Option<String>>,
+    offset: Cell<u64>,
+    segments: RefCell<Vec<Segment>>,
+}
+
+impl Recorder {
+    fn record(&self, kind: &'static str, len: usize, ttl: Option<u32>, oob: bool, payload: usize) {
+        self.segments.borrow_mut().push(Segment {
+            rule: self.rule.borrow().clone(),
+            kind,
+            offset: self.offset.get(),
+            len,
+            ttl,
+            oob,
+            payload,
+        });
+
+        self.offset.set(self.offset.get() + payload as u64);
+    }
+}
+
+impl Emitter for Recorder {
+    fn peer_port(&self) -> Option<u16> {
+        Some(self.port)
+    }
+
+    fn rule(&self, rule: Option<String>) {
+        *self.rule.borrow_mut() = rule;
+    }
+
+    fn set_ttl(&self, ttl: u32) {
+        self.ttl.set(Some(ttl));
+    }
+
+    fn plain(&self, data: &[u8]) -> io::Result<()> {
+        self.record("plain", data.len(), self.ttl.get(), false, data.len());
+
+        Ok(())
+    }
+
+    fn duplicate(&self, data: Vec<u8>, conf: &AuxConfig) -> io::Result<()> {
+        self.record("duplicate", data.len(), Some(1), false, data.len());
+        self.ttl.set(Some(conf.default_ttl.into()));
+
+        Ok(())
+    }
+
+    // Only the first byte of a fake goes out, see utils::send_drop
+
+    fn fake(&self, data: Vec<u8>, conf: &AuxConfig) {
+        self.record("fake", data.len().min(1), Some(conf.fake_packet_ttl.into()), conf.fake_as_oob, 0);
+        self.ttl.set(Some(conf.default_ttl.into()));
+    }
+
+    fn oob(&self, data: Vec<u8>) {
+        self.record("oob", data.len(), self.ttl.get(), true, data.len().saturating_sub(1));
+    }
+
+    fn disable_sack(&self) { }
+}
+
+fn describe(payload: &[u8]) -> &'static str {
+    if payload.first() == Some(&0x16) {
+        "TLS record"
+    } else if utils::message_state(payload) != utils::MessageState::Unknown {
+        "HTTP request"
+    } else {
+        "unrecognized data"
+    }
+}
+
+pub struct Simulation {
+    payload_len: usize,
+    kind: &'static str,
+    sni: Option<(String, u32, u32)>,
+    port: u16,
+    segments: Vec<Segment>,
+}
+
+impl Simulation {
+    fn splits_sni(&self, segment: &Segment) -> bool {
+        let end = segment.offset + segment.payload as u64;
+
+        segment.payload > 0 && self.sni.as_ref().is_some_and(|(_, start, sni_end)| u64::from(*start) < end && end < u64::from(*sni_end))
+    }
+
+    pub fn text(&self) -> String {
+        let mut output = format!("Payload: {} bytes, {}", self.payload_len, self.kind);
+
+        if let Some((ref sni, start, end)) = self.sni {
+            output.push_str(&format!(", SNI {} at {}..{}", sni, start, end));
+        }
+
+        output.push_str(&format!("\nRemote port: {}\n\n", self.port));
+
+        let mut rows: Vec<[String; 7]> = vec![["#", "rule", "kind", "offset", "len", "ttl", "flags"].map(String::from)];
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            let mut flags: Vec<&str> = Vec::new();
+
+            if segment.kind == "fake" {
+                flags.push("fake");
+            }
+
+            if segment.oob {
+                flags.push("oob");
+            }
+
+            if self.splits_sni(segment) {
+                flags.push("splits SNI");
+            }
+
+            rows.push([
+                (index + 1).to_string(),
+                segment.rule.clone().unwrap_or("-".to_string()),
+                segment.kind.to_string(),
+                segment.offset.to_string(),
+                segment.len.to_string(),
+                segment.ttl.map(|ttl| ttl.to_string()).unwrap_or("-".to_string()),
+                flags.join(", ")
+            ]);
+        }
+
+        let widths: Vec<usize> = (0..7)
+            .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+            .collect();
+
+        for row in &rows {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ");
+
+            output.push_str(line.trim_end());
+            output.push('\n');
+        }
+
+        output.push_str(&format!("\nSNI split: {}\n", match self.sni {
+            None => "no SNI",
+            Some(_) if self.segments.iter().any(|segment| self.splits_sni(segment)) => "yes",
+            Some(_) => "no"
+        }));
+
+        output.push_str("TTL - is the system default\n");
+
+        output
+    }
+
+    pub fn json(&self) -> Value {
+        let segments: Vec<Value> = self.segments
+            .iter()
+            .map(|segment| json!({
+                "rule": segment.rule,
+                "kind": segment.kind,
+                "offset": segment.offset,
+                "len": segment.len,
+                "ttl": segment.ttl,
+                "fake": segment.kind == "fake",
+                "oob": segment.oob,
+                "splits_sni": self.splits_sni(segment)
+            }))
+            .collect();
+
+        json!({
+            "payload": {
+                "len": self.payload_len,
+                "kind": self.kind,
+                "sni": self.sni.as_ref().map(|(sni, start, end)| json!({ "host": sni, "start": start, "end": end }))
+            },
+            "port": self.port,
+            "segments": segments,
+            "sni_split": self.segments.iter().any(|segment| self.splits_sni(segment))
+        })
+    }
+}
+
+// Runs the same steps as the client hook on the first write of a connection.
+
+pub fn simulate(payload: &[u8], port: u16, config: &AuxConfig) -> Simulation {
+    let sni_data = utils::parse_sni_index(payload.to_vec());
+
+    let recorder = Recorder {
+        port,
+        ttl: Cell::new(None),
+        rule: RefCell::new(None),
+        offset: Cell::new(0),
+        segments: RefCell::new(Vec::new()),
+    };
+
+    let position = StreamPosition {
+        write: 1,
+        offset: 0,
+        len: payload.len() as u64,
+        ..StreamPosition::default()
+    };
+
+    let mut l5_data = execute_l5_bypasses(payload, config);
+
+    execute_l4_bypasses(&recorder, config, &mut l5_data, &sni_data, &position);
+
+    if !l5_data.is_empty() {
+        recorder.rule(None);
+
+        let _ = recorder.plain(&l5_data);
+    }
+
+    Simulation {
+        payload_len: payload.len(),
+        kind: describe(payload),
+        sni: (sni_data != (0, 0)).then(|| (String::from_utf8_lossy(&payload[sni_data.0 as usize..sni_data.1 as usize]).to_string(), sni_data.0, sni_data.1)),
+        port,
+        segments: recorder.segments.into_inner(),
+    }
+}
+
+// Link layer header length for the link types a capture of a client is likely to use.
+
+fn link_header(link_type: u32, frame: &[u8]) -> Option<usize> {
+    match link_type {
+        0 => Some(4),
+        1 => {
+            let ether_type = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+
+            Some(if ether_type == 0x8100 { 18 } else { 14 })
+        },
+        12 | 101 => Some(0),
+        113 => Some(16),
+        276 => Some(20),
+        _ => None
+    }
+}
+
+// Returns the TCP payload and destination port of an IP packet.
+
+fn tcp_payload(packet: &[u8]) -> Option<(&[u8], u16)> {
+    let (protocol, header) = match packet.first()? >> 4 {
+        4 => (*packet.get(9)?, usize::from(packet[0] & 0x0f) * 4),
+        6 => (*packet.get(6)?, 40),
+        _ => return None
+    };
+
+    if protocol != 6 {
+        return None;
+    }
+
+    let tcp = packet.get(header..)?;
+    let data_offset = usize::from(tcp.get(12)? >> 4) * 4;
+
+    Some((tcp.get(data_offset..)?, u16::from_be_bytes([*tcp.get(2)?, *tcp.get(3)?])))
+}
+
+// The first TCP segment with a payload in a pcap or pcapng file, which for a capture of a
+// client connection is the ClientHello or HTTP request.
+
+pub fn first_payload(capture: &[u8]) -> Result<(Vec<u8>, u16), String> {
+    let u32_at = |offset: usize, little: bool| -> Option<u32> {
+        let bytes: [u8; 4] = capture.get(offset..offset + 4)?.try_into().ok()?;
+
+        Some(if little { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    };
+
+    let mut frames: Vec<(u32, &[u8])> = Vec::new();
+
+    match u32_at(0, true) {
+        Some(0xa1b2c3d4 | 0xa1b23c4d | 0xd4c3b2a1 | 0x4d3cb2a1) => {
+            let little = matches!(u32_at(0, true), Some(0xa1b2c3d4 | 0xa1b23c4d));
+            let link_type = u32_at(20, little).ok_or("truncated pcap header")?;
+            let mut offset = 24;
+
+            while let Some(length) = u32_at(offset + 8, little) {
+                let Some(frame) = capture.get(offset + 16..offset + 16 + length as usize) else {
+                    break;
+                };
+
+                frames.push((link_type, frame));
+                offset += 16 + length as usize;
+            }
+        },
+        Some(0x0a0d0d0a) => {
+            let little = u32_at(8, true) == Some(0x1a2b3c4d);
+            let mut link_types: Vec<u32> = Vec::new();
+            let mut offset = 0;
+
+            while let (Some(kind), Some(length)) = (u32_at(offset, little), u32_at(offset + 4, little)) {
+                if length < 12 {
+                    break;
+                }
+
+                let body = capture.get(offset + 8..offset + length as usize - 4).ok_or("truncated pcapng block")?;
+
+                match kind {
+                    0x0a0d0d0a => link_types.clear(),
+                    1 if body.len() >= 2 => link_types.push(u32::from(if little { u16::from_le_bytes([body[0], body[1]]) } else { u16::from_be_bytes([body[0], body[1]]) })),
+                    6 if body.len() >= 20 => {
+                        let field = |at: usize| -> u32 {
+                            let bytes = [body[at], body[at + 1], body[at + 2], body[at + 3]];
+
+                            if little { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+                        };
+
+                        let (interface, captured) = (field(0) as usize, field(12) as usize);
+
+                        if let (Some(link_type), Some(frame)) = (link_types.get(interface), body.get(20..20 + captured)) {
+                            frames.push((*link_type, frame));
+                        }
+                    },
+                    _ => { }
+                }
+
+                offset += length as usize;
+            }
+        },
+        _ => return Err("not a pcap or pcapng file".to_string())
+    }
+
+    frames
+        .into_iter()
+        .filter_map(|(link_type, frame)| frame.get(link_header(link_type, frame)?..))
+        .filter_map(tcp_payload)
+        .find(|(payload, _)| !payload.is_empty())
+        .map(|(payload, port)| (payload.to_vec(), port))
+        .ok_or("no TCP segment with a payload in the capture".to_string())
+}
+
+// The payload to simulate and, when it came from a capture, the port it was sent to.
+
+type Input = Result<(Vec<u8>, Option<u16>), String>;
+
+const SIMULATE_USAGE: &str = "Usage: waterfall simulate (--hex <hex> | --file <path> | --pcap <path>) [--port <port>] [--json] [FLAGS]
+
+Runs the configured strategies over a ClientHello or HTTP request without opening any socket
+and prints every segment that would be sent. The port defaults to the one in the capture, or
+to 443 for TLS and 80 otherwise.";
+
+pub fn simulate_command(args: &[String]) -> io::Result<()> {
+    let mut payload: Option<Input> = None;
+    let mut port: Option<u16> = None;
+    let mut json_output = false;
+    let mut flags: Vec<String> = Vec::new();
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hex" | "--file" | "--pcap" => {
+                let Some(value) = args.next() else {
+                    payload = Some(Err(format!("{} expects a value", arg)));
+
+                    continue;
+                };
+
+                payload = Some(match arg.as_str() {
+                    "--hex" => config::parse_hex(&value.split_whitespace().collect::<String>()).map(|data| (data, None)),
+                    "--file" => fs::read(value).map(|data| (data, None)).map_err(|error| format!("{}: {}", value, error)),
+                    _ => fs::read(value)
+                        .map_err(|error| format!("{}: {}", value, error))
+                        .and_then(|capture| first_payload(&capture).map_err(|error| format!("{}: {}", value, error)))
+                        .map(|(data, port)| (data, Some(port)))
+                });
+            },
+            "--port" => match args.next().and_then(|port| port.parse::<u16>().ok()) {
+                Some(value) => port = Some(value),
+                None => {
+                    eprintln!("error: --port expects a port number");
+
+                    std::process::exit(2);
+                }
+            },
+            "--json" => json_output = true,
+            _ => flags.push(arg.clone())
+        }
+    }
+
+    let (payload, captured_port) = match payload {
+        Some(Ok(payload)) => payload,
+        Some(Err(message)) => {
+            eprintln!("error: {}", message);
+
+            std::process::exit(2);
+        },
+        None => {
+            eprintln!("{}", SIMULATE_USAGE);
+
+            std::process::exit(2);
+        }
+    };
+
+    let config = core::parse_args_from(flags).unwrap_or_else(|errors| {
+        config::report(&errors);
+
+        std::process::exit(2);
+    });
+
+    let port = port
+        .or(captured_port)
+        .unwrap_or(if payload.first() == Some(&0x16) { 443 } else { 80 });
+
+    let simulation = simulate(&payload, port, &config);
+
+    if json_output {
+        println!("{}", serde_json::to_string_pretty(&simulation.json()).map_err(io::Error::other)?);
+    } else {
+        print!("{}", simulation.text());
+    }
+
+    Ok(())
+}
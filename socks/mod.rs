@@ -1,52 +1,419 @@
 use crate::IpParser;
 use crate::core;
+use crate::core::{AuxConfig, DesyncTrigger, StreamPosition};
+use crate::event;
+use crate::fallback::Handoff;
+use crate::learn;
+use crate::metrics::{self, Failure};
+use crate::outcome;
+use crate::relay;
+use crate::relay::{Direction, Session};
+use crate::trace;
+use crate::utils::{self, MessageState, RequestError};
 
 use std::{
-    io::{Read, Write, BufReader},
+    io::{Read, Write, BufRead, BufReader},
     net::{TcpStream, SocketAddr},
-    thread
+    sync::Arc,
+    thread,
+    time
 };
 use std::io;
 
+// The client's bytes up to and including the first hooked write, kept so that a blocked
+// connection can be replayed on a new upstream socket.
+
+#[derive(Default)]
+struct Replay {
+    plain: Vec<u8>,
+    hooked: Option<(Vec<u8>, StreamPosition)>,
+}
+
 struct BufReaderHook<R, F> {
     inner: BufReader<R>,
     hook: F,
     socket: TcpStream,
-    hops: u64,
-    max_hops: u64,
+    packet_hop: u64,
+    triggers: Vec<DesyncTrigger>,
+    position: StreamPosition,
+    started: time::Instant,
+    reassembly_timeout: time::Duration,
+    reassembly_max_size: usize,
+    pending: Vec<u8>,
+    received: u64,
+    session: Arc<Session>,
+    replay: Option<Replay>,
+    handoff: Option<Arc<Handoff>>,
+    config: Arc<AuxConfig>,
 }
 
-impl<R: Read, F> Read for BufReaderHook<R, F>
+impl<R, F> BufReaderHook<R, F> {
+    fn hook_pending(&self) -> bool {
+        self.triggers
+            .iter()
+            .any(|trigger| !trigger.exhausted(self.packet_hop, &self.position))
+    }
+
+    fn advance(&mut self, size: usize) {
+        self.position.write += 1;
+        self.position.offset += self.position.len;
+        self.position.len = size as u64;
+        self.position.last_elapsed = self.position.elapsed;
+        self.position.elapsed = self.started.elapsed();
+    }
+
+    fn hook_fires(&self) -> bool {
+        self.triggers
+            .iter()
+            .any(|trigger| trigger.fires(self.packet_hop, &self.position))
+    }
+
+    // Anything larger than a reassembled first message isn't worth replaying.
+
+    fn keep(&mut self, data: &[u8]) {
+        if let Some(replay) = self.replay.as_mut().filter(|replay| replay.hooked.is_none()) {
+            if replay.plain.len() + data.len() > self.reassembly_max_size {
+                self.replay = None;
+            } else {
+                replay.plain.extend_from_slice(data);
+            }
+        }
+    }
+
+    fn replay_ready(&self) -> bool {
+        self.replay.as_ref().is_some_and(|replay| replay.hooked.is_some())
+    }
+
+    // The host is known from the first hooked write on, so that's where a set learned for it
+    // takes over from the rules' own strategies.
+
+    fn start_learned(&mut self, data: &[u8]) {
+        let Some(ref handoff) = self.handoff else {
+            return;
+        };
+
+        let sni = utils::parse_sni_index(data.to_vec());
+        let sni = (sni != (0, 0)).then(|| String::from_utf8_lossy(&data[sni.0 as usize..sni.1 as usize]).to_string());
+
+        let key = learn::key(sni.as_deref(), &self.session.info.lock().unwrap().target);
+
+        let Some(set) = learn::lookup(&key, &self.config).filter(|set| *set != 0 && handoff.start(*set)) else {
+            return;
+        };
+
+        event!(Debug, "learned set applied", key = key, set = set);
+
+        self.config = Arc::new(AuxConfig { attempt: set, ..(*self.config).clone() });
+        self.session.info.lock().unwrap().set = set;
+    }
+}
+
+impl<F> BufReaderHook<TcpStream, F> {
+    // Keeps reading until a whole TLS record or HTTP header block is buffered, so that
+    // the hook sees the complete ClientHello even when the client split it across writes.
+
+    fn reassemble(&mut self, buf: &mut [u8], mut size: usize) -> io::Result<usize> {
+        let limit = buf.len().min(self.reassembly_max_size);
+        let deadline = time::Instant::now() + self.reassembly_timeout;
+        let read_timeout = self.inner.get_ref().read_timeout()?;
+
+        while size < limit && utils::message_state(&buf[..size]) == MessageState::Partial {
+            let now = time::Instant::now();
+
+            if now >= deadline {
+                break;
+            }
+
+            self.inner.get_ref().set_read_timeout(Some(deadline - now))?;
+
+            match self.inner.read(&mut buf[size..limit]) {
+                Ok(0) => break,
+                Ok(received) => {
+                    size += received;
+                    self.received += received as u64;
+                },
+                Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    self.inner.get_ref().set_read_timeout(read_timeout)?;
+
+                    return Err(error);
+                }
+            }
+        }
+
+        self.inner.get_ref().set_read_timeout(read_timeout)?;
+
+        Ok(size)
+    }
+}
+
+// What a read from the client came to.
+
+enum Hooked {
+    Data(usize),
+    // The strategies sent the hooked write themselves and left nothing to relay
+    Sent
+}
+
+impl<F> BufReaderHook<TcpStream, F>
 where
-    F: Fn(&TcpStream, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    F: Fn(&TcpStream, &[u8], &StreamPosition, &AuxConfig) -> Vec<u8> + Send + Sync + 'static,
 {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let size = self.inner.read(buf)?;
-        if size == 0 || self.hops < self.max_hops {
-            return Ok(size);
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<Hooked> {
+        loop {
+            if !self.pending.is_empty() {
+                let size = self.pending.len().min(buf.len());
+
+                buf[..size].copy_from_slice(&self.pending[..size]);
+                self.pending.drain(..size);
+
+                return Ok(Hooked::Data(size));
+            }
+
+            let mut size = self.inner.read(buf)?;
+            self.received += size as u64;
+
+            if size == 0 || !self.hook_pending() {
+                self.keep(&buf[..size]);
+
+                return Ok(Hooked::Data(size));
+            }
+
+            self.advance(size);
+
+            if !self.hook_fires() {
+                self.keep(&buf[..size]);
+
+                return Ok(Hooked::Data(size));
+            }
+
+            size = self.reassemble(buf, size)?;
+
+            self.position.len = size as u64;
+
+            if let Some(replay) = self.replay.as_mut().filter(|replay| replay.hooked.is_none()) {
+                replay.hooked = Some((buf[..size].to_vec(), self.position.clone()));
+
+                self.start_learned(&buf[..size]);
+            }
+
+            // The hook may grow, shrink or entirely consume the payload; whatever it returns
+            // is handed out over as many reads as needed.
+
+            // Marked before the hook runs, since the server may answer its first segment
+            // before the hook returns.
+
+            self.session.desynced();
+
+            self.pending = (self.hook)(&self.socket, &buf[..size], &self.position, &self.config);
+
+            // When the strategies sent everything themselves, control goes back to the relay so
+            // it can wait for the first response instead of blocking on the next client read.
+
+            if self.pending.is_empty() && self.replay_ready() {
+                return Ok(Hooked::Sent);
+            }
+        }
+    }
+}
+
+fn replay<F>(processor: &mut BufReaderHook<TcpStream, F>, replay: &Replay, set: usize, addr: SocketAddr, session: &Session) -> io::Result<(TcpStream, TcpStream)>
+where
+    F: Fn(&TcpStream, &[u8], &StreamPosition, &AuxConfig) -> Vec<u8> + Send + Sync + 'static,
+{
+    let config = Arc::new(AuxConfig { attempt: set, ..(*processor.config).clone() });
+
+    let mut server = core::connect_socket(addr, &config)?;
+    let reader = server.try_clone()?;
+
+    session.retry(&server)?;
+    session.desynced();
+
+    session.info.lock().unwrap().set = set;
+
+    // Once connected, a failing write is for the first response to classify, like on the
+    // first attempt.
+
+    let _ = server.write_all(&replay.plain);
+
+    if let Some((ref data, ref position)) = replay.hooked {
+        let rest = (processor.hook)(&server, data, position, &config);
+
+        let _ = server.write_all(&rest);
+    }
+
+    processor.socket = server.try_clone()?;
+    processor.config = config;
+
+    Ok((server, reader))
+}
+
+// Holds the upstream side after the first hooked write until the first response is settled,
+// and replays the kept bytes on a new connection for every fallback set that gets tried.
+// Returns whether `socket` was replaced, which also drops whatever was pending for the old one.
+
+fn settle<F>(processor: &mut BufReaderHook<TcpStream, F>, socket: &mut TcpStream, session: &Session) -> bool
+where
+    F: Fn(&TcpStream, &[u8], &StreamPosition, &AuxConfig) -> Vec<u8> + Send + Sync + 'static,
+{
+    let Some(handoff) = processor.handoff.clone() else {
+        return false;
+    };
+
+    let Some(kept) = processor.replay.take_if(|replay| replay.hooked.is_some()) else {
+        return false;
+    };
+
+    let mut replaced = false;
+
+    while let Some(set) = handoff.wait() {
+        match replay(processor, &kept, set, handoff.addr, session) {
+            Ok((server, reader)) => {
+                handoff.replaced(Some(reader));
+
+                *socket = server;
+
+                processor.pending.clear();
+
+                replaced = true;
+            },
+            Err(error) => {
+                event!(Warn, "fallback failed", set = set, error = error.to_string());
+
+                handoff.replaced(None);
+            }
+        }
+    }
+
+    replaced
+}
+
+fn relay_hooked<F>(processor: &mut BufReaderHook<TcpStream, F>, socket: &mut TcpStream, session: &Session) -> io::Result<u64>
+where
+    F: Fn(&TcpStream, &[u8], &StreamPosition, &AuxConfig) -> Vec<u8> + Send + Sync + 'static,
+{
+    let mut buffer = vec![0u8; processor.reassembly_max_size.max(8192)];
+    let mut total: u64 = 0;
+
+    session.rearm(processor.inner.get_ref())?;
+
+    while processor.hook_pending() {
+        let size = match processor.read(&mut buffer) {
+            Ok(Hooked::Data(size)) => size,
+            Ok(Hooked::Sent) => {
+                settle(processor, socket, session);
+
+                continue;
+            },
+            Err(error) if relay::is_timeout(&error) => {
+                session.rearm(processor.inner.get_ref())?;
+
+                continue;
+            },
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error)
+        };
+
+        // Counted as read from the client, since the hook may send part of it on its own
+
+        session.relayed(Direction::Upstream, std::mem::take(&mut processor.received));
+
+        if size == 0 {
+            return Ok(total);
+        }
+
+        // A write failing right after the hooked one may just be the DPI resetting the
+        // connection, which the first response settles.
+
+        match socket.write_all(&buffer[..size]) {
+            Err(error) if !settle(processor, socket, session) => return Err(error),
+            Ok(()) if processor.pending.is_empty() => {
+                settle(processor, socket, session);
+            },
+            _ => {}
         }
 
-        let processed = (self.hook)(&self.socket, &buf[..size]);
-            
-        buf[..processed.len()].copy_from_slice(&processed);
+        session.touch();
+        session.check()?;
+
+        total += size as u64;
+    }
 
-        self.hops += 1;
+    if !processor.pending.is_empty() {
+        match socket.write_all(&processor.pending) {
+            Err(error) if !settle(processor, socket, session) => return Err(error),
+            _ => total += processor.pending.len() as u64
+        }
 
-        Ok(processed.len())
+        processor.pending.clear();
     }
+
+    settle(processor, socket, session);
+
+    trace::finish();
+
+    let buffered = processor.inner.buffer().to_vec();
+
+    if !buffered.is_empty() {
+        socket.write_all(&buffered)?;
+
+        session.relayed(Direction::Upstream, buffered.len() as u64);
+
+        processor.inner.consume(buffered.len());
+
+        total += buffered.len() as u64;
+    }
+
+    Ok(total + session.copy(processor.inner.get_ref(), socket, Direction::Upstream)?)
 }
 
-pub fn socks5_proxy(proxy_client: &mut TcpStream, client_hook: impl Fn(&TcpStream, &[u8]) -> Vec<u8> + std::marker::Sync + std::marker::Send + 'static) {
-    proxy_client
+pub fn socks5_proxy(proxy_client: &mut TcpStream, id: u64, config: Arc<AuxConfig>, client_hook: impl Fn(&TcpStream, &[u8], &StreamPosition, &AuxConfig) -> Vec<u8> + std::marker::Sync + std::marker::Send + 'static) {
+    let handshake = proxy_client
         .try_clone()
         .and_then(|mut client| {
-            let mut buffer = [0; 64];
+            client.set_read_timeout(Some(config.handshake_timeout))?;
+            client.set_write_timeout(Some(config.handshake_timeout))?;
+
+            // Room for the longest request: four header bytes, a 255 byte name with its length,
+            // and the port.
+
+            let mut buffer = [0; 262];
+            let mut greeting = [0; 2];
+
+            client.read_exact(&mut greeting)?;
+
+            let mut methods = vec![0; greeting[1] as usize];
+
+            client.read_exact(&mut methods)?;
+
+            if greeting[0] != 5 || !methods.contains(&0) {
+                let _ = client.write_all(&[5, 0xff]);
+
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "client offers no SOCKS5 method without authentication"));
+            }
 
-            client.read(&mut buffer)?;
             client.write_all(&[5, 0])?;
-            client.read(&mut buffer)?;
-            
-            let parsed_data: IpParser = IpParser::parse(&buffer);
+
+            // The request may come in several segments, or already be buffered behind the greeting.
+
+            let mut len = 0;
+
+            while len < buffer.len() && utils::request(&buffer[..len]) == Err(RequestError::Truncated) {
+                match client.read(&mut buffer[len..])? {
+                    0 => break,
+                    received => len += received
+                }
+            }
+
+            let parsed_data: IpParser = match IpParser::parse(&buffer[..len]) {
+                Ok(parsed_data) => parsed_data,
+                Err(error) => {
+                    let _ = client.write_all(&[5, error.reply(), 0, 1, 0, 0, 0, 0, 0, 0]);
+
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                }
+            };
 
             let mut packet = vec![5, 0, 0, parsed_data.dest_addr_type];
 
@@ -55,10 +422,6 @@ pub fn socks5_proxy(proxy_client: &mut TcpStream, client_hook: impl Fn(&TcpStrea
             packet.extend_from_slice(&parsed_data.host_unprocessed);
             packet.extend_from_slice(&parsed_data.port.to_be_bytes());
 
-            if parsed_data.is_udp {
-                todo!();
-            }
-
             match parsed_data.host_raw.len() {
                 4 => {
                     let ip_bytes: [u8; 4] = unsafe { *(parsed_data.host_raw.as_ptr() as *const [u8; 4]) };
@@ -72,7 +435,23 @@ pub fn socks5_proxy(proxy_client: &mut TcpStream, client_hook: impl Fn(&TcpStrea
                 },
                 _ => None
             }.and_then(|sock_addr| {
-                let server_socket = core::connect_socket(sock_addr);
+                let target = match parsed_data.dest_addr_type {
+                    3 => String::from_utf8_lossy(parsed_data.host_unprocessed).to_string(),
+                    _ => sock_addr.ip().to_string()
+                };
+
+                event!(Info, "connect", target = target, port = parsed_data.port, resolved = sock_addr.ip().to_string());
+
+                if sock_addr.ip().is_unspecified() {
+                    event!(Warn, "resolve failed", target = target);
+
+                    metrics::failed(Failure::Resolve);
+                }
+
+                let connect_started = time::Instant::now();
+                let server_socket = core::connect_socket(sock_addr, &config);
+
+                metrics::connected(connect_started.elapsed());
 
                 match server_socket {
                     Ok(mut socket) => {
@@ -80,36 +459,238 @@ pub fn socks5_proxy(proxy_client: &mut TcpStream, client_hook: impl Fn(&TcpStrea
 
                         drop(packet);
 
+                        client.set_read_timeout(None).ok()?;
+
                         socket.set_nodelay(true).ok()?;
-                        
+
                         let client_reader = client.try_clone().ok()?;
                         let socket_reader = socket.try_clone().ok()?;
 
+                        let session = Session::open(id, &client, &socket, &config).ok()?;
+                        let server_session = session.clone();
+                        let server_config = config.clone();
+                        let handoff = Handoff::new(sock_addr, &config);
+                        let server_handoff = handoff.clone();
+
+                        session.info.lock().unwrap().target = format!("{}:{}", target, parsed_data.port);
+
+                        let mut triggers: Vec<DesyncTrigger> = config.strategies
+                            .iter()
+                            .filter(|strategy| strategy.active)
+                            .map(|strategy| strategy.data.trigger.clone())
+                            .collect();
+
+                        if triggers.is_empty() {
+                            triggers.push(DesyncTrigger::default());
+                        }
+
                         let mut processor = BufReaderHook {
                             inner: BufReader::new(client_reader),
                             hook: client_hook,
                             socket: socket.try_clone().ok()?,
-                            hops: 0,
-                            max_hops: core::parse_args().packet_hop
+                            packet_hop: config.packet_hop,
+                            triggers,
+                            position: StreamPosition::default(),
+                            started: time::Instant::now(),
+                            reassembly_timeout: config.reassembly_timeout,
+                            reassembly_max_size: config.reassembly_max_size,
+                            pending: Vec::new(),
+                            received: 0,
+                            session: session.clone(),
+                            replay: handoff.as_ref().map(|_| Replay::default()),
+                            handoff: handoff.clone(),
+                            config: config.clone()
                         };
 
                         thread::spawn(move || {
-                            drop(io::copy(
-                                &mut BufReader::new(socket_reader), 
-                                &mut client
-                            ));
+                            server_session.attach();
+
+                            let result = outcome::first_response(&server_session, socket_reader, &client, &server_config, server_handoff.as_deref())
+                                .and_then(|(first, socket_reader)| Ok(first + server_session.copy(&socket_reader, &client, Direction::Downstream)?));
+
+                            if let Some(handoff) = server_handoff {
+                                handoff.finish();
+                            }
+
+                            drop(server_session.finish(&client, result));
                         });
 
                         thread::spawn(move || {
-                            drop(io::copy(&mut processor, &mut socket));
+                            session.attach();
+
+                            trace::begin(id, &socket, &processor.config);
+
+                            let result = relay_hooked(&mut processor, &mut socket, &session);
+
+                            trace::finish();
+
+                            drop(session.finish(&socket, result));
                         });
                     },
-                    Err(_) => { }
+                    Err(error) => {
+                        event!(Warn, "connect failed", target = target, error = error.to_string());
+
+                        if !sock_addr.ip().is_unspecified() {
+                            metrics::failed(Failure::Connect);
+                        }
+                    }
                 }
 
                 Some(())
             }).unwrap_or(());
             Ok(())
+        });
+
+    if let Err(error) = handshake {
+        event!(Warn, "handshake failed", error = error.to_string());
+
+        metrics::failed(Failure::Handshake);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls;
+
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    type Seen = Arc<Mutex<Vec<Vec<u8>>>>;
+    type Recorder = Box<dyn Fn(&TcpStream, &[u8], &StreamPosition, &AuxConfig) -> Vec<u8> + Send + Sync>;
+
+    // A hook over the accepted end of a loopback connection that records what it was given and
+    // passes it on unchanged, and the client end to write to.
+
+    fn hooked(id: u64, max_size: usize, timeout: time::Duration) -> (TcpStream, BufReaderHook<TcpStream, Recorder>, Seen) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let server = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let config = Arc::new(AuxConfig::default());
+        let session = Session::open(id, &accepted, &server, &config).unwrap();
+        let seen = Seen::default();
+        let recorded = seen.clone();
+
+        let hook: Recorder = Box::new(move |_, data, _, _| {
+            recorded.lock().unwrap().push(data.to_vec());
+
+            data.to_vec()
+        });
+
+        let processor = BufReaderHook {
+            inner: BufReader::new(accepted),
+            hook,
+            socket: server,
+            packet_hop: 1,
+            triggers: vec![DesyncTrigger::default()],
+            position: StreamPosition::default(),
+            started: time::Instant::now(),
+            reassembly_timeout: timeout,
+            reassembly_max_size: max_size,
+            pending: Vec::new(),
+            received: 0,
+            session,
+            replay: None,
+            handoff: None,
+            config
+        };
+
+        (client, processor, seen)
+    }
+
+    // Writes `first`, and `rest` once the hook is already waiting for more.
+
+    fn send_split(mut client: TcpStream, first: &[u8], rest: &[u8]) -> thread::JoinHandle<TcpStream> {
+        client.write_all(first).unwrap();
+
+        let rest = rest.to_vec();
+
+        thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(100));
+
+            client.write_all(&rest).unwrap();
+            client
         })
-        .unwrap_or(());
+    }
+
+    fn first_read(processor: &mut BufReaderHook<TcpStream, Recorder>) -> usize {
+        let mut buffer = vec![0u8; 8192];
+
+        match processor.read(&mut buffer) {
+            Ok(Hooked::Data(size)) => size,
+            Ok(Hooked::Sent) => panic!("nothing left to relay"),
+            Err(error) => panic!("{}", error)
+        }
+    }
+
+    #[test]
+    fn hello_split_across_reads_is_hooked_whole() {
+        let hello = tls::client_hello(&[0x5a; 32], &[0xa5; 32], &[0x1301], &[tls::server_name("example.com")]);
+        let (client, mut processor, seen) = hooked(u64::MAX, 16384, time::Duration::from_secs(5));
+        let writer = send_split(client, &hello[..20], &hello[20..]);
+
+        assert_eq!(first_read(&mut processor), hello.len());
+        assert_eq!(*seen.lock().unwrap(), vec![hello]);
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn http_headers_split_across_reads_are_hooked_whole() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (client, mut processor, seen) = hooked(u64::MAX - 1, 16384, time::Duration::from_secs(5));
+        let writer = send_split(client, &request[..22], &request[22..]);
+
+        assert_eq!(first_read(&mut processor), request.len());
+        assert_eq!(*seen.lock().unwrap(), vec![request.to_vec()]);
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn reassembly_stops_at_the_size_cap() {
+        let request = [&b"GET / HTTP/1.1\r\nHost: "[..], &[b'a'; 200]].concat();
+        let (client, mut processor, seen) = hooked(u64::MAX - 2, 64, time::Duration::from_secs(5));
+        let writer = send_split(client, &request[..40], &request[40..]);
+        let started = time::Instant::now();
+
+        assert_eq!(first_read(&mut processor), 64);
+        assert_eq!(*seen.lock().unwrap(), vec![request[..64].to_vec()]);
+        assert!(started.elapsed() < time::Duration::from_secs(2));
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn record_longer_than_the_cap_is_hooked_up_to_the_cap() {
+        let record = [&[0x16, 0x03, 0x01, 0x03, 0xe8][..], &[0x01; 1000]].concat();
+        let (client, mut processor, seen) = hooked(u64::MAX - 3, 100, time::Duration::from_secs(5));
+        let writer = send_split(client, &record[..50], &record[50..]);
+        let started = time::Instant::now();
+
+        assert_eq!(first_read(&mut processor), 100);
+        assert_eq!(*seen.lock().unwrap(), vec![record[..100].to_vec()]);
+        assert!(started.elapsed() < time::Duration::from_secs(2));
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn reassembly_gives_up_after_the_timeout() {
+        let hello = tls::client_hello(&[0x5a; 32], &[0xa5; 32], &[0x1301], &[tls::server_name("example.com")]);
+        let (mut client, mut processor, seen) = hooked(u64::MAX - 4, 16384, time::Duration::from_millis(200));
+        let started = time::Instant::now();
+
+        client.write_all(&hello[..20]).unwrap();
+
+        assert_eq!(first_read(&mut processor), 20);
+        assert_eq!(*seen.lock().unwrap(), vec![hello[..20].to_vec()]);
+        assert!(started.elapsed() >= time::Duration::from_millis(200));
+
+        // The relay's own read timeout is back in place afterwards.
+
+        assert_eq!(processor.inner.get_ref().read_timeout().unwrap(), None);
+    }
 }
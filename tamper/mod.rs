@@ -1,8 +1,6 @@
 use crate::core;
 
-pub fn edit_http(mut data: Vec<u8>) -> Vec<u8> {
-  let conf = core::parse_args();
-
+pub fn edit_http(mut data: Vec<u8>, conf: &core::AuxConfig) -> Vec<u8> {
   for iter in 0..data.len() {
     // Scan for HTTP
 
@@ -17,7 +15,7 @@ pub fn edit_http(mut data: Vec<u8>) -> Vec<u8> {
         data[iter + 3] = 84;
       }
 
-      if conf.http_host_rmspace && data[iter + 5] == 32 {
+      if conf.http_host_rmspace && data.get(iter + 5) == Some(&32) {
         data.remove(iter + 5);
       }
 
@@ -26,9 +24,9 @@ pub fn edit_http(mut data: Vec<u8>) -> Vec<u8> {
       }
 
       if conf.http_domain_cmix {
-        let b = std::str::from_utf8(&[data[iter + 6]]).expect("HOST detected but domain is wrong").to_uppercase();
-
-        data[iter + 6] = b.as_bytes()[0];
+        if let Some(byte) = data.get_mut(iter + 6) {
+          *byte = byte.to_ascii_uppercase();
+        }
       }
     }
   }
@@ -47,18 +45,35 @@ pub fn as_record(data: Vec<u8>) -> Vec<u8> {
   record
 }
 
-pub fn edit_tls(mut data: Vec<u8>, index: usize) -> Vec<u8> {
-  if data[0] == 0x16 && data[1] == 0x03 && data[2] == 0x01 {
-    let payload = data.split_off(5);
-    let (first_part, second_part) = payload.split_at(index);
-
-    let record1 = as_record(first_part.to_vec());
-    let record2 = as_record(second_part.to_vec());
-        
-    let mut result = record1;
-    result.extend(record2);
-        
-    return result;
+// `index` counts handshake bytes from the start of the first record's payload and cuts the
+// record that holds it, so a later position still lands on the same byte once an earlier one
+// has split the handshake into records of its own.
+
+pub fn edit_tls(data: Vec<u8>, index: usize) -> Vec<u8> {
+  if data.len() > 5 && data[0] == 0x16 && data[1] == 0x03 && data[2] == 0x01 {
+    let mut result: Vec<u8> = Vec::new();
+    let mut at: usize = 0;
+    let mut offset: usize = 0;
+
+    while at + 5 <= data.len() && data[at] == 0x16 {
+      let end: usize = (at + 5 + u16::from_be_bytes([data[at + 3], data[at + 4]]) as usize).min(data.len());
+      let payload = &data[at + 5..end];
+
+      if index > offset && index < offset + payload.len() {
+        let (first_part, second_part) = payload.split_at(index - offset);
+
+        result.extend(as_record(first_part.to_vec()));
+        result.extend(as_record(second_part.to_vec()));
+        result.extend_from_slice(&data[end..]);
+
+        return result;
+      }
+
+      result.extend_from_slice(&data[at..end]);
+
+      offset += payload.len();
+      at = end;
+    }
   }
 
   data
@@ -0,0 +1,126 @@
+// Shared by the integration tests: waterfall as a child process, the SOCKS5 CONNECT that goes
+// through it, and the ClientHello builder `waterfall probe` uses. Every test crate uses a
+// different part of it.
+
+#![allow(dead_code)]
+
+#[path = "../../tls/mod.rs"]
+pub mod tls;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Waterfall {
+    child: Child,
+    pub port: u16,
+}
+
+impl Waterfall {
+    // Starts waterfall on a free loopback port and waits until it accepts connections.
+
+    pub fn start(args: &[&str]) -> Waterfall {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        Waterfall::start_with(&[], port, args, move || TcpStream::connect(("127.0.0.1", port)).is_ok())
+    }
+
+    // Same, with the command prefixed by `wrapper` (e.g. `ip netns exec <name>`), and `ready`
+    // telling when it accepts connections.
+
+    pub fn start_with(wrapper: &[&str], port: u16, args: &[&str], ready: impl Fn() -> bool) -> Waterfall {
+        let mut command = match wrapper {
+            [program, wrapper_args @ ..] => {
+                let mut command = Command::new(program);
+
+                command.args(wrapper_args).arg(env!("CARGO_BIN_EXE_waterfall"));
+                command
+            },
+            [] => Command::new(env!("CARGO_BIN_EXE_waterfall"))
+        };
+
+        let child = command
+            .args(["--bind_host", "127.0.0.1", "--bind_port", &port.to_string(), "--so_opt_cutoff", "100000", "--log_level", "error"])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let waterfall = Waterfall { child, port };
+        let started = Instant::now();
+
+        while !ready() {
+            assert!(started.elapsed() < Duration::from_secs(5), "waterfall didn't start");
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        waterfall
+    }
+
+    pub fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        socks_connect(self.port, host, port)
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    // Waits up to `timeout` for the process to exit on its own.
+
+    pub fn exited(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let started = Instant::now();
+
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return Some(status);
+            }
+
+            if started.elapsed() >= timeout {
+                return None;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Waterfall {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A connection through the SOCKS5 proxy on loopback `proxy` to `host:port`, once waterfall
+// has answered the request.
+
+pub fn socks_connect(proxy: u16, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut socks = TcpStream::connect(("127.0.0.1", proxy))?;
+    let mut reply = [0u8; 262];
+    let request = [&[5, 1, 0, 3, host.len() as u8][..], host.as_bytes(), &port.to_be_bytes()].concat();
+
+    socks.write_all(&[5, 1, 0])?;
+    socks.read_exact(&mut reply[..2])?;
+    socks.write_all(&request)?;
+    socks.read_exact(&mut reply[..request.len()])?;
+
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!("SOCKS reply {}", reply[1])));
+    }
+
+    Ok(socks)
+}
+
+pub fn client_hello(sni: &str) -> Vec<u8> {
+    let extensions = [
+        tls::server_name(sni),
+        tls::extension(0x000a, &tls::with_length(2, &tls::list(&[0x001d, 0x0017]))),
+        tls::extension(0x002b, &[0x02, 0x03, 0x04]),
+    ];
+
+    tls::client_hello(&[0x5a; 32], &[0xa5; 32], &[0x1301, 0x1302, 0xc02b], &extensions)
+}
@@ -0,0 +1,700 @@
+// A lab of four network namespaces with a DPI box in the path:
+//
+//   client (waterfall) -- dpi (bridge) -- router -- server
+//
+// The DPI namespace bridges frames between its two interfaces in userspace and decides on
+// every TCP segment from the client on the way; the router is an ordinary kernel router, so
+// segments sent with TTL 1 are seen by the DPI and really expire before the server. Needs
+// root and `ip netns`; `Lab::start` returns None otherwise and the tests pass vacuously.
+
+#![allow(dead_code)]
+
+use crate::common::{client_hello, socks_connect, Waterfall};
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const SERVER: &str = "10.0.2.2";
+
+// Sends back everything it read once the client closed its side.
+
+pub const ECHO: u16 = 7;
+
+const SOCKS_PORT: u16 = 1080;
+
+// The lowest TTL a segment can pass the DPI with and still get past the router to the server.
+
+const SERVER_TTL: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inspect {
+    // Decides on the first segment with data and lets the rest of the connection through
+    FirstSegment,
+    // Follows the stream in order and decides once it has enough of it; segments beyond a
+    // gap are not buffered, like on boxes that keep no out-of-order queue
+    Reassemble,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parsing {
+    // Only a ClientHello complete in the first TLS record, and a `Host: ` header spelled
+    // exactly like that
+    Strict,
+    // The blocked names anywhere in the handshake records joined together, or in the HTTP
+    // headers regardless of case
+    Loose,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dpi {
+    pub inspect: Inspect,
+    pub honour_ttl: bool,
+    pub parsing: Parsing,
+    pub blocked: Vec<String>,
+}
+
+impl Dpi {
+    pub fn new(inspect: Inspect, parsing: Parsing) -> Dpi {
+        Dpi { inspect, honour_ttl: false, parsing, blocked: vec!["blocked.test".to_string()] }
+    }
+
+    pub fn honour_ttl(mut self) -> Dpi {
+        self.honour_ttl = true;
+        self
+    }
+
+    fn blocks(&self, name: &[u8]) -> bool {
+        let name = String::from_utf8_lossy(name).to_lowercase();
+
+        self.blocked.iter().any(|blocked| name == *blocked || name.ends_with(&format!(".{}", blocked)))
+    }
+
+    fn contains_blocked(&self, data: &[u8]) -> bool {
+        let data = String::from_utf8_lossy(data).to_lowercase();
+
+        self.blocked.iter().any(|blocked| data.contains(blocked.as_str()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Block,
+    Pass,
+}
+
+fn u16_at(data: &[u8], at: usize) -> Option<usize> {
+    Some(u16::from_be_bytes([*data.get(at)?, *data.get(at + 1)?]) as usize)
+}
+
+fn u24_at(data: &[u8], at: usize) -> Option<usize> {
+    Some(u32::from_be_bytes([0, *data.get(at)?, *data.get(at + 1)?, *data.get(at + 2)?]) as usize)
+}
+
+// The server name of a complete ClientHello handshake message, walked field by field.
+
+fn server_name(hello: &[u8]) -> Option<&[u8]> {
+    let mut at = 4 + 2 + 32;
+
+    at += 1 + *hello.get(at)? as usize;
+    at += 2 + u16_at(hello, at)?;
+    at += 1 + *hello.get(at)? as usize;
+
+    let end = (at + 2 + u16_at(hello, at)?).min(hello.len());
+
+    at += 2;
+
+    while at + 4 <= end {
+        let (kind, len) = (u16_at(hello, at)?, u16_at(hello, at + 2)?);
+
+        if kind == 0 {
+            let name_len = u16_at(hello, at + 7)?;
+
+            return hello.get(at + 9..at + 9 + name_len);
+        }
+
+        at += 4 + len;
+    }
+
+    None
+}
+
+// Handshake bytes from the complete TLS records at the start of the stream.
+
+fn handshake(stream: &[u8]) -> Vec<u8> {
+    let mut joined = Vec::new();
+    let mut at = 0;
+
+    while let (Some(&0x16), Some(len)) = (stream.get(at), u16_at(stream, at + 3)) {
+        let Some(payload) = stream.get(at + 5..at + 5 + len) else {
+            break;
+        };
+
+        joined.extend_from_slice(payload);
+        at += 5 + len;
+    }
+
+    joined
+}
+
+fn inspect_tls(dpi: &Dpi, stream: &[u8]) -> Option<Verdict> {
+    let verdict = |blocked: bool| if blocked { Verdict::Block } else { Verdict::Pass };
+
+    match dpi.parsing {
+        Parsing::Strict => {
+            let record = stream.get(5..5 + u16_at(stream, 3)?)?;
+
+            match (record.first(), u24_at(record, 1)) {
+                (Some(0x01), Some(len)) if len + 4 <= record.len() => Some(verdict(server_name(&record[..len + 4]).is_some_and(|name| dpi.blocks(name)))),
+                _ => Some(Verdict::Pass)
+            }
+        },
+        Parsing::Loose => {
+            let joined = handshake(stream);
+
+            if dpi.contains_blocked(&joined) {
+                return Some(Verdict::Block);
+            }
+
+            u24_at(&joined, 1).filter(|len| joined.len() >= len + 4).map(|_| Verdict::Pass)
+        }
+    }
+}
+
+fn inspect_http(dpi: &Dpi, stream: &[u8]) -> Option<Verdict> {
+    let complete = stream.windows(4).any(|window| window == b"\r\n\r\n");
+
+    match dpi.parsing {
+        Parsing::Strict => {
+            if !complete {
+                return None;
+            }
+
+            let blocked = stream
+                .split(|byte| *byte == b'\n')
+                .filter_map(|line| line.strip_prefix(b"Host: "))
+                .any(|host| dpi.blocks(host.strip_suffix(b"\r").unwrap_or(host)));
+
+            Some(if blocked { Verdict::Block } else { Verdict::Pass })
+        },
+        Parsing::Loose if dpi.contains_blocked(stream) => Some(Verdict::Block),
+        Parsing::Loose => complete.then_some(Verdict::Pass)
+    }
+}
+
+// None while the box needs more of the stream to decide.
+
+fn inspect(dpi: &Dpi, stream: &[u8]) -> Option<Verdict> {
+    match stream.first()? {
+        0x16 => inspect_tls(dpi, stream),
+        byte if byte.is_ascii_uppercase() => inspect_http(dpi, stream),
+        _ => Some(Verdict::Pass)
+    }
+}
+
+// Frames are Ethernet with IPv4 inside; everything else is bridged untouched.
+
+struct Segment {
+    ip: usize,
+    tcp: usize,
+    end: usize,
+    ttl: u8,
+    src: [u8; 4],
+    dst: [u8; 4],
+    sport: u16,
+    dport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: usize,
+}
+
+impl Segment {
+    fn parse(frame: &[u8]) -> Option<Segment> {
+        if frame.get(12..14)? != [0x08, 0x00] || frame.get(23)? != &6 {
+            return None;
+        }
+
+        let ip = 14;
+        let tcp = ip + (frame[ip] & 0x0f) as usize * 4;
+        let end = (ip + u16_at(frame, ip + 2)?).min(frame.len());
+        let payload = tcp + (*frame.get(tcp + 12)? >> 4) as usize * 4;
+
+        let word = |at: usize| u32::from_be_bytes(frame[at..at + 4].try_into().unwrap());
+
+        Some(Segment {
+            ip,
+            tcp,
+            end,
+            ttl: frame[ip + 8],
+            src: frame[ip + 12..ip + 16].try_into().ok()?,
+            dst: frame[ip + 16..ip + 20].try_into().ok()?,
+            sport: u16_at(frame, tcp)? as u16,
+            dport: u16_at(frame, tcp + 2)? as u16,
+            seq: word(tcp + 4),
+            ack: word(tcp + 8),
+            flags: frame[tcp + 13],
+            payload: payload.min(end),
+        })
+    }
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+// Veth hands segments over with the checksum left to offloading, so whatever goes back out
+// of the bridge gets a real one.
+
+fn fix_tcp_checksum(frame: &mut [u8], segment: &Segment) {
+    frame[segment.tcp + 16..segment.tcp + 18].fill(0);
+
+    let pseudo = [&segment.src[..], &segment.dst[..], &[0, 6], &((segment.end - segment.tcp) as u16).to_be_bytes()[..]].concat();
+    let sum = checksum(&[&pseudo[..], &frame[segment.tcp..segment.end]].concat());
+
+    frame[segment.tcp + 16..segment.tcp + 18].copy_from_slice(&sum.to_be_bytes());
+}
+
+fn reset(ethernet: &[u8], src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16, seq: u32, ack: Option<u32>) -> Vec<u8> {
+    let mut ip = [&[0x45, 0x00, 0x00, 40, 0x00, 0x00, 0x40, 0x00, 64, 6, 0, 0][..], &src, &dst].concat();
+    let sum = checksum(&ip);
+
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    let flags = if ack.is_some() { 0x14 } else { 0x04 };
+    let tcp = [&sport.to_be_bytes()[..], &dport.to_be_bytes(), &seq.to_be_bytes(), &ack.unwrap_or(0).to_be_bytes(), &[0x50, flags, 0, 0, 0, 0, 0, 0]].concat();
+
+    let mut frame = [ethernet, &ip, &tcp].concat();
+    let segment = Segment::parse(&frame).unwrap();
+
+    fix_tcp_checksum(&mut frame, &segment);
+
+    frame
+}
+
+type Flow = ([u8; 4], u16, [u8; 4], u16);
+
+#[derive(Default)]
+struct FlowState {
+    next: Option<u32>,
+    stream: Vec<u8>,
+    verdict: Option<bool>,
+}
+
+struct Link {
+    fd: OwnedFd,
+}
+
+impl Link {
+    fn open(interface: &str) -> io::Result<Link> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as i32) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let name = CString::new(interface).unwrap();
+
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) } as i32;
+
+        let timeout = libc::timeval { tv_sec: 0, tv_usec: 100_000 };
+
+        let result = unsafe {
+            libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout as *const _ as *const libc::c_void, std::mem::size_of::<libc::timeval>() as libc::socklen_t);
+            libc::bind(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Link { fd })
+    }
+
+    // Frames arriving on the interface; the ones the bridge sent out itself are skipped.
+
+    fn receive(&self, buffer: &mut [u8]) -> Option<usize> {
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+
+        let received = unsafe {
+            libc::recvfrom(self.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+        };
+
+        (received > 0 && addr.sll_pkttype != libc::PACKET_OUTGOING).then_some(received as usize)
+    }
+
+    fn send(&self, frame: &[u8]) {
+        unsafe { libc::send(self.fd.as_raw_fd(), frame.as_ptr() as *const libc::c_void, frame.len(), 0) };
+    }
+}
+
+struct Bridge {
+    dpi: Dpi,
+    flows: Mutex<HashMap<Flow, FlowState>>,
+}
+
+impl Bridge {
+    // Client to server: the only direction the box looks into.
+
+    fn upstream(&self, frame: &mut [u8], client: &Link, server: &Link) {
+        let Some(segment) = Segment::parse(frame) else {
+            return server.send(frame);
+        };
+
+        let flow = (segment.src, segment.sport, segment.dst, segment.dport);
+        let mut flows = self.flows.lock().unwrap();
+
+        if segment.flags & 0x02 != 0 {
+            flows.insert(flow, FlowState { next: Some(segment.seq.wrapping_add(1)), ..FlowState::default() });
+        }
+
+        let state = flows.entry(flow).or_default();
+
+        if state.verdict == Some(true) {
+            return;
+        }
+
+        let data = &frame[segment.payload..segment.end];
+        let visible = !self.dpi.honour_ttl || segment.ttl >= SERVER_TTL;
+
+        if state.verdict.is_none() && visible && !data.is_empty() {
+            match self.dpi.inspect {
+                Inspect::FirstSegment => {
+                    state.verdict = Some(inspect(&self.dpi, data) == Some(Verdict::Block));
+                },
+                Inspect::Reassemble => {
+                    let next = state.next.unwrap_or(segment.seq);
+                    let skip = next.wrapping_sub(segment.seq) as usize;
+
+                    if skip < data.len() {
+                        state.stream.extend_from_slice(&data[skip..]);
+                        state.next = Some(segment.seq.wrapping_add(data.len() as u32));
+                    }
+
+                    state.verdict = inspect(&self.dpi, &state.stream).map(|verdict| verdict == Verdict::Block);
+                }
+            }
+        }
+
+        if state.verdict == Some(true) {
+            let data_len = (segment.end - segment.payload) as u32;
+            let reversed = [&frame[6..12], &frame[0..6], &frame[12..14]].concat();
+
+            server.send(&reset(&frame[..14], segment.src, segment.dst, segment.sport, segment.dport, segment.seq, None));
+            client.send(&reset(&reversed, segment.dst, segment.src, segment.dport, segment.sport, segment.ack, Some(segment.seq.wrapping_add(data_len))));
+
+            return;
+        }
+
+        fix_tcp_checksum(frame, &segment);
+
+        server.send(frame);
+    }
+
+    fn downstream(&self, frame: &mut [u8], client: &Link) {
+        let Some(segment) = Segment::parse(frame) else {
+            return client.send(frame);
+        };
+
+        let flow = (segment.dst, segment.dport, segment.src, segment.sport);
+
+        if self.flows.lock().unwrap().get(&flow).is_some_and(|state| state.verdict == Some(true)) {
+            return;
+        }
+
+        fix_tcp_checksum(frame, &segment);
+
+        client.send(frame);
+    }
+}
+
+fn enter(namespace: &str) {
+    let file = File::open(format!("/var/run/netns/{}", namespace)).unwrap();
+
+    assert_eq!(unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) }, 0, "setns {}", namespace);
+}
+
+fn ip(args: &str) -> bool {
+    Command::new("ip")
+        .args(args.split_whitespace())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+// The server answers a ClientHello with a ServerHello record once the whole handshake message
+// is in, joined across records the way TLS allows, and anything else with an alert. HTTP
+// requests get a 200 with the Host they named.
+
+fn serve(mut stream: TcpStream) {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let response: Vec<u8> = loop {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => return,
+            Ok(len) => received.extend_from_slice(&buffer[..len])
+        }
+
+        if received.first() == Some(&0x16) {
+            let joined = handshake(&received);
+
+            match u24_at(&joined, 1) {
+                Some(len) if joined.len() >= len + 4 && joined[0] == 0x01 && server_name(&joined[..len + 4]).is_some() => {
+                    break vec![0x16, 0x03, 0x03, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00];
+                },
+                Some(len) if joined.len() >= len + 4 => break vec![0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28],
+                _ => continue
+            }
+        }
+
+        if received.windows(4).any(|window| window == b"\r\n\r\n") {
+            break b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_vec();
+        }
+    };
+
+    let _ = stream.write_all(&response);
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let _ = stream.read(&mut buffer);
+}
+
+fn echo(mut stream: TcpStream) {
+    let mut received = Vec::new();
+
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    if stream.read_to_end(&mut received).is_ok() {
+        let _ = stream.write_all(&received);
+    }
+}
+
+static LABS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Lab {
+    prefix: String,
+    stop: Arc<AtomicBool>,
+    waterfall: Option<Waterfall>,
+}
+
+impl Lab {
+    pub fn start(dpi: Dpi) -> Option<Lab> {
+        let prefix = format!("wf{}n{}", std::process::id(), LABS.fetch_add(1, Ordering::Relaxed));
+
+        if unsafe { libc::geteuid() } != 0 || !ip(&format!("netns add {}c", prefix)) {
+            eprintln!("skipping: the DPI lab needs root and `ip netns`");
+
+            return None;
+        }
+
+        let lab = Lab { prefix: prefix.clone(), stop: Arc::new(AtomicBool::new(false)), waterfall: None };
+
+        let setup = [
+            "netns add {p}d",
+            "netns add {p}r",
+            "netns add {p}s",
+            "link add c0 netns {p}c type veth peer name d0 netns {p}d",
+            "link add d1 netns {p}d type veth peer name r0 netns {p}r",
+            "link add r1 netns {p}r type veth peer name s0 netns {p}s",
+            "-n {p}c addr add 10.0.1.1/24 dev c0",
+            "-n {p}r addr add 10.0.1.2/24 dev r0",
+            "-n {p}r addr add 10.0.2.1/24 dev r1",
+            "-n {p}s addr add 10.0.2.2/24 dev s0",
+            "-n {p}c link set lo up",
+            "-n {p}c link set c0 up",
+            "-n {p}d link set d0 up promisc on",
+            "-n {p}d link set d1 up promisc on",
+            "-n {p}r link set r0 up",
+            "-n {p}r link set r1 up",
+            "-n {p}s link set s0 up",
+            "-n {p}c route add default via 10.0.1.2",
+            "-n {p}s route add default via 10.0.2.1",
+        ];
+
+        for command in setup {
+            assert!(ip(&command.replace("{p}", &prefix)), "ip {}", command.replace("{p}", &prefix));
+        }
+
+        let router = format!("{}r", prefix);
+
+        thread::spawn(move || {
+            enter(&router);
+
+            std::fs::write("/proc/sys/net/ipv4/ip_forward", "1").unwrap();
+        }).join().unwrap();
+
+        let (ready, bound) = mpsc::channel();
+        let (namespace, stop) = (format!("{}s", prefix), lab.stop.clone());
+
+        thread::spawn(move || {
+            enter(&namespace);
+
+            let listeners: Vec<(TcpListener, fn(TcpStream))> = [(443, serve as fn(TcpStream)), (80, serve), (ECHO, echo)]
+                .into_iter()
+                .map(|(port, handle)| (TcpListener::bind((SERVER, port)).unwrap(), handle))
+                .collect();
+
+            ready.send(()).unwrap();
+
+            for (listener, handle) in listeners {
+                let stop = stop.clone();
+
+                thread::spawn(move || {
+                    for stream in listener.incoming().flatten().take_while(|_| !stop.load(Ordering::Relaxed)) {
+                        thread::spawn(move || handle(stream));
+                    }
+                });
+            }
+        });
+
+        bound.recv().unwrap();
+
+        let (ready, bound) = mpsc::channel();
+        let (namespace, stop) = (format!("{}d", prefix), lab.stop.clone());
+
+        thread::spawn(move || {
+            enter(&namespace);
+
+            let client = Arc::new(Link::open("d0").unwrap());
+            let server = Arc::new(Link::open("d1").unwrap());
+            let bridge = Arc::new(Bridge { dpi, flows: Mutex::new(HashMap::new()) });
+
+            ready.send(()).unwrap();
+
+            let (downstream_client, downstream_server, downstream_bridge, downstream_stop) = (client.clone(), server.clone(), bridge.clone(), stop.clone());
+
+            thread::spawn(move || {
+                let mut buffer = vec![0u8; 65536];
+
+                while !downstream_stop.load(Ordering::Relaxed) {
+                    if let Some(len) = downstream_server.receive(&mut buffer) {
+                        downstream_bridge.downstream(&mut buffer[..len], &downstream_client);
+                    }
+                }
+            });
+
+            let mut buffer = vec![0u8; 65536];
+
+            while !stop.load(Ordering::Relaxed) {
+                if let Some(len) = client.receive(&mut buffer) {
+                    bridge.upstream(&mut buffer[..len], &client, &server);
+                }
+            }
+        });
+
+        bound.recv().unwrap();
+
+        Some(lab)
+    }
+
+    // (Re)starts waterfall in the client namespace and waits until it accepts connections.
+
+    pub fn waterfall(&mut self, args: &[&str]) {
+        self.waterfall = None;
+
+        let namespace = format!("{}c", self.prefix);
+        let ready = || self.in_client(|| TcpStream::connect(("127.0.0.1", SOCKS_PORT)).is_ok());
+
+        let waterfall = Waterfall::start_with(&["ip", "netns", "exec", &namespace], SOCKS_PORT, args, ready);
+
+        self.waterfall = Some(waterfall);
+    }
+
+    fn in_client<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> T {
+        let namespace = format!("{}c", self.prefix);
+
+        thread::spawn(move || {
+            enter(&namespace);
+
+            f()
+        }).join().unwrap()
+    }
+
+    // Sends `request` to the server through waterfall and returns the first response, if any
+    // came back before the connection was closed or reset.
+
+    pub fn exchange(&self, port: u16, request: Vec<u8>) -> Option<Vec<u8>> {
+        self.in_client(move || {
+            let mut socks = socks_connect(SOCKS_PORT, SERVER, port).ok()?;
+
+            socks.set_read_timeout(Some(Duration::from_secs(8))).ok()?;
+
+            socks.write_all(&request).ok()?;
+
+            let mut response = vec![0u8; 4096];
+
+            match socks.read(&mut response) {
+                Ok(len) if len > 0 => Some(response[..len].to_vec()),
+                _ => None
+            }
+        })
+    }
+
+    // What the server read when the client wrote `data` through waterfall and closed its side.
+
+    pub fn echo(&self, data: Vec<u8>) -> Vec<u8> {
+        self.in_client(move || {
+            let mut received = Vec::new();
+
+            let Ok(mut socks) = socks_connect(SOCKS_PORT, SERVER, ECHO) else {
+                return received;
+            };
+
+            let _ = socks.set_read_timeout(Some(Duration::from_secs(8)));
+
+            if socks.write_all(&data).is_ok() && socks.shutdown(std::net::Shutdown::Write).is_ok() {
+                let _ = socks.read_to_end(&mut received);
+            }
+
+            received
+        })
+    }
+
+    pub fn tls(&self, sni: &str) -> bool {
+        self.exchange(443, client_hello(sni)).is_some_and(|response| response.starts_with(&[0x16, 0x03, 0x03, 0x00, 0x04, 0x02]))
+    }
+
+    pub fn http(&self, host: &str) -> bool {
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n\r\n", host);
+
+        self.exchange(80, request.into_bytes()).is_some_and(|response| response.starts_with(b"HTTP/1.1 200"))
+    }
+}
+
+impl Drop for Lab {
+    fn drop(&mut self) {
+        self.waterfall = None;
+
+        self.stop.store(true, Ordering::Relaxed);
+
+        for suffix in ["c", "d", "r", "s"] {
+            ip(&format!("netns del {}{}", self.prefix, suffix));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+// Which strategies get past which DPI behaviours, with waterfall, the DPI box and the server
+// in separate network namespaces so that TTL expiry happens on a real router. See
+// tests/dpi/mod.rs for the lab; without root the tests have nothing to run against and pass.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+mod dpi;
+
+use dpi::{Dpi, Inspect, Lab, Parsing};
+
+#[test]
+fn no_strategy_is_blocked_by_every_box() {
+    for dpi in [
+        Dpi::new(Inspect::FirstSegment, Parsing::Strict),
+        Dpi::new(Inspect::FirstSegment, Parsing::Loose),
+        Dpi::new(Inspect::Reassemble, Parsing::Strict).honour_ttl(),
+        Dpi::new(Inspect::Reassemble, Parsing::Loose),
+    ] {
+        let Some(mut lab) = Lab::start(dpi.clone()) else {
+            return;
+        };
+
+        lab.waterfall(&[]);
+
+        assert!(!lab.tls("blocked.test"), "{:?}", dpi);
+        assert!(lab.tls("allowed.test"), "{:?}", dpi);
+        assert!(!lab.http("blocked.test"), "{:?}", dpi);
+        assert!(lab.http("allowed.test"), "{:?}", dpi);
+    }
+}
+
+#[test]
+fn split_defeats_first_segment_inspection_only() {
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::FirstSegment, Parsing::Loose)) else {
+        return;
+    };
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tcp_split", "1+s"]);
+
+    assert!(lab.tls("blocked.test"));
+
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::Reassemble, Parsing::Loose)) else {
+        return;
+    };
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tcp_split", "1+s"]);
+
+    assert!(!lab.tls("blocked.test"));
+}
+
+#[test]
+fn record_fragmentation_defeats_strict_parsing_only() {
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::Reassemble, Parsing::Strict)) else {
+        return;
+    };
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tls_record_frag", "1+s"]);
+
+    assert!(lab.tls("blocked.test"));
+
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::FirstSegment, Parsing::Loose)) else {
+        return;
+    };
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tls_record_frag", "1+s"]);
+
+    assert!(!lab.tls("blocked.test"));
+}
+
+// The TTL 1 copy of the first part expires at the router. A box that ignores TTL reassembles
+// it with the second part like the server would; one that honours it sees the second part
+// beyond a gap, drops it, and only gets the first part once it is retransmitted.
+
+#[test]
+fn disorder_defeats_reassembly_that_honours_ttl() {
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::Reassemble, Parsing::Loose).honour_ttl()) else {
+        return;
+    };
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tcp_disorder", "1+s"]);
+
+    assert!(lab.tls("blocked.test"));
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tcp_disorder2", "1+s"]);
+
+    assert!(!lab.tls("blocked.test"));
+
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::Reassemble, Parsing::Loose)) else {
+        return;
+    };
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tcp_disorder", "1+s"]);
+
+    assert!(!lab.tls("blocked.test"));
+}
+
+// The urgent byte takes a place in the sequence space, so the box reassembles it into the
+// middle of the name, while the server's stack takes it out of the stream.
+
+#[test]
+fn out_of_band_defeats_reassembly() {
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::Reassemble, Parsing::Loose)) else {
+        return;
+    };
+
+    lab.waterfall(&["--dpi_bypass_strategies", "tcp_out_of_band", "1+s"]);
+
+    assert!(lab.tls("blocked.test"));
+}
+
+#[test]
+fn host_case_defeats_strict_http_parsing_only() {
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::FirstSegment, Parsing::Strict)) else {
+        return;
+    };
+
+    lab.waterfall(&["--http_host_cmix"]);
+
+    assert!(lab.http("blocked.test"));
+
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::FirstSegment, Parsing::Loose)) else {
+        return;
+    };
+
+    lab.waterfall(&["--http_host_cmix"]);
+
+    assert!(!lab.http("blocked.test"));
+}
+
+#[test]
+fn fallback_recovers_from_a_reset() {
+    let Some(mut lab) = Lab::start(Dpi::new(Inspect::Reassemble, Parsing::Loose).honour_ttl()) else {
+        return;
+    };
+
+    lab.waterfall(&[
+        "--dpi_bypass_strategies", "tcp_split", "1+s",
+        "--fallback_strategies", "tcp_disorder", "1+s",
+    ]);
+
+    assert!(lab.tls("blocked.test"));
+}
@@ -0,0 +1,137 @@
+// Automatic fallback against a stand-in server that resets a ClientHello arriving in a single
+// segment, the way a DPI box that doesn't reassemble would: waterfall has to reconnect, replay
+// the hello with the next strategy set, and stop once the retries or the time budget run out.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{client_hello, Waterfall};
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const SERVER_HELLO: &[u8] = &[0x16, 0x03, 0x03, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00];
+
+fn data_segments(stream: &TcpStream) -> u32 {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, &mut info as *mut _ as *mut libc::c_void, &mut len)
+    };
+
+    assert_eq!(result, 0);
+
+    info.tcpi_data_segs_in
+}
+
+fn first_record(stream: &mut TcpStream) -> Vec<u8> {
+    let mut record = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+
+    while record.len() < 5 || record.len() < 5 + u16::from_be_bytes([record[3], record[4]]) as usize {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(received) => record.extend_from_slice(&buffer[..received])
+        }
+    }
+
+    record
+}
+
+struct Server {
+    port: u16,
+    accepted: Arc<AtomicUsize>,
+}
+
+// Resets hellos that arrive in one segment, or every hello with `block_all`, `delay` after
+// reading them; answers the others with a ServerHello. Counts the connections it accepts.
+
+fn stand_in(block_all: bool, delay: Duration) -> Server {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            thread::spawn(move || {
+                first_record(&mut stream);
+
+                if block_all || data_segments(&stream) == 1 {
+                    thread::sleep(delay);
+
+                    socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)).unwrap();
+
+                    return;
+                }
+
+                let _ = stream.write_all(SERVER_HELLO);
+            });
+        }
+    });
+
+    Server { port, accepted }
+}
+
+// What the client reads back after sending a ClientHello through waterfall.
+
+fn exchange(waterfall: &Waterfall, server: &Server) -> Vec<u8> {
+    let mut client = waterfall.connect("127.0.0.1", server.port).unwrap();
+    let mut response = Vec::new();
+
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(&client_hello("blocked.example")).unwrap();
+
+    let _ = client.read_to_end(&mut response);
+
+    response
+}
+
+#[test]
+fn blocked_hello_is_replayed_with_the_next_set() {
+    let server = stand_in(false, Duration::ZERO);
+    let waterfall = Waterfall::start(&["--dpi_bypass_strategies", "tcp_split", "1000", "--fallback_strategies", "tcp_split", "1+s"]);
+
+    assert_eq!(exchange(&waterfall, &server), SERVER_HELLO);
+    assert_eq!(server.accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn fallback_stops_after_the_retries() {
+    let server = stand_in(true, Duration::ZERO);
+
+    let waterfall = Waterfall::start(&[
+        "--fallback_retries", "1",
+        "--dpi_bypass_strategies", "tcp_split", "1+s",
+        "--fallback_strategies", "tcp_split", "2+s",
+        "--fallback_strategies", "tcp_split", "3+s",
+    ]);
+
+    assert_eq!(exchange(&waterfall, &server), b"");
+    assert_eq!(server.accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn fallback_stops_after_the_time_budget() {
+    let server = stand_in(true, Duration::from_millis(300));
+
+    let waterfall = Waterfall::start(&[
+        "--fallback_budget", "100",
+        "--dpi_bypass_strategies", "tcp_split", "1+s",
+        "--fallback_strategies", "tcp_split", "2+s",
+    ]);
+
+    assert_eq!(exchange(&waterfall, &server), b"");
+    assert_eq!(server.accepted.load(Ordering::SeqCst), 1);
+}
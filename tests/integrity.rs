@@ -0,0 +1,298 @@
+// Property tests for stream integrity: desync may change how the bytes are segmented, never
+// what the server reassembles. Every case starts waterfall with a random list of strategies and
+// positions, sends a random ClientHello through it to a local server and compares what the
+// server read with what the client wrote. tls_record_frag is the one intended change: there the
+// records may be cut differently, but their payloads must join up to the same handshake.
+//
+// Fakes only stay out of the stream when their TTL really expires on the way, so those cases
+// run through the DPI lab in tests/dpi/mod.rs with a box that lets everything through.
+
+mod common;
+#[cfg(target_os = "linux")]
+mod dpi;
+
+use common::{tls, Waterfall};
+
+use proptest::prelude::*;
+use proptest::test_runner::TestRunner;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const IN_BAND: &[&str] = &[
+    "tcp_split",
+    "tcp_disorder",
+    "tcp_disorder2",
+    "tcp_meltdown",
+    "tls_record_frag",
+];
+
+const FAKES: &[&str] = &[
+    "tcp_fake_disordered",
+    "tcp_fake_insert",
+    "tcp_fake_surround",
+    "tcp_fake2_disordered",
+    "tcp_fake2_insert",
+];
+
+// TCP keeps a single urgent pointer: when the next urgent byte arrives before the receiver read
+// the previous one, the previous one stays in the stream. The loopback cases send one urgent
+// byte per write; the lab cases below send as many as the strategies do.
+
+const URGENT: &[&str] = &[
+    "tcp_out_of_band",
+    "tcp_out_of_band_disorder",
+    "tcp_out_of_band_disorder2",
+    "tcp_out_of_band_hell",
+    "tcp_fake_disordered",
+    "tcp_fake_insert",
+    "tcp_fake2_disordered",
+    "tcp_fake2_insert",
+];
+
+const SEVERAL_URGENT: &[&str] = &["tcp_fake_surround", "tcp_out_of_band_hell"];
+
+// Loopback doesn't expire anything, so a fake sent in band always reaches the server. As
+// urgent data it is taken out of the stream by the receiving stack instead.
+
+const ONE_URGENT_BYTE: &[&str] = &["--fake_as_oob", "--oob_stream_hell_data", "."];
+
+// The lab's router is the first hop, so TTL 1 expires before the server.
+
+const EXPIRING_FAKES: &[&str] = &["--fake_packet_ttl", "1"];
+
+#[derive(Debug, Clone)]
+struct Hello {
+    sni: String,
+    random: Vec<u8>,
+    session: Vec<u8>,
+    ciphers: Vec<u16>,
+    padding: usize,
+}
+
+impl Hello {
+    fn bytes(&self) -> Vec<u8> {
+        let extensions = [
+            tls::server_name(&self.sni),
+            tls::extension(0x000a, &tls::with_length(2, &tls::list(&[0x001d, 0x0017]))),
+            tls::extension(0x002b, &[0x02, 0x03, 0x04]),
+            tls::extension(0x0015, &vec![0u8; self.padding]),
+        ];
+
+        tls::client_hello(&self.random, &self.session, &self.ciphers, &extensions)
+    }
+}
+
+// The padding keeps at least 32 bytes after the server name, so the positions below always
+// fall inside the record.
+
+fn hello() -> impl Strategy<Value = Hello> {
+    (
+        prop::collection::vec("[a-z0-9]{1,12}", 1..4),
+        prop::collection::vec(any::<u8>(), 32),
+        prop::collection::vec(any::<u8>(), 0..=32),
+        prop::collection::vec(any::<u16>(), 1..20),
+        32..400usize,
+    ).prop_map(|(labels, random, session, ciphers, padding)| Hello { sni: format!("{}.test", labels.join(".")), random, session, ciphers, padding })
+}
+
+fn position() -> impl Strategy<Value = String> {
+    prop_oneof![
+        (1..120usize).prop_map(|index| index.to_string()),
+        (0..16usize).prop_map(|index| format!("{}+s", index)),
+    ]
+}
+
+#[derive(Debug, Clone)]
+struct Rules {
+    entries: Vec<(&'static str, Vec<String>)>,
+    stacked: bool,
+}
+
+impl Rules {
+    // Either every entry is a rule of its own, or they are one comma separated stack.
+
+    fn args(&self) -> Vec<String> {
+        if !self.stacked {
+            return self.entries
+                .iter()
+                .flat_map(|(name, positions)| ["--dpi_bypass_strategies".to_string(), name.to_string(), positions.join(",")])
+                .collect();
+        }
+
+        let names = self.entries.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(",");
+
+        ["--dpi_bypass_strategies".to_string(), names]
+            .into_iter()
+            .chain(self.entries.iter().map(|(_, positions)| positions.join(",")))
+            .collect()
+    }
+
+    fn frames_records(&self) -> bool {
+        self.entries.iter().any(|(name, _)| *name == "tls_record_frag")
+    }
+}
+
+fn entry(names: &'static [&'static str], positions: std::ops::RangeInclusive<usize>) -> impl Strategy<Value = (&'static str, Vec<String>)> {
+    (prop::sample::select(names), prop::collection::vec(position(), positions))
+}
+
+fn in_band() -> impl Strategy<Value = Rules> {
+    (prop::collection::vec(entry(IN_BAND, 1..=2), 1..=3), any::<bool>())
+        .prop_map(|(entries, stacked)| Rules { entries, stacked })
+}
+
+// In band strategies with one of `names` somewhere among them, at `positions`.
+
+fn with_one(names: &'static [&'static str], positions: std::ops::RangeInclusive<usize>) -> impl Strategy<Value = Rules> {
+    (prop::collection::vec(entry(IN_BAND, 1..=2), 0..=2), entry(names, positions), any::<prop::sample::Index>(), any::<bool>())
+        .prop_map(|(mut entries, one, index, stacked)| {
+            entries.insert(index.index(entries.len() + 1), one);
+
+            Rules { entries, stacked }
+        })
+}
+
+// Handshake bytes of a stream made of whole TLS records, None when anything else is in it.
+
+fn records(mut stream: &[u8]) -> Option<Vec<u8>> {
+    let mut joined = Vec::new();
+
+    while !stream.is_empty() {
+        let [0x16, 0x03, _, high, low, ..] = *stream else {
+            return None;
+        };
+
+        let end = 5 + u16::from_be_bytes([high, low]) as usize;
+
+        joined.extend_from_slice(stream.get(5..end)?);
+        stream = &stream[end..];
+    }
+
+    Some(joined)
+}
+
+// What a server behind the proxy reads once the client wrote `data`: everything up to the end
+// of the handshake, or whatever arrived before the stream went quiet.
+
+fn relay(waterfall: &Waterfall, data: &[u8]) -> Vec<u8> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = listener.local_addr().unwrap().port();
+    let expected = records(data).map(|handshake| handshake.len()).unwrap_or(data.len());
+
+    let (sender, received) = mpsc::channel();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut stream_data = Vec::new();
+        let mut buffer = [0u8; 4096];
+
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        while records(&stream_data).is_none_or(|handshake| handshake.len() < expected) {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => stream_data.extend_from_slice(&buffer[..len])
+            }
+        }
+
+        sender.send(stream_data).unwrap();
+    });
+
+    waterfall.connect("127.0.0.1", target).unwrap().write_all(data).unwrap();
+
+    received.recv_timeout(Duration::from_secs(5)).unwrap_or_default()
+}
+
+fn arguments(rules: &Rules, extra: &[&str]) -> Vec<String> {
+    extra.iter().map(|arg| arg.to_string()).chain(rules.args()).collect()
+}
+
+fn compare(rules: &Rules, args: &[String], sent: Vec<u8>, received: Vec<u8>) -> Result<(), TestCaseError> {
+    if rules.frames_records() {
+        prop_assert!(records(&received).is_some(), "not whole TLS records with {:?}: {:02x?}", args, received);
+        prop_assert_eq!(records(&received), records(&sent), "handshake differs with {:?}", args);
+    } else {
+        prop_assert_eq!(received, sent, "stream differs with {:?}", args);
+    }
+
+    Ok(())
+}
+
+fn check(hello: &Hello, rules: &Rules, extra: &[&str]) -> Result<(), TestCaseError> {
+    let args = arguments(rules, extra);
+    let waterfall = Waterfall::start(&args.iter().map(String::as_str).collect::<Vec<_>>());
+
+    let sent = hello.bytes();
+    let received = relay(&waterfall, &sent);
+
+    compare(rules, &args, sent, received)
+}
+
+// Runs the cases against the echo server of one lab, restarting waterfall for each of them.
+
+#[cfg(target_os = "linux")]
+fn check_in_lab(rules: impl Strategy<Value = Rules>, extra: &[&str]) {
+    use dpi::{Dpi, Inspect, Lab, Parsing};
+
+    let Some(lab) = Lab::start(Dpi { blocked: Vec::new(), ..Dpi::new(Inspect::FirstSegment, Parsing::Loose) }) else {
+        return;
+    };
+
+    let lab = std::cell::RefCell::new(lab);
+    let mut runner = TestRunner::new(ProptestConfig::with_cases(16));
+
+    let result = runner.run(&(hello(), rules), |(hello, rules)| {
+        let args = arguments(&rules, extra);
+        let mut lab = lab.borrow_mut();
+
+        lab.waterfall(&args.iter().map(String::as_str).collect::<Vec<_>>());
+
+        let sent = hello.bytes();
+        let received = lab.echo(sent.clone());
+
+        compare(&rules, &args, sent, received)
+    });
+
+    if let Err(error) = result {
+        panic!("{}", error);
+    }
+}
+
+// Known failure: an in band fake is one byte written to the socket with a low TTL. It expires
+// at the router, but the kernel then retransmits it with the normal TTL, so the server reads
+// one extra byte for every fake.
+
+#[cfg(target_os = "linux")]
+#[test]
+#[ignore = "known failure: in band fakes are retransmitted into the stream"]
+fn in_band_fakes_preserve_the_stream() {
+    check_in_lab(with_one(FAKES, 1..=2), EXPIRING_FAKES);
+}
+
+// Known failure: tcp_fake_surround and the default --oob_stream_hell_data put more than one
+// urgent byte into a write, and every one but the last stays in the stream.
+
+#[cfg(target_os = "linux")]
+#[test]
+#[ignore = "known failure: only the last urgent byte of a write leaves the stream"]
+fn urgent_data_preserves_the_stream() {
+    check_in_lab(with_one(SEVERAL_URGENT, 1..=2), &["--fake_as_oob"]);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(48))]
+
+    #[test]
+    fn in_band_strategies_preserve_the_stream(hello in hello(), rules in in_band()) {
+        check(&hello, &rules, &[])?;
+    }
+
+    #[test]
+    fn one_urgent_byte_preserves_the_stream(hello in hello(), rules in with_one(URGENT, 1..=1)) {
+        check(&hello, &rules, ONE_URGENT_BYTE)?;
+    }
+}
@@ -0,0 +1,143 @@
+// `waterfall probe` against a stand-in for a DPI box that only looks at single segments: a
+// ClientHello that arrives in one piece and names a blocked host gets a reset, anything that
+// parses as a ClientHello otherwise gets a ServerHello record, and the rest a TLS alert.
+
+#![cfg(target_os = "linux")]
+
+use serde_json::Value;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+const SERVER_HELLO: &[u8] = &[0x16, 0x03, 0x03, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00];
+const ALERT: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+
+fn data_segments(stream: &TcpStream) -> u32 {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, &mut info as *mut _ as *mut libc::c_void, &mut len)
+    };
+
+    assert_eq!(result, 0);
+
+    info.tcpi_data_segs_in
+}
+
+// Reads until the first TLS record is complete, or until nothing more arrives.
+
+fn first_record(stream: &mut TcpStream) -> Vec<u8> {
+    let mut record = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+
+    while record.len() < 5 || record.len() < 5 + u16::from_be_bytes([record[3], record[4]]) as usize {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(received) => record.extend_from_slice(&buffer[..received])
+        }
+    }
+
+    record
+}
+
+fn is_client_hello(record: &[u8]) -> bool {
+    let [0x16, 0x03, _, high, low, 0x01, a, b, c, ..] = *record else {
+        return false;
+    };
+
+    let len = u16::from_be_bytes([high, low]) as usize;
+
+    record.len() == 5 + len && u32::from_be_bytes([0, a, b, c]) as usize + 4 == len
+}
+
+fn handle(mut stream: TcpStream, blocked: &str) {
+    let record = first_record(&mut stream);
+
+    let names_blocked = record.windows(blocked.len()).any(|window| window == blocked.as_bytes());
+
+    if names_blocked && data_segments(&stream) == 1 {
+        socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)).unwrap();
+
+        return;
+    }
+
+    let _ = stream.write_all(if is_client_hello(&record) { SERVER_HELLO } else { ALERT });
+}
+
+fn stand_in(blocked: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || handle(stream, blocked));
+        }
+    });
+
+    addr
+}
+
+fn probe(domain: &str, addr: SocketAddr, args: &[&str]) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_waterfall"))
+        .args(["probe", domain, "--connect", &addr.to_string(), "--json", "--response_timeout", "2000"])
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn working(report: &Value) -> Vec<&str> {
+    report["working"].as_array().unwrap().iter().map(|fragment| fragment.as_str().unwrap()).collect()
+}
+
+#[test]
+fn split_gets_past_single_segment_inspection() {
+    let addr = stand_in("blocked.test");
+
+    let report = probe("blocked.test", addr, &["--attempts", "2", "--positions", "1+s", "--ttls", "3", "--no_stacks"]);
+
+    assert_eq!(report["baseline"]["outcomes"], serde_json::json!(["reset"]));
+    assert_eq!(report["baseline"]["working"], false);
+
+    let working = working(&report);
+
+    assert!(working.contains(&"--dpi_bypass_strategies tcp_split 1+s"), "{:?}", working);
+    assert!(working.contains(&"--dpi_bypass_strategies tcp_disorder 1+s"), "{:?}", working);
+
+    // On loopback nothing expires, so fakes reach the server and spoil the ClientHello
+
+    assert!(!working.iter().any(|fragment| fragment.contains("fake")), "{:?}", working);
+}
+
+#[test]
+fn stacks_are_ranked_after_single_strategies() {
+    let addr = stand_in("blocked.test");
+
+    let report = probe("blocked.test", addr, &["--attempts", "1", "--positions", "1+s", "--ttls", "3"]);
+
+    let working = working(&report);
+    let first_stack = working.iter().position(|fragment| fragment.ends_with(" auto")).expect("no stack works");
+
+    assert!(working[..first_stack].iter().all(|fragment| !fragment.contains(',')), "{:?}", working);
+    assert!(working[first_stack..].iter().all(|fragment| fragment.contains(',')), "{:?}", working);
+}
+
+#[test]
+fn unblocked_host_needs_no_strategy() {
+    let addr = stand_in("blocked.test");
+
+    let report = probe("example.test", addr, &["--attempts", "1", "--positions", "1", "--no_stacks"]);
+
+    assert_eq!(report["baseline"]["outcomes"], serde_json::json!(["success"]));
+    assert_eq!(report["baseline"]["working"], true);
+}
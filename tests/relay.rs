@@ -0,0 +1,196 @@
+// The life of a relayed connection: a half-close is passed on while the other direction keeps
+// flowing, idle and overlong relays are closed, and closed ones leave the list of active
+// connections.
+
+mod common;
+
+use common::Waterfall;
+
+use serde_json::Value;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A server on a free port that runs `handle` on the first connection it accepts.
+
+fn server(handle: impl FnOnce(TcpStream) + Send + 'static) -> (u16, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        handle(stream);
+    });
+
+    (port, handle)
+}
+
+// How long until the relay closes `client` on its own, without the client or the server
+// closing anything.
+
+fn closed_after(mut client: TcpStream) -> Duration {
+    let started = Instant::now();
+    let mut rest = Vec::new();
+
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let _ = client.read_to_end(&mut rest);
+
+    started.elapsed()
+}
+
+fn connections(control: u16) -> Vec<Value> {
+    let mut stream = TcpStream::connect(("127.0.0.1", control)).unwrap();
+    let mut response = String::new();
+
+    write!(stream, "GET /connections HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\n\r\n", control).unwrap();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+
+    serde_json::from_str::<Value>(body).unwrap().as_array().unwrap().clone()
+}
+
+// Polls the list of active connections until `done` accepts it.
+
+fn wait_for(control: u16, done: impl Fn(&[Value]) -> bool) -> Vec<Value> {
+    let started = Instant::now();
+
+    loop {
+        let listed = connections(control);
+
+        if done(&listed) {
+            return listed;
+        }
+
+        assert!(started.elapsed() < Duration::from_secs(3), "connections still listed as {:?}", listed);
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn half_close_keeps_the_other_direction_open() {
+    let (port, server) = server(|mut stream| {
+        let mut request = Vec::new();
+
+        stream.read_to_end(&mut request).unwrap();
+
+        assert_eq!(request, b"ping");
+
+        stream.write_all(b"pong").unwrap();
+    });
+
+    let waterfall = Waterfall::start(&[]);
+    let mut client = waterfall.connect("127.0.0.1", port).unwrap();
+    let mut response = Vec::new();
+
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"ping").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    client.read_to_end(&mut response).unwrap();
+
+    assert_eq!(response, b"pong");
+
+    server.join().unwrap();
+}
+
+#[test]
+fn idle_relays_are_closed() {
+    let (port, server) = server(|mut stream| {
+        let mut request = [0u8; 4];
+
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(b"pong").unwrap();
+
+        let mut rest = Vec::new();
+
+        assert!(stream.read_to_end(&mut rest).is_ok(), "waterfall didn't close the idle relay");
+    });
+
+    let waterfall = Waterfall::start(&["--idle_timeout", "300"]);
+    let mut client = waterfall.connect("127.0.0.1", port).unwrap();
+    let mut response = [0u8; 4];
+
+    client.write_all(b"ping").unwrap();
+    client.read_exact(&mut response).unwrap();
+
+    let closed = closed_after(client);
+
+    assert!(closed >= Duration::from_millis(200) && closed < Duration::from_secs(3), "closed after {:?}", closed);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn relays_are_closed_at_the_maximum_lifetime_even_when_busy() {
+    let (port, server) = server(|mut stream| {
+        let mut buffer = [0u8; 64];
+
+        while let Ok(received @ 1..) = stream.read(&mut buffer) {
+            if stream.write_all(&buffer[..received]).is_err() {
+                break;
+            }
+        }
+    });
+
+    let waterfall = Waterfall::start(&["--max_lifetime", "600"]);
+    let mut client = waterfall.connect("127.0.0.1", port).unwrap();
+    let started = Instant::now();
+    let mut echo = [0u8; 1];
+
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // Traffic every 50 ms keeps the relay from ever being idle.
+
+    let closed = loop {
+        if client.write_all(b".").is_err() || !matches!(client.read(&mut echo), Ok(1)) {
+            break started.elapsed();
+        }
+
+        assert!(started.elapsed() < Duration::from_secs(3), "relay outlived its maximum lifetime");
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    assert!(closed >= Duration::from_millis(500), "closed after {:?}", closed);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn closed_relays_leave_the_connection_list() {
+    let control = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let (port, server) = server(|mut stream| {
+        let mut request = Vec::new();
+
+        stream.read_to_end(&mut request).unwrap();
+    });
+
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let control_addr = format!("127.0.0.1:{}", control);
+
+    let waterfall = Waterfall::start_with(&[], proxy, &["--control_addr", &control_addr], || {
+        TcpStream::connect(("127.0.0.1", proxy)).is_ok() && TcpStream::connect(("127.0.0.1", control)).is_ok()
+    });
+
+    let mut client = waterfall.connect("127.0.0.1", port).unwrap();
+
+    client.write_all(b"ping").unwrap();
+
+    let listed = wait_for(control, |listed| !listed.is_empty());
+
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["target"], format!("127.0.0.1:{}", port));
+
+    drop(client);
+    server.join().unwrap();
+
+    wait_for(control, |listed| listed.is_empty());
+}
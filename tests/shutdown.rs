@@ -0,0 +1,82 @@
+// SIGTERM stops accepting, waits up to --shutdown_grace for open connections, including ones
+// still in the SOCKS handshake, and aborts whatever is left after that.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::Waterfall;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn terminate(waterfall: &Waterfall) {
+    assert_eq!(unsafe { libc::kill(waterfall.pid() as libc::pid_t, libc::SIGTERM) }, 0);
+
+    // Gives the watcher time to wake the accept loop.
+
+    thread::sleep(Duration::from_millis(300));
+}
+
+// A client that has sent its greeting and got the answer, but no request yet.
+
+fn greeted(waterfall: &Waterfall) -> TcpStream {
+    let mut socks = TcpStream::connect(("127.0.0.1", waterfall.port)).unwrap();
+    let mut reply = [0u8; 2];
+
+    socks.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socks.write_all(&[5, 1, 0]).unwrap();
+    socks.read_exact(&mut reply).unwrap();
+
+    socks
+}
+
+#[test]
+fn connections_in_the_handshake_are_waited_for() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut waterfall = Waterfall::start(&["--shutdown_grace", "5000"]);
+    let mut socks = greeted(&waterfall);
+
+    terminate(&waterfall);
+
+    assert!(waterfall.exited(Duration::ZERO).is_none(), "exited with a connection in the handshake");
+
+    let mut reply = [0u8; 10];
+
+    socks.write_all(&[&[5, 1, 0, 1, 127, 0, 0, 1][..], &port.to_be_bytes()].concat()).unwrap();
+    socks.read_exact(&mut reply).unwrap();
+
+    assert_eq!(reply[1], 0);
+
+    let (mut upstream, _) = server.accept().unwrap();
+    let mut received = [0u8; 4];
+
+    socks.write_all(b"ping").unwrap();
+    upstream.read_exact(&mut received).unwrap();
+
+    assert_eq!(&received, b"ping");
+
+    drop(socks);
+    drop(upstream);
+
+    assert!(waterfall.exited(Duration::from_secs(3)).is_some_and(|status| status.success()));
+}
+
+#[test]
+fn connections_left_after_the_grace_period_are_aborted() {
+    let mut waterfall = Waterfall::start(&["--shutdown_grace", "300"]);
+    let mut socks = greeted(&waterfall);
+    let started = Instant::now();
+
+    terminate(&waterfall);
+
+    assert!(waterfall.exited(Duration::from_secs(3)).is_some_and(|status| status.success()));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    let mut rest = Vec::new();
+
+    assert!(!matches!(socks.read_to_end(&mut rest), Ok(n) if n > 0));
+}
@@ -0,0 +1,92 @@
+// Golden tests for `waterfall simulate`: each case runs the binary on a fixture from
+// tests/golden and compares its output with tests/golden/<case>.txt. Run with
+// UPDATE_GOLDEN=1 to rewrite the expected files after an intended change.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn golden(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name)
+}
+
+fn input(fixture: &str) -> Vec<String> {
+    match fixture {
+        "clienthello.hex" => vec!["--hex".to_string(), fs::read_to_string(golden(fixture)).unwrap()],
+        _ => vec!["--file".to_string(), golden(fixture).display().to_string()]
+    }
+}
+
+fn check(case: &str, fixture: &str, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_waterfall"))
+        .arg("simulate")
+        .args(input(fixture))
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{}: {}", case, String::from_utf8_lossy(&output.stderr));
+
+    let actual = String::from_utf8(output.stdout).unwrap();
+    let expected_path = golden(&format!("{}.txt", case));
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&expected_path, &actual).unwrap();
+
+        return;
+    }
+
+    let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+
+    assert_eq!(actual, expected, "{} differs from {}", case, expected_path.display());
+}
+
+#[test]
+fn no_strategies() {
+    check("no_strategies", "clienthello.hex", &[]);
+}
+
+#[test]
+fn split_inside_sni() {
+    check("split_inside_sni", "clienthello.hex", &["--dpi_bypass_strategies", "tcp_split", "1+s"]);
+}
+
+#[test]
+fn fake_then_auto_disorder() {
+    check("fake_then_auto_disorder", "clienthello.hex", &[
+        "--fake_packet_ttl", "5",
+        "--dpi_bypass_strategies", "tcp_fake_insert,tcp_disorder", "2+s", "auto"
+    ]);
+}
+
+#[test]
+fn out_of_band() {
+    check("out_of_band", "clienthello.hex", &["--dpi_bypass_strategies", "tcp_out_of_band", "7+"]);
+}
+
+#[test]
+fn sni_filter_skips_rule() {
+    check("sni_filter_skips_rule", "clienthello.hex", &[
+        "--filter_sni", "example.org",
+        "--dpi_bypass_strategies", "tcp_split", "3+s"
+    ]);
+}
+
+#[test]
+fn port_filter_skips_rule() {
+    check("port_filter_skips_rule", "clienthello.hex", &[
+        "--port", "8443",
+        "--filter_port", "443-443",
+        "--dpi_bypass_strategies", "tcp_split", "3+s"
+    ]);
+}
+
+#[test]
+fn http_request() {
+    check("http_request", "request.http", &["--http_host_cmix", "--dpi_bypass_strategies", "tcp_split,tcp_disorder", "2", "6"]);
+}
+
+#[test]
+fn json_output() {
+    check("json_output", "clienthello.hex", &["--json", "--dpi_bypass_strategies", "tcp_fake_surround", "1+s"]);
+}
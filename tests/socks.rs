@@ -0,0 +1,122 @@
+// The SOCKS5 handshake against requests a client shouldn't send: each one gets a reply saying
+// why it was refused, and the proxy goes on serving the next connection.
+
+mod common;
+
+use common::Waterfall;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// The reply code waterfall answers `request` with, or None when it closed the connection.
+// Nothing follows the request, so a truncated one is refused instead of waited on.
+
+fn request(waterfall: &Waterfall, request: &[u8]) -> Option<u8> {
+    let mut socks = TcpStream::connect(("127.0.0.1", waterfall.port)).unwrap();
+    let mut reply = [0u8; 2];
+
+    socks.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socks.write_all(&[5, 1, 0]).unwrap();
+    socks.read_exact(&mut reply).unwrap();
+    socks.write_all(request).unwrap();
+    socks.shutdown(Shutdown::Write).unwrap();
+
+    socks.read_exact(&mut reply).ok().map(|_| reply[1])
+}
+
+// What waterfall answers the greeting with.
+
+fn greeting(waterfall: &Waterfall, greeting: &[u8]) -> Vec<u8> {
+    let mut socks = TcpStream::connect(("127.0.0.1", waterfall.port)).unwrap();
+    let mut reply = Vec::new();
+
+    socks.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socks.write_all(greeting).unwrap();
+    socks.read_to_end(&mut reply).unwrap();
+
+    reply
+}
+
+fn connect(port: u16) -> Vec<u8> {
+    [&[5, 1, 0, 1, 127, 0, 0, 1][..], &port.to_be_bytes()].concat()
+}
+
+#[test]
+fn unsupported_requests_are_refused() {
+    let waterfall = Waterfall::start(&[]);
+
+    assert_eq!(request(&waterfall, &[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]), Some(0x07));
+    assert_eq!(request(&waterfall, &[5, 2, 0, 1, 127, 0, 0, 1, 0, 80]), Some(0x07));
+    assert_eq!(request(&waterfall, &[5, 1, 0, 9, 127, 0, 0, 1, 0, 80]), Some(0x08));
+}
+
+#[test]
+fn truncated_requests_are_refused() {
+    let waterfall = Waterfall::start(&[]);
+
+    assert_eq!(request(&waterfall, &[5, 1, 0]), Some(0x01));
+    assert_eq!(request(&waterfall, &[5, 1, 0, 1, 127, 0]), Some(0x01));
+    assert_eq!(request(&waterfall, &[5, 1, 0, 3, 200, b'a', b'b']), Some(0x01));
+    assert_eq!(request(&waterfall, &[5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), Some(0x01));
+}
+
+#[test]
+fn proxy_keeps_serving_after_a_bad_request() {
+    let waterfall = Waterfall::start(&[]);
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+
+    assert_eq!(request(&waterfall, &[4, 1, 0, 80, 127, 0, 0, 1]), Some(0x01));
+    assert_eq!(request(&waterfall, &connect(port)), Some(0x00));
+}
+
+#[test]
+fn greetings_without_a_usable_method_are_refused() {
+    let waterfall = Waterfall::start(&[]);
+
+    assert_eq!(greeting(&waterfall, &[5, 1, 2]), [5, 0xff]);
+    assert_eq!(greeting(&waterfall, &[5, 0]), [5, 0xff]);
+    assert_eq!(greeting(&waterfall, &[4, 1, 0]), [5, 0xff]);
+}
+
+#[test]
+fn requests_are_read_however_they_are_segmented() {
+    let waterfall = Waterfall::start(&[]);
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let request = connect(server.local_addr().unwrap().port());
+    let mut reply = [0u8; 10];
+
+    // Greeting and request in one write.
+
+    let mut socks = TcpStream::connect(("127.0.0.1", waterfall.port)).unwrap();
+
+    socks.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socks.write_all(&[&[5, 2, 2, 0][..], &request].concat()).unwrap();
+    socks.read_exact(&mut reply[..2]).unwrap();
+    socks.read_exact(&mut reply).unwrap();
+
+    assert_eq!(reply[1], 0);
+
+    // Greeting and request each split across writes.
+
+    let mut socks = TcpStream::connect(("127.0.0.1", waterfall.port)).unwrap();
+
+    socks.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socks.set_nodelay(true).unwrap();
+
+    for part in [&[5][..], &[1, 0], &request[..3], &request[3..7], &request[7..]] {
+        socks.write_all(part).unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    socks.read_exact(&mut reply[..2]).unwrap();
+
+    assert_eq!(reply[..2], [5, 0]);
+
+    socks.read_exact(&mut reply).unwrap();
+
+    assert_eq!(reply[1], 0);
+}
@@ -0,0 +1,33 @@
+// Pieces of a TLS ClientHello. `waterfall probe` builds the hellos it sends from these, and the
+// integration tests include this file for theirs, so it doesn't use anything else in the crate.
+
+pub fn with_length(width: usize, data: &[u8]) -> Vec<u8> {
+    [&(data.len() as u32).to_be_bytes()[4 - width..], data].concat()
+}
+
+pub fn list(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+pub fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+    [&kind.to_be_bytes()[..], &with_length(2, data)].concat()
+}
+
+pub fn server_name(name: &str) -> Vec<u8> {
+    extension(0x0000, &with_length(2, &[&[0x00][..], &with_length(2, name.as_bytes())].concat()))
+}
+
+// A whole handshake record; `random` is the 32 bytes after the version.
+
+pub fn client_hello(random: &[u8], session: &[u8], ciphers: &[u16], extensions: &[Vec<u8>]) -> Vec<u8> {
+    let hello = [
+        &[0x03, 0x03][..],
+        random,
+        &with_length(1, session),
+        &with_length(2, &list(ciphers)),
+        &[0x01, 0x00],
+        &with_length(2, &extensions.concat()),
+    ].concat();
+
+    [&[0x16, 0x03, 0x01][..], &with_length(2, &[&[0x01][..], &with_length(3, &hello)].concat())].concat()
+}
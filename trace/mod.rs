@@ -0,0 +1,561 @@
+use crate::core::AuxConfig;
+use crate::event;
+use crate::relay;
+
+use std::cell::RefCell;
+use std::fs;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::time;
+
+// The pcapng blocks and options used here, see https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html
+
+const SECTION_HEADER: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const LINKTYPE_RAW: u16 = 101;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+
+struct Record {
+    interface: u32,
+    time: time::SystemTime,
+    packet: Vec<u8>,
+    comment: Option<String>,
+}
+
+struct Trace {
+    id: u64,
+    dir: String,
+    local: SocketAddr,
+    peer: SocketAddr,
+    offset: u32,
+    rule: Option<String>,
+    notes: Vec<String>,
+    records: Vec<Record>,
+    capture: Option<capture::Capture>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+// Starts recording the emissions made on this thread to `socket`, if tracing is enabled.
+
+pub fn begin(id: u64, socket: &TcpStream, config: &AuxConfig) {
+    if config.trace_dir.is_empty() {
+        return;
+    }
+
+    let (Ok(local), Ok(peer)) = (socket.local_addr(), socket.peer_addr()) else {
+        return;
+    };
+
+    let capture = if config.trace_capture {
+        capture::Capture::start(local, peer)
+            .map_err(|error| event!(Warn, "packet capture unavailable", error = error.to_string()))
+            .ok()
+    } else {
+        None
+    };
+
+    CURRENT.with(|current| *current.borrow_mut() = Some(Trace {
+        id,
+        dir: config.trace_dir.clone(),
+        local,
+        peer,
+        offset: 0,
+        rule: None,
+        notes: Vec::new(),
+        records: Vec::new(),
+        capture,
+    }));
+}
+
+pub fn rule(rule: Option<String>) {
+    CURRENT.with(|current| {
+        if let Some(trace) = current.borrow_mut().as_mut() {
+            trace.rule = rule;
+        }
+    });
+}
+
+// Notes are attached to the comment of the next emission.
+
+pub fn note(note: String) {
+    CURRENT.with(|current| {
+        if let Some(trace) = current.borrow_mut().as_mut() {
+            trace.notes.push(note);
+        }
+    });
+}
+
+pub fn emit(kind: &str, ttl: u32, oob: bool, data: &[u8]) {
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+
+        let Some(trace) = current.as_mut() else {
+            return;
+        };
+
+        let mut comment = format!("{} offset={} len={} ttl={}{}", kind, trace.offset, data.len(), ttl, if oob { " MSG_OOB" } else { "" });
+
+        if let Some(ref rule) = trace.rule {
+            comment = format!("{}: {}", rule, comment);
+        }
+
+        for note in trace.notes.drain(..) {
+            comment = format!("{}; {}", comment, note);
+        }
+
+        let packet = packet(trace.local, trace.peer, ttl as u8, trace.offset.wrapping_add(1), oob, data);
+
+        trace.offset = trace.offset.wrapping_add(data.len() as u32);
+
+        trace.records.push(Record {
+            interface: 0,
+            time: time::SystemTime::now(),
+            packet,
+            comment: Some(comment),
+        });
+    });
+}
+
+// Writes the trace of this thread's connection, once the hooked writes are over.
+
+pub fn finish() {
+    let Some(mut trace) = CURRENT.with(|current| current.borrow_mut().take()) else {
+        return;
+    };
+
+    if let Some(capture) = trace.capture.take() {
+        trace.records.extend(capture.stop().into_iter().map(|(time, packet)| Record { interface: 1, time, packet, comment: None }));
+        trace.records.sort_by_key(|record| record.time);
+    }
+
+    let mut description = format!("waterfall connection {}: {} -> {}", trace.id, trace.local, trace.peer);
+
+    relay::with_current(|info| {
+        description.push_str(&format!(", target {}", info.target));
+
+        if let Some(ref sni) = info.sni {
+            description.push_str(&format!(", sni {}", sni));
+        }
+
+        if !info.strategies.is_empty() {
+            description.push_str(&format!(", strategies {}", info.strategies.join(",")));
+        }
+    });
+
+    let path = Path::new(&trace.dir).join(format!("connection-{}.pcapng", trace.id));
+
+    match fs::write(&path, pcapng(&description, &trace.records)) {
+        Ok(()) => event!(Info, "trace written", path = path.display().to_string(), records = trace.records.len()),
+        Err(error) => event!(Warn, "trace not written", path = path.display().to_string(), error = error.to_string())
+    }
+}
+
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+
+    for chunk in chunks {
+        for pair in chunk.chunks(2) {
+            sum += u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]));
+        }
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+// Rebuilds the IP/TCP packet the kernel would put on the wire for one write. Sequence numbers
+// are relative to the first hooked byte, and the urgent pointer marks data sent with MSG_OOB.
+
+fn packet(local: SocketAddr, peer: SocketAddr, ttl: u8, seq: u32, oob: bool, data: &[u8]) -> Vec<u8> {
+    let mut tcp: Vec<u8> = Vec::with_capacity(20 + data.len());
+
+    tcp.extend_from_slice(&local.port().to_be_bytes());
+    tcp.extend_from_slice(&peer.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.push(5 << 4);
+    tcp.push(if oob { 0x38 } else { 0x18 });
+    tcp.extend_from_slice(&[0xff, 0xff, 0, 0]);
+    tcp.extend_from_slice(&(if oob { data.len() as u16 } else { 0 }).to_be_bytes());
+    tcp.extend_from_slice(data);
+
+    let length = tcp.len();
+
+    match (local.ip(), peer.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let pseudo = [&source.octets()[..], &destination.octets(), &[0, 6], &(length as u16).to_be_bytes()].concat();
+            let sum = checksum(&[&pseudo, &tcp]);
+
+            tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+            let mut ip: Vec<u8> = vec![0x45, 0];
+
+            ip.extend_from_slice(&((20 + length) as u16).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0, ttl, 6, 0, 0]);
+            ip.extend_from_slice(&source.octets());
+            ip.extend_from_slice(&destination.octets());
+
+            let sum = checksum(&[&ip]);
+
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            ip.extend_from_slice(&tcp);
+
+            ip
+        },
+        (source, destination) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip
+            };
+
+            let (source, destination) = (to_v6(source), to_v6(destination));
+            let pseudo = [&source.octets()[..], &destination.octets(), &(length as u32).to_be_bytes(), &[0, 0, 0, 6]].concat();
+            let sum = checksum(&[&pseudo, &tcp]);
+
+            tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+            let mut ip: Vec<u8> = vec![0x60, 0, 0, 0];
+
+            ip.extend_from_slice(&(length as u16).to_be_bytes());
+            ip.extend_from_slice(&[6, ttl]);
+            ip.extend_from_slice(&source.octets());
+            ip.extend_from_slice(&destination.octets());
+            ip.extend_from_slice(&tcp);
+
+            ip
+        }
+    }
+}
+
+fn option(code: u16, value: &[u8]) -> Vec<u8> {
+    let mut option = [&code.to_le_bytes()[..], &(value.len() as u16).to_le_bytes(), value].concat();
+
+    option.resize(option.len().next_multiple_of(4), 0);
+
+    option
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let mut body = body.to_vec();
+
+    body.resize(body.len().next_multiple_of(4), 0);
+
+    let length = (12 + body.len()) as u32;
+
+    [&kind.to_le_bytes()[..], &length.to_le_bytes(), &body, &length.to_le_bytes()].concat()
+}
+
+fn interface(name: &str) -> Vec<u8> {
+    let body = [
+        &LINKTYPE_RAW.to_le_bytes()[..], &[0, 0], &0u32.to_le_bytes(),
+        &option(IF_NAME, name.as_bytes()), &[0, 0, 0, 0]
+    ].concat();
+
+    block(INTERFACE_DESCRIPTION, &body)
+}
+
+fn pcapng(description: &str, records: &[Record]) -> Vec<u8> {
+    let header = [
+        &BYTE_ORDER_MAGIC.to_le_bytes()[..], &1u16.to_le_bytes(), &0u16.to_le_bytes(), &(-1i64).to_le_bytes(),
+        &option(OPT_COMMENT, description.as_bytes()), &[0, 0, 0, 0]
+    ].concat();
+
+    let mut output = block(SECTION_HEADER, &header);
+
+    output.extend(interface("waterfall emissions"));
+    output.extend(interface("captured outgoing packets"));
+
+    for record in records {
+        let micros = record.time.duration_since(time::UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        let mut packet = record.packet.clone();
+
+        packet.resize(packet.len().next_multiple_of(4), 0);
+
+        let mut body = [
+            &record.interface.to_le_bytes()[..],
+            &((micros >> 32) as u32).to_le_bytes(), &(micros as u32).to_le_bytes(),
+            &(record.packet.len() as u32).to_le_bytes(), &(record.packet.len() as u32).to_le_bytes(),
+            &packet
+        ].concat();
+
+        if let Some(ref comment) = record.comment {
+            body.extend(option(OPT_COMMENT, comment.as_bytes()));
+            body.extend_from_slice(&[0, 0, 0, 0]);
+        }
+
+        output.extend(block(ENHANCED_PACKET, &body));
+    }
+
+    output
+}
+
+#[cfg(target_os = "linux")]
+mod capture {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    const PACKET_OUTGOING: u8 = 4;
+
+    pub struct Capture {
+        stop: Arc<AtomicBool>,
+        thread: thread::JoinHandle<Vec<(time::SystemTime, Vec<u8>)>>,
+    }
+
+    // Classic BPF over the network header: TCP from the local to the remote port, for IPv4
+    // (any header length) and IPv6 (without extension headers).
+
+    fn filter(source: u16, destination: u16) -> [libc::sock_filter; 19] {
+        let (source, destination) = (u32::from(source), u32::from(destination));
+        let op = |code: u16, jt: u8, jf: u8, k: u32| libc::sock_filter { code, jt, jf, k };
+
+        [
+            op(0x30, 0, 0, 0),
+            op(0x74, 0, 0, 4),
+            op(0x15, 0, 7, 4),
+            op(0x30, 0, 0, 9),
+            op(0x15, 0, 13, 6),
+            op(0xb1, 0, 0, 0),
+            op(0x48, 0, 0, 0),
+            op(0x15, 0, 10, source),
+            op(0x48, 0, 0, 2),
+            op(0x15, 7, 8, destination),
+            op(0x15, 0, 7, 6),
+            op(0x30, 0, 0, 6),
+            op(0x15, 0, 5, 6),
+            op(0x28, 0, 0, 40),
+            op(0x15, 0, 3, source),
+            op(0x28, 0, 0, 42),
+            op(0x15, 0, 1, destination),
+            op(0x06, 0, 0, 0x40000),
+            op(0x06, 0, 0, 0),
+        ]
+    }
+
+    impl Capture {
+        pub fn start(local: SocketAddr, peer: SocketAddr) -> io::Result<Capture> {
+            let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM, (libc::ETH_P_ALL as u16).to_be() as libc::c_int) };
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let program = filter(local.port(), peer.port());
+
+            let bpf = libc::sock_fprog {
+                len: program.len() as libc::c_ushort,
+                filter: program.as_ptr() as *mut libc::sock_filter,
+            };
+
+            let timeout = libc::timeval { tv_sec: 0, tv_usec: 50000 };
+
+            let configured = unsafe {
+                libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER,
+                    &bpf as *const _ as *const libc::c_void, std::mem::size_of_val(&bpf) as libc::socklen_t) == 0 &&
+                libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+                    &timeout as *const _ as *const libc::c_void, std::mem::size_of_val(&timeout) as libc::socklen_t) == 0
+            };
+
+            if !configured {
+                let error = io::Error::last_os_error();
+
+                unsafe { libc::close(fd) };
+
+                return Err(error);
+            }
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopped = stop.clone();
+
+            let thread = thread::spawn(move || {
+                let mut packets = Vec::new();
+                let mut buffer = vec![0u8; 65536];
+
+                // Drains what is already queued after a stop, so the last writes aren't lost
+
+                loop {
+                    let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+                    let mut address_len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+
+                    let stopping = stopped.load(Ordering::Relaxed);
+
+                    let received = unsafe {
+                        libc::recvfrom(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), if stopping { libc::MSG_DONTWAIT } else { 0 },
+                            &mut address as *mut _ as *mut libc::sockaddr, &mut address_len)
+                    };
+
+                    if received < 0 {
+                        if stopping {
+                            break;
+                        }
+
+                        continue;
+                    }
+
+                    if address.sll_pkttype == PACKET_OUTGOING {
+                        packets.push((time::SystemTime::now(), buffer[..received as usize].to_vec()));
+                    }
+                }
+
+                unsafe { libc::close(fd) };
+
+                packets
+            });
+
+            Ok(Capture { stop, thread })
+        }
+
+        pub fn stop(self) -> Vec<(time::SystemTime, Vec<u8>)> {
+            self.stop.store(true, Ordering::Relaxed);
+
+            self.thread.join().unwrap_or_default()
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod capture {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time;
+
+    pub struct Capture;
+
+    impl Capture {
+        pub fn start(_local: SocketAddr, _peer: SocketAddr) -> io::Result<Capture> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "packet capture is only available on Linux"))
+        }
+
+        pub fn stop(self) -> Vec<(time::SystemTime, Vec<u8>)> {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate;
+
+    use std::net::TcpListener;
+
+    // Every block of a little-endian pcapng file as (type, body), checking that the lengths
+    // before and after each body agree and account for the whole file.
+
+    fn blocks(file: &[u8]) -> Vec<(u32, &[u8])> {
+        let u32_at = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+
+        while offset < file.len() {
+            let (kind, length) = (u32_at(offset), u32_at(offset + 4) as usize);
+
+            assert!(length >= 12 && length % 4 == 0, "block length {}", length);
+            assert_eq!(u32_at(offset + length - 4) as usize, length);
+
+            blocks.push((kind, &file[offset + 8..offset + length - 4]));
+            offset += length;
+        }
+
+        assert_eq!(offset, file.len());
+
+        blocks
+    }
+
+    // The value of the first option with `code` in an options list.
+
+    fn option_value(mut options: &[u8], code: u16) -> Option<String> {
+        while let [low, high, length_low, length_high, ref rest @ ..] = *options {
+            let length = u16::from_le_bytes([length_low, length_high]) as usize;
+
+            if u16::from_le_bytes([low, high]) == code {
+                return Some(String::from_utf8_lossy(&rest[..length]).to_string());
+            }
+
+            options = &rest[length.next_multiple_of(4)..];
+        }
+
+        None
+    }
+
+    #[test]
+    fn traces_read_back_as_pcapng() {
+        let dir = std::env::temp_dir().join(format!("waterfall-trace-{}", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let config = AuxConfig { trace_dir: dir.to_string_lossy().to_string(), ..AuxConfig::default() };
+        let writes: [(&str, u32, bool, &[u8]); 3] = [("plain", 64, false, b"\x16\x03\x01"), ("fake", 3, false, b"fake data"), ("oob", 64, true, b"x")];
+
+        begin(7, &socket, &config);
+        rule(Some("rule 1".to_string()));
+        note("split at 3".to_string());
+
+        for (kind, ttl, oob, data) in writes {
+            emit(kind, ttl, oob, data);
+        }
+
+        finish();
+
+        let file = fs::read(dir.join("connection-7.pcapng")).unwrap();
+        let blocks = blocks(&file);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        let (kind, header) = blocks[0];
+
+        assert_eq!(kind, SECTION_HEADER);
+        assert_eq!(header[..4], BYTE_ORDER_MAGIC.to_le_bytes());
+        assert!(option_value(&header[16..], OPT_COMMENT).unwrap().starts_with(&format!("waterfall connection 7: {} -> {}", socket.local_addr().unwrap(), socket.peer_addr().unwrap())));
+
+        let interfaces: Vec<&[u8]> = blocks.iter().filter(|(kind, _)| *kind == INTERFACE_DESCRIPTION).map(|(_, body)| *body).collect();
+
+        assert_eq!(interfaces.len(), 2);
+
+        for (body, name) in interfaces.iter().zip(["waterfall emissions", "captured outgoing packets"]) {
+            assert_eq!(body[..2], LINKTYPE_RAW.to_le_bytes());
+            assert_eq!(option_value(&body[8..], IF_NAME).as_deref(), Some(name));
+        }
+
+        let packets: Vec<&[u8]> = blocks.iter().filter(|(kind, _)| *kind == ENHANCED_PACKET).map(|(_, body)| *body).collect();
+
+        assert_eq!(packets.len(), writes.len());
+
+        let mut offset = 0;
+
+        for (body, (kind, ttl, oob, data)) in packets.iter().zip(writes) {
+            let captured = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+            let packet = &body[20..20 + captured];
+
+            assert_eq!(body[..4], 0u32.to_le_bytes());
+            assert_eq!(body[16..20], body[12..16]);
+            assert_eq!(captured, 40 + data.len());
+            assert_eq!(packet[8], ttl as u8);
+            assert_eq!(&packet[40..], data);
+
+            let comment = option_value(&body[20 + captured.next_multiple_of(4)..], OPT_COMMENT).unwrap();
+
+            assert!(comment.starts_with(&format!("rule 1: {} offset={} len={} ttl={}{}", kind, offset, data.len(), ttl, if oob { " MSG_OOB" } else { "" })), "{}", comment);
+            assert_eq!(comment.ends_with("; split at 3"), offset == 0);
+
+            offset += data.len();
+        }
+
+        assert_eq!(simulate::first_payload(&file), Ok((writes[0].3.to_vec(), socket.peer_addr().unwrap().port())));
+    }
+}